@@ -1,18 +1,85 @@
-use clap::{Args, Parser, Subcommand};
-use tempfile::tempdir;
+use clap::{ArgAction, Args, CommandFactory, Parser, Subcommand, ValueEnum};
+use clap::error::ErrorKind;
+use glob::Pattern;
+use regex::RegexBuilder;
+use serde_json::json;
+use std::any::Any;
+use std::collections::BTreeMap;
+use std::fs;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc;
+use std::thread;
 
-use docxtools::xml_util::XMLUtil;
-use docxtools::zip_util::ZipUtil;
+use docxtools::control_util::ControlUtil;
+use docxtools::docx_package::DocxPackage;
+use docxtools::error::{DocxError, DocxResult};
+use docxtools::field_util::FieldUtil;
+use docxtools::file_util::{FileUtil, InputFile};
+use docxtools::html_util::HtmlUtil;
+use docxtools::link_util::LinkUtil;
+use docxtools::markdown_util::MarkdownUtil;
+use docxtools::merge_util::MergeUtil;
+use docxtools::reporter::{CollectingReporter, Control, DocxReporter, Field, Link, LinkProblem, Paragraph, Replacement,
+    Revision, Table};
+use docxtools::revision_util::{RevisionFilter, RevisionUtil, TrackChanges};
+use docxtools::table_util::TableUtil;
+use docxtools::xml_util::{StoryType, XMLUtil};
+use docxtools::zip_util::{ZipLimits, ZipUtil};
 
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
 #[command(propagate_version = true)]
+#[command(subcommand_precedence_over_arg = true)]
 struct Cli {
-    /// The docx file to operate on.
-    in_file: String,
+    /// The docx files to operate on. Directories are searched for .docx files.
+    #[arg(required = true)]
+    in_files: Vec<String>,
 
-    /// The temporary directory to use. If not specified a system temp directory
-    /// will be used and cleaned after use.
+    /// Also search the subdirectories of the directories provided.
+    #[arg(short, long)]
+    recursive: bool,
+
+    /// Only process files with a name that matches this glob pattern, e.g. 'contract*.docx'.
+    /// Can be specified multiple times. Files in directories must match '*.docx', ignoring case, if not specified.
+    #[arg(long, value_name = "GLOB", value_parser = Pattern::new)]
+    include: Vec<Pattern>,
+
+    /// Skip files with a name that matches this glob pattern, e.g. '~$*'. Can be specified multiple times.
+    #[arg(long, value_name = "GLOB", value_parser = Pattern::new)]
+    exclude: Vec<Pattern>,
+
+    /// Write the documents processed by a modifying command into this directory instead of overwriting
+    /// the input files. Documents found in a directory keep their path relative to that directory.
+    /// The tables, to-markdown and to-html commands write the files they export into this directory,
+    /// and the merge command the documents it generates.
+    #[arg(short, long)]
+    out_dir: Option<String>,
+
+    /// The format to report the results in.
+    #[arg(short, long, value_enum, default_value_t = Format::Text)]
+    format: Format,
+
+    /// The number of documents to process in parallel. Use 0 for the number of available CPUs.
+    #[arg(short, long, default_value_t = 1)]
+    jobs: usize,
+
+    /// The maximum number of entries in a document.
+    #[arg(long, default_value_t = ZipLimits::default().max_entries)]
+    max_entries: usize,
+
+    /// The maximum total uncompressed size of a document, in MiB.
+    #[arg(long, value_name = "MIB", default_value_t = ZipLimits::default().max_total_size / (1024 * 1024))]
+    max_size: u64,
+
+    /// The maximum compression ratio of the parts of a document, checked for parts of more than 1 MiB.
+    #[arg(long, default_value_t = ZipLimits::default().max_ratio)]
+    max_ratio: u64,
+
+    /// Extract the document into this directory and operate on the extracted files.
+    /// If not specified the document is processed in memory. When processing multiple documents
+    /// each is extracted into a numbered subdirectory.
     #[arg(short, long)]
     temp_dir: Option<String>,
 
@@ -25,20 +92,70 @@ enum Commands {
     /// List the text from the document to the console
     Cat(CatArgs),
 
-    /// List the links in the document to the console
+    /// List the links in the document with the text they are attached to
     Links(LinksArgs),
 
+    /// Check the links in the document for missing bookmarks, broken relationships, missing files and invalid URLs
+    CheckLinks(CheckLinksArgs),
+
     /// Search the text in the document
     Grep(GrepArgs),
 
+    /// Export the tables in the document as CSV or TSV
+    Tables(TablesArgs),
+
+    /// List the content controls in the document with their tags, types and values
+    ListControls(ListControlsArgs),
+
+    /// List the fields in the document with their instructions and results
+    Fields(FieldsArgs),
+
+    /// Convert the document to Markdown
+    ToMarkdown(ConvertArgs),
+
+    /// Convert the document to HTML
+    ToHtml(HtmlArgs),
+
+    /// Generate a document from the template for each record in a data file
+    Merge(MergeArgs),
+
     /// Search and replace in document text and tables
     Replace(ReplaceArgs),
 
     /// Search and replace hyperlinks in the document
-    ReplaceLinks(ReplaceArgs),
+    ReplaceLinks(ReplaceLinksArgs),
 
     /// Change styles inside the document
-    StyleChange(StyleChangeArgs)
+    StyleChange(StyleChangeArgs),
+
+    /// Fill the content controls in the document by tag or alias
+    FillControls(FillControlsArgs),
+
+    /// Replace the fields in the document by their results
+    UnlinkFields(UnlinkFieldsArgs),
+
+    /// Accept the tracked changes in the document
+    AcceptChanges(RevisionArgs),
+
+    /// Reject the tracked changes in the document
+    RejectChanges(RevisionArgs),
+}
+
+impl Commands {
+    /// The output file specified for a modifying command.
+    fn out_file(&self) -> Option<&String> {
+        match self {
+            Commands::Replace(args) => args.out_file.as_ref(),
+            Commands::ReplaceLinks(args) => args.out_file.as_ref(),
+            Commands::StyleChange(args) => args.out_file.as_ref(),
+            Commands::FillControls(args) => args.out_file.as_ref(),
+            Commands::UnlinkFields(args) => args.out_file.as_ref(),
+            Commands::AcceptChanges(args) | Commands::RejectChanges(args) => args.out_file.as_ref(),
+            Commands::ToMarkdown(args) => args.out_file.as_ref(),
+            Commands::ToHtml(args) => args.out_file.as_ref(),
+            _ => None
+        }
+    }
 }
 
 #[derive(Args)]
@@ -50,9 +167,110 @@ struct LinksArgs {
 }
 
 #[derive(Args)]
+#[command(disable_help_flag = true)]
 struct GrepArgs {
     /// The regular expression to search for
     regex: String,
+
+    /// Ignore case distinctions in the regular expression
+    #[arg(short, long)]
+    ignore_case: bool,
+
+    /// Select the paragraphs that don't match
+    #[arg(short = 'v', long)]
+    invert_match: bool,
+
+    /// Only print the number of selected paragraphs for each file
+    #[arg(short, long)]
+    count: bool,
+
+    /// Only print the names of the files with selected paragraphs
+    #[arg(short = 'l', long)]
+    files_with_matches: bool,
+
+    /// Only print the names of the files without selected paragraphs
+    #[arg(short = 'L', long, conflicts_with = "files_with_matches")]
+    files_without_match: bool,
+
+    /// Only print the matching parts of the paragraphs, each on a separate line
+    #[arg(short, long)]
+    only_matching: bool,
+
+    /// Prefix each paragraph with its number in the part of the document, starting at 1
+    #[arg(short = 'n', long)]
+    line_number: bool,
+
+    /// Prefix each paragraph with the file name. This is the default when there is more than one file to search
+    #[arg(short = 'H', long, overrides_with = "no_filename")]
+    with_filename: bool,
+
+    /// Don't prefix the paragraphs with the file name
+    #[arg(short = 'h', long, overrides_with = "with_filename")]
+    no_filename: bool,
+
+    /// Print help
+    #[arg(long, action = ArgAction::Help)]
+    help: Option<bool>,
+}
+
+#[derive(Args)]
+struct TablesArgs {
+    /// Separate the values with tabs instead of commas
+    #[arg(long)]
+    tsv: bool,
+
+    /// Repeat the text of merged cells in all the cells they span, instead of leaving those empty
+    #[arg(long)]
+    fill_merged: bool,
+}
+
+#[derive(Args)]
+struct ListControlsArgs {
+}
+
+#[derive(Args)]
+struct FieldsArgs {
+}
+
+#[derive(Args)]
+struct CheckLinksArgs {
+}
+
+#[derive(Args)]
+struct ConvertArgs {
+    /// The file to write to. If ommitted the result is printed, or written next to the document in the
+    /// directory specified with --out-dir.
+    out_file: Option<String>,
+}
+
+#[derive(Args)]
+struct HtmlArgs {
+    /// The file to write to. If ommitted the result is printed, or written next to the document in the
+    /// directory specified with --out-dir.
+    out_file: Option<String>,
+
+    /// Copy the images into a directory next to the HTML file, named after it with '_media' appended,
+    /// instead of embedding them in the HTML
+    #[arg(long)]
+    copy_images: bool,
+}
+
+#[derive(Args)]
+struct MergeArgs {
+    /// The CSV, TSV or JSON file with the records. The first line of a CSV or TSV file holds the names
+    /// of the fields, a JSON file contains an array of objects.
+    data: String,
+
+    /// The name of the generated documents. Placeholders such as '{{customer_name}}' are filled in
+    /// from the record and '{{#}}' is the number of the record. Defaults to the name of the template
+    /// followed by '_{{#}}.docx'. Nothing is written if two records give the same name or a name
+    /// is that of the template.
+    #[arg(long, value_name = "PATTERN")]
+    name: Option<String>,
+
+    /// Only report the documents that would be generated, don't write any file
+    #[arg(long)]
+    dry_run: bool,
 }
 
 #[derive(Args)]
@@ -60,11 +278,54 @@ struct ReplaceArgs {
     /// The regular expression to search for
     regex: String,
 
+    /// The replacement text. It is inserted as is, '$1' doesn't refer to a capture group.
+    replace: String,
+
+    /// The output file to write to. If ommitted writing is done to the input file.
+    out_file: Option<String>,
+
+    /// Only report the changes that would be made, don't write any file
+    #[arg(long)]
+    dry_run: bool,
+
+    /// The story parts to operate on, as a comma-separated list of main, header, footer, footnotes,
+    /// endnotes and comments. All of them if not specified.
+    #[arg(long, value_name = "PARTS", value_delimiter = ',', value_parser = parse_story_type)]
+    parts: Vec<StoryType>,
+
+    /// Record the replacements as tracked changes, which can be reviewed in the word processor
+    #[arg(long)]
+    track_changes: bool,
+
+    /// The author of the tracked changes
+    #[arg(long, requires = "track_changes", default_value = "docxtools")]
+    author: String,
+
+    /// Search and replace in the instructions of the fields, such as 'MERGEFIELD name', instead of the text.
+    /// The replacement can refer to capture groups, e.g. '$1'.
+    #[arg(long, conflicts_with = "track_changes")]
+    field_codes: bool,
+}
+
+#[derive(Args)]
+struct ReplaceLinksArgs {
+    /// The regular expression to search for
+    regex: String,
+
     /// The replacement text
     replace: String,
 
     /// The output file to write to. If ommitted writing is done to the input file.
-    out_file: Option<String>
+    out_file: Option<String>,
+
+    /// The type of the relationships to change, e.g. 'image', 'attachedTemplate' or 'oleObject', or the full URI
+    /// of the relationship type
+    #[arg(long, default_value = "hyperlink")]
+    rel_type: String,
+
+    /// Only report the changes that would be made, don't write any file
+    #[arg(long)]
+    dry_run: bool,
 }
 
 #[derive(Args)]
@@ -76,7 +337,288 @@ struct StyleChangeArgs {
     replace: String,
 
     /// The output file to write to. If ommitted writing is done to the input file.
-    out_file: Option<String>
+    out_file: Option<String>,
+
+    /// Only report the changes that would be made, don't write any file
+    #[arg(long)]
+    dry_run: bool,
+
+    /// The story parts to operate on, as a comma-separated list of main, header, footer, footnotes,
+    /// endnotes and comments. All of them if not specified.
+    #[arg(long, value_name = "PARTS", value_delimiter = ',', value_parser = parse_story_type)]
+    parts: Vec<StoryType>,
+}
+
+#[derive(Args)]
+struct FillControlsArgs {
+    /// The file with the values, with a line 'key=value' for each tag or alias, or a JSON object
+    /// if its name ends with '.json'
+    values: String,
+
+    /// The output file to write to. If ommitted writing is done to the input file.
+    out_file: Option<String>,
+
+    /// Only report the changes that would be made, don't write any file
+    #[arg(long)]
+    dry_run: bool,
+}
+
+#[derive(Args)]
+struct UnlinkFieldsArgs {
+    /// The output file to write to. If ommitted writing is done to the input file.
+    out_file: Option<String>,
+
+    /// Only unlink the fields of these types, as a comma-separated list such as 'MERGEFIELD,DATE'
+    #[arg(long = "type", value_name = "TYPES", value_delimiter = ',')]
+    types: Vec<String>,
+
+    /// Only report the changes that would be made, don't write any file
+    #[arg(long)]
+    dry_run: bool,
+}
+
+#[derive(Args)]
+struct RevisionArgs {
+    /// The output file to write to. If ommitted writing is done to the input file.
+    out_file: Option<String>,
+
+    /// Only resolve the changes made by this author
+    #[arg(long)]
+    author: Option<String>,
+
+    /// Only resolve the changes made on or after this date, e.g. '2024-03-01'
+    #[arg(long, value_name = "DATE")]
+    after: Option<String>,
+
+    /// Only resolve the changes made before this date, e.g. '2024-03-01T12:00:00Z'
+    #[arg(long, value_name = "DATE")]
+    before: Option<String>,
+
+    /// Only report the changes that would be made, don't write any file
+    #[arg(long)]
+    dry_run: bool,
+}
+
+impl RevisionArgs {
+    fn filter(&self) -> RevisionFilter {
+        RevisionFilter { author: self.author.clone(), after: self.after.clone(), before: self.before.clone() }
+    }
+}
+
+/// The output format for the results of the commands.
+#[derive(Clone, Copy, PartialEq, ValueEnum)]
+enum Format {
+    /// Lines of text, prefixed with the name of the file
+    Text,
+
+    /// A JSON array with an object for each result
+    Json,
+
+    /// A JSON object for each result, one per line
+    Jsonl,
+}
+
+/// Collects the results of the operations on a document in the output format, so that the output of
+/// documents processed in parallel can be printed in order.
+struct OutputReporter {
+    format: Format,
+    records: Vec<String>,
+
+    /// If the results of grep and the rows of tables are prefixed with the name of the file
+    with_filename: bool,
+
+    /// The separator of the values in the rows of tables
+    delimiter: char,
+
+    /// If grep selected any paragraphs or, with `-L`, listed the file
+    selected: bool,
+}
+
+impl OutputReporter {
+    fn new(format: Format) -> Self {
+        OutputReporter { format, records: vec![], with_filename: false, selected: false, delimiter: ',' }
+    }
+
+    fn add(&mut self, text: String, json: serde_json::Value) {
+        if self.format == Format::Text {
+            self.records.push(text);
+        } else {
+            self.records.push(json.to_string());
+        }
+    }
+
+    fn add_paragraph(&mut self, para: &Paragraph) {
+        self.add(format!("{}: {}", para.src_file, para.text), json!({
+            "file": para.src_file,
+            "part": para.part,
+            "paragraph": para.index,
+            "style": para.style,
+            "text": para.text,
+        }));
+    }
+
+    /// Add paragraph `para` as a result of grep, or only `matched`, the part of it that matched, if set.
+    fn add_grep_match(&mut self, para: &Paragraph, matched: Option<&str>, line_number: bool) {
+        let mut line = String::new();
+        if self.with_filename {
+            line.push_str(&format!("{}:", para.src_file));
+        }
+        if line_number {
+            line.push_str(&format!("{}:", para.index + 1));
+        }
+        line.push_str(matched.unwrap_or(&para.text));
+
+        let mut json = json!({
+            "file": para.src_file,
+            "part": para.part,
+            "paragraph": para.index,
+            "style": para.style,
+            "text": para.text,
+        });
+        if let Some(m) = matched {
+            json["match"] = json!(m);
+        }
+        self.add(line, json);
+    }
+}
+
+impl DocxReporter for OutputReporter {
+    fn on_paragraph(&mut self, para: &Paragraph) {
+        self.add_paragraph(para);
+    }
+
+    fn on_match(&mut self, para: &Paragraph) {
+        self.add_paragraph(para);
+    }
+
+    fn on_replacement(&mut self, repl: &Replacement) {
+        let text = match (&repl.attribute, repl.paragraph) {
+            (Some(attr), Some(para)) => format!("{}: paragraph {}: {}={}\n-> {}={}",
+                repl.src_file, para, attr, repl.old, attr, repl.new),
+            (Some(attr), None) => format!("{}: {}={}\n-> {}={}",
+                repl.src_file, attr, repl.old, attr, repl.new),
+            (None, _) => format!("{}: {}\n-> {}", repl.src_file, repl.old, repl.new)
+        };
+        self.add(text, json!({
+            "file": repl.src_file,
+            "part": repl.part,
+            "paragraph": repl.paragraph,
+            "attribute": repl.attribute,
+            "old": repl.old,
+            "new": repl.new,
+        }));
+    }
+
+    fn on_revision(&mut self, rev: &Revision) {
+        let action = if rev.accepted { "accepted" } else { "rejected" };
+        self.add(format!("{}: {}: {} {} by {} on {}", rev.src_file, rev.part, action, rev.kind,
+                rev.author.as_deref().unwrap_or("unknown"), rev.date.as_deref().unwrap_or("unknown date")), json!({
+            "file": rev.src_file,
+            "part": rev.part,
+            "kind": rev.kind,
+            "author": rev.author,
+            "date": rev.date,
+            "accepted": rev.accepted,
+        }));
+    }
+
+    fn on_table(&mut self, table: &Table) {
+        if self.format == Format::Text {
+            // One record per row, prefixed with the number of the table, starting at 1
+            for row in &table.rows {
+                let mut values = vec![(table.index + 1).to_string()];
+                if self.with_filename {
+                    values.insert(0, table.src_file.clone());
+                }
+                values.extend(row.iter().cloned());
+                self.records.push(TableUtil::format_row(&values, self.delimiter));
+            }
+        } else {
+            self.records.push(json!({
+                "file": table.src_file,
+                "part": table.part,
+                "table": table.index + 1,
+                "rows": table.rows,
+            }).to_string());
+        }
+    }
+
+    fn on_control(&mut self, control: &Control) {
+        let name = control.tag.as_ref().or(control.alias.as_ref()).map(String::as_str).unwrap_or("-");
+        let placeholder = if control.placeholder { " [placeholder]" } else { "" };
+        self.add(format!("{}: {} ({}): {}{}", control.src_file, name, control.kind, control.value, placeholder), json!({
+            "file": control.src_file,
+            "part": control.part,
+            "tag": control.tag,
+            "alias": control.alias,
+            "type": control.kind,
+            "value": control.value,
+            "placeholder": control.placeholder,
+        }));
+    }
+
+    fn on_field(&mut self, field: &Field) {
+        self.add(format!("{}: {{ {} }} {}", field.src_file, field.instruction, field.result), json!({
+            "file": field.src_file,
+            "part": field.part,
+            "type": field.kind,
+            "instruction": field.instruction,
+            "result": field.result,
+        }));
+    }
+
+    fn on_link(&mut self, link: &Link) {
+        let text = match &link.text {
+            Some(t) => format!("{}: {} -> {}", link.src_file, t, link.target),
+            None => format!("{}: {}", link.src_file, link.target),
+        };
+        self.add(text, json!({
+            "file": link.src_file,
+            "part": link.part,
+            "paragraph": link.paragraph,
+            "text": link.text,
+            "id": link.id,
+            "type": link.rel_type,
+            "target": link.target,
+            "target_mode": link.target_mode,
+            "external": link.external,
+        }));
+    }
+
+    fn on_link_problem(&mut self, problem: &LinkProblem) {
+        let link = &problem.link;
+        let target = if link.target.is_empty() { link.id.as_deref().unwrap_or_default() } else { &link.target };
+        let text = match &link.text {
+            Some(t) => format!("{}: {}: {} ({})", link.src_file, problem.problem, target, t),
+            None => format!("{}: {}: {}", link.src_file, problem.problem, target),
+        };
+        self.add(text, json!({
+            "file": link.src_file,
+            "part": link.part,
+            "paragraph": link.paragraph,
+            "text": link.text,
+            "id": link.id,
+            "target": link.target,
+            "problem": problem.problem,
+        }));
+    }
+}
+
+/// The outcome of processing a single document.
+struct FileResult {
+    records: Vec<String>,
+    matched: bool,
+    modified: bool,
+    error: Option<DocxError>,
+}
+
+/// Totals over all documents processed, reported at the end of a batch.
+#[derive(Default)]
+struct Summary {
+    scanned: usize,
+    matched: usize,
+    modified: usize,
+    failed: usize,
 }
 
 fn main() {
@@ -86,53 +628,525 @@ fn main() {
 }
 
 fn real_main(args: Cli) -> i32 {
-    let src_file = args.in_file;
-    // let dest_file = args.out_file;
+    let mut inputs = Vec::new();
+    let mut summary = Summary::default();
+    let mut exit = 0;
+
+    for path in &args.in_files {
+        for input in FileUtil::find_documents(path, args.recursive, &args.include, &args.exclude) {
+            match input {
+                Ok(i) => inputs.push(i),
+                Err(e) => {
+                    eprintln!("{}: {}", path, e);
+                    summary.failed += 1;
+                    if exit == 0 {
+                        exit = exit_code(&e);
+                    }
+                }
+            }
+        }
+    }
+
+    if args.command.out_file().is_some() {
+        if inputs.len() > 1 || args.in_files.len() > 1 {
+            Cli::command().error(ErrorKind::ArgumentConflict,
+                "an output file can only be used with a single input file, use --out-dir instead").exit();
+        }
+        if args.out_dir.is_some() {
+            Cli::command().error(ErrorKind::ArgumentConflict,
+                "an output file can't be used together with --out-dir").exit();
+        }
+    }
+
+    if args.format == Format::Json {
+        print!("[");
+    }
+    let mut first_record = true;
+
+    process_all(&args, &inputs, |input, res| {
+        for record in &res.records {
+            if args.format == Format::Json {
+                print!("{}\n  {}", if first_record { "" } else { "," }, record);
+            } else {
+                println!("{}", record);
+            }
+            first_record = false;
+        }
+
+        summary.scanned += 1;
+        if res.matched {
+            summary.matched += 1;
+        }
+        if res.modified {
+            summary.modified += 1;
+        }
+        if let Some(e) = res.error {
+            eprintln!("{}: {}", input.path, e);
+            summary.failed += 1;
+            if exit == 0 {
+                exit = exit_code(&e);
+            }
+        }
+    });
+
+    if args.format == Format::Json {
+        println!("{}]", if first_record { "" } else { "\n" });
+    }
 
-    let fstempdir = tempdir().unwrap();
+    if inputs.len() > 1 {
+        eprintln!("{} files scanned, {} matched, {} modified, {} failed",
+            summary.scanned, summary.matched, summary.modified, summary.failed);
+    }
+
+    // Like grep, return 0 if anything matched, 1 if nothing matched and 2 if there was an error
+    if let Commands::Grep(_) = args.command {
+        return match (summary.failed, summary.matched) {
+            (0, 0) => 1,
+            (0, _) => 0,
+            _ => 2,
+        };
+    }
+
+    // Problems found by check-links make it fail, so that it can be used as a check before documents are released
+    if let Commands::CheckLinks(_) = args.command {
+        if exit == 0 && summary.matched > 0 {
+            return 1;
+        }
+    }
+
+    exit
+}
+
+/// Process `inputs` on the number of threads requested with `--jobs`. `on_result` is called with the result
+/// of each document, in the order of `inputs`, regardless of the order in which the threads finish them.
+fn process_all(args: &Cli, inputs: &[InputFile], mut on_result: impl FnMut(&InputFile, FileResult)) {
+    let jobs = match args.jobs {
+        0 => thread::available_parallelism().map(|n| n.get()).unwrap_or(1),
+        n => n,
+    }.min(inputs.len()).max(1);
+
+    let next_input = AtomicUsize::new(0);
+    let (tx, rx) = mpsc::channel();
+
+    thread::scope(|s| {
+        for _ in 0..jobs {
+            let tx = tx.clone();
+            let next_input = &next_input;
+            s.spawn(move || loop {
+                let i = next_input.fetch_add(1, Ordering::Relaxed);
+                if i >= inputs.len() {
+                    break;
+                }
+
+                // A panic while processing one document only fails that document
+                let res = panic::catch_unwind(AssertUnwindSafe(|| process_file(args, &inputs[i], i, inputs.len())))
+                    .unwrap_or_else(|payload| FileResult {
+                        records: vec![], matched: false, modified: false,
+                        error: Some(DocxError::Internal(panic_message(payload.as_ref()))),
+                    });
+                if tx.send((i, res)).is_err() {
+                    break;
+                }
+            });
+        }
+        drop(tx);
+
+        // Hold on to results that arrive early until all documents before them are reported
+        let mut pending = BTreeMap::new();
+        let mut next_output = 0;
+        for (i, res) in rx {
+            pending.insert(i, res);
+            while let Some(res) = pending.remove(&next_output) {
+                on_result(&inputs[next_output], res);
+                next_output += 1;
+            }
+        }
+    });
+}
+
+/// The message of a panic with `payload`, as caught by `catch_unwind`.
+fn panic_message(payload: &(dyn Any + Send)) -> String {
+    match payload.downcast_ref::<&str>() {
+        Some(msg) => msg.to_string(),
+        None => payload.downcast_ref::<String>().cloned().unwrap_or_else(|| "panic while processing the document".to_string()),
+    }
+}
+
+/// Process the document `input`, which is number `index` of `count` documents.
+fn process_file(args: &Cli, input: &InputFile, index: usize, count: usize) -> FileResult {
+    let temp_dir = args.temp_dir.as_ref().map(|td| {
+        if count == 1 {
+            td.clone()
+        } else {
+            Path::new(td).join(index.to_string()).to_string_lossy().to_string()
+        }
+    });
+
+    let mut reporter = OutputReporter::new(args.format);
+    if let Commands::Grep(grep_args) = &args.command {
+        reporter.with_filename = grep_args.with_filename || (!grep_args.no_filename && (count > 1 || args.recursive));
+    }
+    if let Commands::Tables(tables_args) = &args.command {
+        reporter.with_filename = count > 1 || args.recursive;
+        reporter.delimiter = if tables_args.tsv { '\t' } else { ',' };
+    }
+    let (modified, error) = match process(args, input, temp_dir, &mut reporter) {
+        Ok(w) => (w, None),
+        Err(e) => (false, Some(e)),
+    };
+
+    let matched = match args.command {
+        Commands::Grep(_) => reporter.selected,
+        _ => !reporter.records.is_empty(),
+    };
+    FileResult { records: reporter.records, matched, modified, error }
+}
 
-    let temp_dir;
-    if let Option::Some(v) = args.temp_dir {
-        temp_dir = v;
+/// Map an error to the exit code of the process. The codes start at 3 as 1 is commonly used for generic
+/// failures and 2 is used by the command line parser for usage errors.
+fn exit_code(err: &DocxError) -> i32 {
+    match err {
+        DocxError::Io(_) => 3,
+        DocxError::Zip(_) => 4,
+        DocxError::Xml { .. } => 5,
+        DocxError::Regex(_) => 6,
+        DocxError::MissingStyle { .. } => 7,
+        DocxError::MissingContentType(_) => 8,
+        DocxError::MissingPart(_) => 9,
+        DocxError::MissingAttribute { .. } => 10,
+        DocxError::ZipLimit(_) => 11,
+        DocxError::DuplicateEntry(_) => 12,
+        DocxError::InvalidData(_) => 13,
+        DocxError::Internal(_) => 14,
+    }
+}
+
+/// Process a single document. If `temp_dir` is set the document is extracted into it and the command
+/// operates on the extracted files. Returns `true` if the document was modified and written.
+fn process(args: &Cli, input: &InputFile, temp_dir: Option<String>, reporter: &mut OutputReporter) -> DocxResult<bool> {
+    let src_file = &input.path;
+
+    let limits = ZipLimits {
+        max_entries: args.max_entries,
+        max_total_size: args.max_size.saturating_mul(1024 * 1024),
+        max_ratio: args.max_ratio,
+    };
+
+    if let Some(temp_dir) = temp_dir {
+        let comments = ZipUtil::read_zip_with_limits(src_file, &temp_dir, &limits)?;
+
+        let mut pkg = DocxPackage::from_dir(&temp_dir)?;
+        for (name, comment) in &comments {
+            pkg.set_comment(name, comment);
+        }
+        if run_command(args, input, &mut pkg, reporter)? {
+            let out_file = out_file(args, input)?;
+            pkg.write_to_dir(&temp_dir)?;
+            ZipUtil::write_zip_with_template(&temp_dir, src_file, &out_file)?;
+            return Ok(pkg.is_modified());
+        }
     } else {
-        temp_dir = fstempdir.path().to_string_lossy().to_string();
+        let mut pkg = DocxPackage::open_with_limits(src_file, &limits)?;
+        if run_command(args, input, &mut pkg, reporter)? {
+            let out_file = out_file(args, input)?;
+            if pkg.is_modified() || &out_file != src_file {
+                pkg.save(&out_file)?;
+                return Ok(pkg.is_modified());
+            }
+        }
     }
 
-    ZipUtil::read_zip(&src_file, &temp_dir)
-        .expect(&src_file);
+    Ok(false)
+}
 
+/// Run the command in `args` on `pkg`, the package of `input`. Returns `true` if the command modifies
+/// the document and the result should be written, which is not the case for a dry run.
+fn run_command(args: &Cli, input: &InputFile, pkg: &mut DocxPackage, reporter: &mut OutputReporter)
+        -> DocxResult<bool> {
+    let src_file = &input.path;
     match &args.command {
         Commands::Cat(_) => {
-            XMLUtil::cat(&temp_dir, &src_file);
+            XMLUtil::cat(pkg, src_file, reporter)?;
         },
         Commands::Links(_) => {
-            XMLUtil::cat_rel_attr (
-                "Relationship", "Target",
-                "Type", "http://schemas.openxmlformats.org/officeDocument/2006/relationships/hyperlink",
-                &temp_dir, &src_file);
+            LinkUtil::links(pkg, src_file, reporter)?;
+        }
+        Commands::CheckLinks(_) => {
+            // Relative targets are resolved against the directory of the document
+            LinkUtil::check_links(pkg, src_file, Path::new(src_file).parent(), reporter)?;
         }
         Commands::Grep(grep_args) => {
-            XMLUtil::grep_xml(&temp_dir, &src_file, &grep_args.regex)
+            grep(grep_args, pkg, src_file, reporter)?;
+        },
+        Commands::Tables(tables_args) => {
+            match &args.out_dir {
+                Some(dir) => write_tables(tables_args, pkg, input, dir)?,
+                None => TableUtil::tables(pkg, src_file, tables_args.fill_merged, reporter)?,
+            }
+        },
+        Commands::ListControls(_) => {
+            ControlUtil::list_controls(pkg, src_file, reporter)?;
+        },
+        Commands::Fields(_) => {
+            FieldUtil::list_fields(pkg, src_file, reporter)?;
+        },
+        Commands::ToMarkdown(convert_args) => {
+            let markdown = MarkdownUtil::to_markdown(pkg)?;
+            let target = export_file(args, input, convert_args.out_file.as_ref(), ".md")?;
+            export(target, input, &markdown, reporter)?;
+        },
+        Commands::ToHtml(html_args) => {
+            let target = export_file(args, input, html_args.out_file.as_ref(), ".html")?;
+            let title = file_stem(Path::new(src_file));
+            let html = if html_args.copy_images {
+                // The images are copied next to the HTML file, or into the current directory if it is printed
+                let media_dir = format!("{}_media", target.as_deref().map(file_stem).unwrap_or_else(|| title.clone()));
+                let parent = target.as_deref().and_then(|t| t.parent()).unwrap_or(Path::new(""));
+                copy_images(pkg, &parent.join(&media_dir))?;
+                HtmlUtil::to_html(pkg, &title, Some(&media_dir))?
+            } else {
+                HtmlUtil::to_html(pkg, &title, None)?
+            };
+            export(target, input, &html, reporter)?;
+        },
+        Commands::Merge(merge_args) => {
+            merge(args, merge_args, pkg, input, reporter)?;
         },
         Commands::Replace(replace_args) => {
-            XMLUtil::replace_xml(&temp_dir, &src_file,
-                &replace_args.regex, &replace_args.replace,
-                &replace_args.out_file.as_deref());
+            if replace_args.field_codes {
+                FieldUtil::replace_instructions(pkg, src_file, &replace_args.regex, &replace_args.replace,
+                    stories(&replace_args.parts), reporter)?;
+            } else if replace_args.track_changes {
+                XMLUtil::replace_xml_tracked(pkg, src_file, &replace_args.regex, &replace_args.replace,
+                    stories(&replace_args.parts), &TrackChanges::new(&replace_args.author), reporter)?;
+            } else {
+                XMLUtil::replace_xml_parts(pkg, src_file,
+                    &replace_args.regex, &replace_args.replace, stories(&replace_args.parts), reporter)?;
+            }
+            return Ok(!replace_args.dry_run);
         },
         Commands::ReplaceLinks(replace_args) => {
-            XMLUtil::replace_rel_attr(&temp_dir, &src_file,
-                &replace_args.regex, &replace_args.replace,
-                &replace_args.out_file.as_deref());
+            XMLUtil::replace_rel_attr(pkg, src_file,
+                &replace_args.regex, &replace_args.replace, &replace_args.rel_type, reporter)?;
+            return Ok(!replace_args.dry_run);
         },
         Commands::StyleChange(args) => {
-            XMLUtil::change_style(&temp_dir, &src_file,
-                &args.search, &args.replace,
-                &args.out_file.as_deref());
+            XMLUtil::change_style_parts(pkg, src_file,
+                &args.search, &args.replace, stories(&args.parts), reporter)?;
+            return Ok(!args.dry_run);
+        },
+        Commands::FillControls(args) => {
+            let values = ControlUtil::read_values(&args.values)?;
+            ControlUtil::fill_controls(pkg, src_file, &values, reporter)?;
+            return Ok(!args.dry_run);
+        },
+        Commands::UnlinkFields(args) => {
+            FieldUtil::unlink_fields(pkg, src_file, &args.types, reporter)?;
+            return Ok(!args.dry_run);
+        },
+        Commands::AcceptChanges(args) => {
+            RevisionUtil::accept_changes(pkg, src_file, &args.filter(), reporter)?;
+            return Ok(!args.dry_run);
+        },
+        Commands::RejectChanges(args) => {
+            RevisionUtil::reject_changes(pkg, src_file, &args.filter(), reporter)?;
+            return Ok(!args.dry_run);
+        }
+    }
+
+    Ok(false)
+}
+
+/// Search the paragraphs of `pkg` for the regular expression in `args`, reporting the selected paragraphs
+/// to `reporter` in the way GNU grep reports lines.
+fn grep(args: &GrepArgs, pkg: &DocxPackage, src_file: &str, reporter: &mut OutputReporter) -> DocxResult<()> {
+    let regex = RegexBuilder::new(&args.regex).case_insensitive(args.ignore_case).build()?;
+
+    let mut paras = CollectingReporter::default();
+    XMLUtil::cat(pkg, src_file, &mut paras)?;
+    let selected: Vec<&Paragraph> = paras.paragraphs.iter()
+        .filter(|p| regex.is_match(&p.text) != args.invert_match)
+        .collect();
+
+    if args.files_with_matches || args.files_without_match {
+        reporter.selected = selected.is_empty() == args.files_without_match;
+        if reporter.selected {
+            reporter.add(src_file.to_string(), json!({ "file": src_file }));
         }
+        return Ok(());
     }
 
-    // Delete temp dir
-    fstempdir.close().unwrap();
+    reporter.selected = !selected.is_empty();
+    if args.count {
+        let prefix = if reporter.with_filename { format!("{}:", src_file) } else { String::new() };
+        reporter.add(format!("{}{}", prefix, selected.len()), json!({ "file": src_file, "count": selected.len() }));
+    } else if args.only_matching {
+        // Like grep, there is nothing to print for paragraphs that are selected because they don't match
+        if !args.invert_match {
+            for para in selected {
+                for m in regex.find_iter(&para.text) {
+                    reporter.add_grep_match(para, Some(m.as_str()), args.line_number);
+                }
+            }
+        }
+    } else {
+        for para in selected {
+            reporter.add_grep_match(para, None, args.line_number);
+        }
+    }
+    Ok(())
+}
+
+/// Generate a document from `pkg`, the template `input`, for each record in the data file of `args`. The
+/// documents are written next to the template, or in the output directory if specified.
+fn merge(args: &Cli, merge_args: &MergeArgs, pkg: &DocxPackage, input: &InputFile, reporter: &mut OutputReporter)
+        -> DocxResult<()> {
+    let records = MergeUtil::read_records(&merge_args.data)?;
+    let pattern = merge_args.name.clone()
+        .unwrap_or_else(|| format!("{}_{{{{#}}}}.docx", file_stem(Path::new(&input.path))));
 
-    0
+    // Check all the names before writing anything, so that no document overwrites another or the template
+    let template = fs::canonicalize(&input.path).ok();
+    let mut out_files: Vec<String> = vec![];
+    for (i, record) in records.iter().enumerate() {
+        let name = MergeUtil::file_name(&pattern, record, i + 1);
+        let out_file = match &args.out_dir {
+            Some(dir) => out_dir_path(dir, input, &name)?,
+            None => Path::new(&input.path).with_file_name(&name),
+        };
+        if template.is_some() && fs::canonicalize(&out_file).ok() == template {
+            return Err(DocxError::InvalidData(format!("record {} would overwrite the template {}, use '{{{{#}}}}' in the name",
+                i + 1, input.path)));
+        }
+        let out_file = out_file.to_string_lossy().to_string();
+        if let Some(n) = out_files.iter().position(|f| f == &out_file) {
+            return Err(DocxError::InvalidData(format!("records {} and {} both give {}, use '{{{{#}}}}' in the name",
+                n + 1, i + 1, out_file)));
+        }
+        out_files.push(out_file);
+    }
+
+    for (i, (record, out_file)) in records.iter().zip(out_files).enumerate() {
+        let mut doc = pkg.clone();
+        let unresolved = MergeUtil::merge(&mut doc, &input.path, record, &mut CollectingReporter::default())?;
+        if !merge_args.dry_run {
+            doc.save(&out_file)?;
+        }
+
+        let mut text = format!("{}: {}", input.path, out_file);
+        if !unresolved.is_empty() {
+            text.push_str(&format!(" (no value for: {})", unresolved.join(", ")));
+        }
+        reporter.add(text, json!({
+            "file": input.path,
+            "record": i + 1,
+            "out_file": out_file,
+            "unresolved": unresolved,
+        }));
+    }
+    Ok(())
+}
+
+/// Write each table of `pkg`, the package of `input`, to a separate file in `out_dir`. The files are named
+/// after the document and the number of the table, e.g. `report_table1.csv`.
+fn write_tables(args: &TablesArgs, pkg: &DocxPackage, input: &InputFile, out_dir: &str) -> DocxResult<()> {
+    let mut tables = CollectingReporter::default();
+    TableUtil::tables(pkg, &input.path, args.fill_merged, &mut tables)?;
+
+    let (delimiter, ext) = if args.tsv { ('\t', "tsv") } else { (',', "csv") };
+    for table in &tables.tables {
+        let mut content = String::new();
+        for row in &table.rows {
+            content.push_str(&TableUtil::format_row(row, delimiter));
+            content.push_str("\r\n");
+        }
+        fs::write(export_path(out_dir, input, &format!("_table{}.{}", table.index + 1, ext))?, content)?;
+    }
+    Ok(())
+}
+
+/// The file to export the conversion of `input` to. This is `out_file` if specified, otherwise a file named after
+/// the document with `suffix` in the output directory if specified. Returns `None` if the result is to be printed.
+fn export_file(args: &Cli, input: &InputFile, out_file: Option<&String>, suffix: &str) -> DocxResult<Option<PathBuf>> {
+    match (out_file, &args.out_dir) {
+        (Some(of), _) => Ok(Some(PathBuf::from(of))),
+        (None, Some(dir)) => Ok(Some(export_path(dir, input, suffix)?)),
+        (None, None) => Ok(None),
+    }
+}
+
+/// Write `content`, converted from `input`, to `target` or report it if there is no target.
+fn export(target: Option<PathBuf>, input: &InputFile, content: &str, reporter: &mut OutputReporter) -> DocxResult<()> {
+    match target {
+        Some(t) => fs::write(t, content)?,
+        None => reporter.add(content.strip_suffix('\n').unwrap_or(content).to_string(),
+            json!({ "file": input.path, "content": content })),
+    }
+    Ok(())
+}
+
+/// Copy the images of `pkg` into `dir`.
+fn copy_images(pkg: &DocxPackage, dir: &Path) -> DocxResult<()> {
+    for part in HtmlUtil::media_parts(pkg) {
+        if let (Some(data), Some(name)) = (pkg.get_part(&part), part.rsplit('/').next()) {
+            fs::create_dir_all(dir)?;
+            fs::write(dir.join(name), data)?;
+        }
+    }
+    Ok(())
+}
+
+/// The name of the file at `path` without its extension.
+fn file_stem(path: &Path) -> String {
+    path.file_stem().map(|s| s.to_string_lossy().to_string()).unwrap_or_default()
+}
+
+/// The path in `out_dir` of a file exported from `input`. It keeps the path of the document relative to the
+/// directory it was found in and its name is the name of the document without extension followed by `suffix`.
+fn export_path(out_dir: &str, input: &InputFile, suffix: &str) -> DocxResult<PathBuf> {
+    let stem = file_stem(Path::new(&input.rel_path));
+    out_dir_path(out_dir, input, &format!("{}{}", stem, suffix))
+}
+
+/// The path in `out_dir` of the file `name` generated from `input`, in the same directory relative to `out_dir`
+/// as the document relative to the directory it was found in.
+fn out_dir_path(out_dir: &str, input: &InputFile, name: &str) -> DocxResult<PathBuf> {
+    let parent = Path::new(&input.rel_path).parent().unwrap_or(Path::new(""));
+    let dir = Path::new(out_dir).join(parent);
+    fs::create_dir_all(&dir)?;
+    Ok(dir.join(name))
+}
+
+/// Parse the name of a story type for the `--parts` option.
+fn parse_story_type(name: &str) -> Result<StoryType, String> {
+    StoryType::from_name(name).ok_or_else(|| format!("unknown part '{}', expected one of: {}", name,
+        StoryType::ALL.iter().map(|st| st.name()).collect::<Vec<_>>().join(", ")))
+}
+
+/// The story types selected with `--parts`, all of them if none were selected.
+fn stories(parts: &[StoryType]) -> &[StoryType] {
+    if parts.is_empty() {
+        &StoryType::ALL
+    } else {
+        parts
+    }
+}
+
+/// The file to write the modified `input` document to. This is the output file of the command if specified,
+/// otherwise the document in the output directory if specified, otherwise the input file itself.
+fn out_file(args: &Cli, input: &InputFile) -> DocxResult<String> {
+    if let Some(of) = args.command.out_file() {
+        return Ok(of.clone());
+    }
+
+    match &args.out_dir {
+        Some(dir) => {
+            let path = Path::new(dir).join(&input.rel_path);
+            if let Some(p) = path.parent() {
+                fs::create_dir_all(p)?;
+            }
+            Ok(path.to_string_lossy().to_string())
+        },
+        None => Ok(input.path.clone())
+    }
 }
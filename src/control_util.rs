@@ -0,0 +1,455 @@
+use std::fs;
+
+use crate::docx_package::DocxPackage;
+use crate::error::{DocxError, DocxResult};
+use crate::merge_util::{MergeUtil, Record};
+use crate::reporter::{Control, DocxReporter, Replacement};
+use crate::xml_tree::{Element, Node, XmlTree, PROPERTIES};
+use crate::xml_util::{StoryType, XMLUtil};
+
+/// The properties of a content control that give its type. A control without any of these is a rich text control.
+const KINDS: [&str; 12] = ["text", "richText", "date", "dropDownList", "comboBox", "picture", "docPartObj",
+    "docPartList", "group", "citation", "bibliography", "equation"];
+
+/// The types of content controls that can be filled with text.
+const FILLABLE: [&str; 5] = ["text", "richText", "date", "dropDownList", "comboBox"];
+
+/// Fills the content controls in a part.
+struct Filler<'a> {
+    values: &'a Record,
+    src_file: &'a str,
+    part: &'a str,
+    replacements: Vec<Replacement>,
+}
+
+pub struct ControlUtil;
+
+impl ControlUtil {
+    /// Report the content controls in all the story parts of the document to `reporter`, including the
+    /// controls nested in other controls.
+    pub fn list_controls(pkg: &DocxPackage, src_file: &str, reporter: &mut dyn DocxReporter) -> DocxResult<()> {
+        for part in XMLUtil::get_story_parts(pkg, &StoryType::ALL)? {
+            if let Some(xml) = pkg.get_part(&part) {
+                let nodes = XmlTree::parse(xml, &part)?;
+                Self::report_controls(&nodes, src_file, &part, reporter);
+            }
+        }
+        Ok(())
+    }
+
+    /// Replace the content of the content controls in all the story parts of the document with the value in
+    /// `values` for their tag, or else for their alias. The content keeps the paragraph and run properties of
+    /// the first paragraph and run in it and the control no longer shows its placeholder text. The value of a
+    /// dropdown list or combo box can be the display text or the value of one of its items. A date in the format
+    /// `YYYY-MM-DD` also sets the date of a date control. Plain text, rich text, date, dropdown list and combo
+    /// box controls are filled, other controls and controls around table rows or cells are left as they are.
+    /// Each control that is filled is reported to `reporter` as a replacement of its `tag` or `alias` attribute.
+    pub fn fill_controls(pkg: &mut DocxPackage, src_file: &str, values: &Record, reporter: &mut dyn DocxReporter)
+            -> DocxResult<()> {
+        for part in XMLUtil::get_story_parts(pkg, &StoryType::ALL)? {
+            let mut nodes = match pkg.get_part(&part) {
+                Some(xml) => XmlTree::parse(xml, &part)?,
+                None => continue
+            };
+
+            let mut filler = Filler { values, src_file, part: &part, replacements: vec![] };
+            filler.fill_nodes(&mut nodes);
+            if filler.replacements.is_empty() {
+                continue;
+            }
+
+            for repl in &filler.replacements {
+                reporter.on_replacement(repl);
+            }
+            pkg.set_part(&part, XmlTree::write(nodes)?);
+        }
+        Ok(())
+    }
+
+    /// Read the values to fill the content controls with from `file`. Files ending in `.json` must contain an
+    /// object with the values by tag or alias, other files are read with `parse_values()`.
+    pub fn read_values(file: &str) -> DocxResult<Record> {
+        let data = fs::read_to_string(file)?;
+        if file.to_lowercase().ends_with(".json") {
+            match serde_json::from_str(&data) {
+                Ok(serde_json::Value::Object(fields)) => Ok(MergeUtil::record(fields)),
+                Ok(_) => Err(DocxError::InvalidData("expected an object with the values by tag or alias".to_string())),
+                Err(e) => Err(DocxError::InvalidData(e.to_string())),
+            }
+        } else {
+            Self::parse_values(&data)
+        }
+    }
+
+    /// Parse `data` as lines of `key=value`, ignoring empty lines and lines starting with `#`. The whitespace
+    /// around keys and values is ignored and `\n`, `\t` and `\\` in values stand for a line break, a tab and
+    /// a backslash.
+    pub fn parse_values(data: &str) -> DocxResult<Record> {
+        let data = data.strip_prefix('\u{feff}').unwrap_or(data);
+
+        let mut values = Record::new();
+        for (i, line) in data.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let Some((key, value)) = line.split_once('=') else {
+                return Err(DocxError::InvalidData(format!("line {} is not a key=value pair", i + 1)));
+            };
+            values.insert(key.trim().to_string(), Self::unescape(value.trim()));
+        }
+        Ok(values)
+    }
+
+    fn unescape(value: &str) -> String {
+        let mut out = String::with_capacity(value.len());
+        let mut chars = value.chars();
+        while let Some(c) = chars.next() {
+            match (c, chars.clone().next()) {
+                ('\\', Some('n')) => out.push('\n'),
+                ('\\', Some('t')) => out.push('\t'),
+                ('\\', Some('\\')) => out.push('\\'),
+                _ => {
+                    out.push(c);
+                    continue;
+                }
+            }
+            chars.next();
+        }
+        out
+    }
+
+    fn report_controls(nodes: &[Node], src_file: &str, part: &str, reporter: &mut dyn DocxReporter) {
+        for node in nodes {
+            let Node::Element(el) = node else {
+                continue;
+            };
+            if el.is("sdt") {
+                reporter.on_control(&Control {
+                    src_file: src_file.to_string(),
+                    part: part.to_string(),
+                    tag: Self::property(el, "tag"),
+                    alias: Self::property(el, "alias"),
+                    kind: Self::kind(el),
+                    value: Self::value(el),
+                    placeholder: Self::showing_placeholder(el),
+                });
+            }
+            Self::report_controls(&el.children, src_file, part, reporter);
+        }
+    }
+
+    /// The value of property `local` of content control `el`.
+    fn property(el: &Element, local: &str) -> Option<String> {
+        el.child("sdtPr")?.child(local)?.attrs.get("val").cloned()
+    }
+
+    /// The type of content control `el`, the local name of the property that gives its type.
+    fn kind(el: &Element) -> String {
+        let props = el.child("sdtPr").map(|p| p.children.as_slice()).unwrap_or_default();
+        for node in props {
+            let Node::Element(p) = node else {
+                continue;
+            };
+            if let Some(kind) = KINDS.iter().find(|k| p.is(k)) {
+                return kind.to_string();
+            }
+            // Check boxes are defined in the namespace of the Word 2010 extensions
+            if p.local.is_none() && p.start.local_name().as_ref() == b"checkbox" {
+                return "checkbox".to_string();
+            }
+        }
+        "richText".to_string()
+    }
+
+    fn showing_placeholder(el: &Element) -> bool {
+        el.child("sdtPr").and_then(|p| p.child("showingPlcHdr"))
+            .is_some_and(|s| !matches!(s.attrs.get("val").map(String::as_str), Some("false" | "0" | "off")))
+    }
+
+    /// The text of the content of content control `el`, with its paragraphs separated by line breaks.
+    fn value(el: &Element) -> String {
+        let mut text = String::new();
+        if let Some(content) = el.child("sdtContent") {
+            Self::text(&content.children, &mut text, &mut 0);
+        }
+        text
+    }
+
+    fn text(nodes: &[Node], out: &mut String, paragraphs: &mut usize) {
+        for node in nodes {
+            let Node::Element(el) = node else {
+                continue;
+            };
+            if el.is("p") {
+                if *paragraphs > 0 {
+                    out.push('\n');
+                }
+                *paragraphs += 1;
+            }
+
+            if el.is("t") {
+                out.push_str(&el.text());
+            } else if el.is("tab") {
+                out.push('\t');
+            } else if el.is("br") || el.is("cr") {
+                out.push('\n');
+            } else if !el.is_any(&PROPERTIES) {
+                Self::text(&el.children, out, paragraphs);
+            }
+        }
+    }
+}
+
+impl Filler<'_> {
+    fn fill_nodes(&mut self, nodes: &mut [Node]) {
+        for node in nodes {
+            let Node::Element(el) = node else {
+                continue;
+            };
+            if el.is("sdt") && self.fill(el) {
+                continue;
+            }
+            self.fill_nodes(&mut el.children);
+        }
+    }
+
+    /// Fill content control `el` if there is a value for it. Returns `true` if it was filled.
+    fn fill(&mut self, el: &mut Element) -> bool {
+        let names = [ControlUtil::property(el, "tag"), ControlUtil::property(el, "alias")];
+        let Some((name, value)) = names.into_iter().flatten()
+                .find_map(|n| self.values.get(&n).map(|v| (n, v))) else {
+            return false;
+        };
+
+        let kind = ControlUtil::kind(el);
+        let Some(content) = el.child("sdtContent") else {
+            return false;
+        };
+        let repeating = content.children.iter().any(|n| matches!(n, Node::Element(e) if e.is_any(&["tr", "tc"])));
+        if !FILLABLE.contains(&kind.as_str()) || repeating {
+            return false;
+        }
+
+        let old = ControlUtil::value(el);
+        let placeholder = ControlUtil::showing_placeholder(el);
+        let run_props = Self::run_properties(el, placeholder);
+        let para_props = content.descendant("p").map(|p| p.child("pPr").cloned());
+        let prefix = el.prefix();
+
+        let Some(props) = el.child_mut("sdtPr") else {
+            return false;
+        };
+        props.children.retain(|n| !matches!(n, Node::Element(e) if e.is("showingPlcHdr")));
+        let text = Self::update_properties(props, &kind, value);
+
+        let children = match para_props {
+            // A control around paragraphs gets a paragraph for each line of the value
+            Some(ppr) => text.split('\n').map(|line| {
+                let mut children: Vec<Node> = ppr.iter().cloned().map(Node::Element).collect();
+                children.extend(Self::runs(&prefix, &run_props, line));
+                Node::Element(Element::new(&prefix, "p", children))
+            }).collect(),
+            None => Self::runs(&prefix, &run_props, &text),
+        };
+        if let Some(content) = el.child_mut("sdtContent") {
+            content.empty = children.is_empty();
+            content.children = children;
+        }
+
+        self.replacements.push(Replacement {
+            src_file: self.src_file.to_string(),
+            part: self.part.to_string(),
+            paragraph: None,
+            attribute: Some(name),
+            old,
+            new: text,
+        });
+        true
+    }
+
+    /// Update the properties `props` of a content control of type `kind` for `value`. Returns the text to
+    /// show in the control, which is the display text of the item of a list that has `value` as value.
+    fn update_properties(props: &mut Element, kind: &str, value: &str) -> String {
+        let Some(el) = props.child_mut(kind) else {
+            return value.to_string();
+        };
+
+        match kind {
+            "date" => {
+                let is_date = value.len() >= 10 && value.is_char_boundary(10) && value[..10].char_indices()
+                    .all(|(i, c)| if i == 4 || i == 7 { c == '-' } else { c.is_ascii_digit() });
+                let full_date = is_date.then(|| format!("{}T00:00:00Z", &value[..10]));
+                el.set_attribute("fullDate", full_date.as_deref());
+                value.to_string()
+            },
+            "dropDownList" | "comboBox" => {
+                let item = el.children.iter().find_map(|n| match n {
+                    Node::Element(e) if e.is("listItem") && (e.attrs.get("value").map(String::as_str) == Some(value)
+                            || e.attrs.get("displayText").map(String::as_str) == Some(value)) => Some(e),
+                    _ => None,
+                });
+                let (item_value, display) = match item {
+                    Some(e) => {
+                        let item_value = e.attrs.get("value").cloned().unwrap_or_else(|| value.to_string());
+                        let display = e.attrs.get("displayText").cloned().unwrap_or_else(|| item_value.clone());
+                        (item_value, display)
+                    },
+                    None => (value.to_string(), value.to_string()),
+                };
+                el.set_attribute("lastValue", Some(&item_value));
+                display
+            },
+            _ => value.to_string(),
+        }
+    }
+
+    /// The run properties for the new content of content control `el`. These are the properties of the
+    /// control itself when it shows its placeholder, otherwise the properties of the first run of the content.
+    /// The style of the placeholder text is not kept.
+    fn run_properties(el: &Element, placeholder: bool) -> Option<Element> {
+        let control_props = el.child("sdtPr").and_then(|p| p.child("rPr"));
+        let run_props = el.child("sdtContent").and_then(|c| c.descendant("r")).and_then(|r| r.child("rPr"));
+
+        let mut props = match (placeholder, control_props, run_props) {
+            (true, Some(props), _) | (_, _, Some(props)) => props.clone(),
+            _ => return None,
+        };
+        props.children.retain(|n| !matches!(n, Node::Element(e)
+            if e.is("rStyle") && e.attrs.get("val").map(String::as_str) == Some("PlaceholderText")));
+        (!props.children.is_empty()).then_some(props)
+    }
+
+    /// A run with `text` and properties `props`, with the tabs and line breaks in the text as elements.
+    /// Returns no run if `text` is empty.
+    fn runs(prefix: &str, props: &Option<Element>, text: &str) -> Vec<Node> {
+        if text.is_empty() {
+            return vec![];
+        }
+
+        let mut children: Vec<Node> = props.iter().cloned().map(Node::Element).collect();
+        let mut start = 0;
+        for (i, c) in text.match_indices(['\t', '\n']).chain([(text.len(), "")]) {
+            if i > start {
+                let mut t = Element::new(prefix, "t", vec![]);
+                t.set_text(&text[start..i]);
+                children.push(Node::Element(t));
+            }
+            match c {
+                "\t" => children.push(Node::Element(Element::new(prefix, "tab", vec![]))),
+                "\n" => children.push(Node::Element(Element::new(prefix, "br", vec![]))),
+                _ => (),
+            }
+            start = i + c.len();
+        }
+        vec![Node::Element(Element::new(prefix, "r", children))]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ControlUtil;
+    use crate::docx_package::DocxPackage;
+    use crate::error::{DocxError, DocxResult};
+    use crate::reporter::CollectingReporter;
+    use crate::test_util::{document, part_str, record};
+    use crate::xml_util::{XMLUtil, WORDDOC_NS};
+
+    #[test]
+    fn test_list_controls() -> DocxResult<()> {
+        let pkg = DocxPackage::from_dir("./src/test/test_tree14")?;
+
+        let mut rep = CollectingReporter::default();
+        ControlUtil::list_controls(&pkg, "form.docx", &mut rep)?;
+        let controls: Vec<_> = rep.controls.iter()
+            .map(|c| (c.tag.as_deref(), c.alias.as_deref(), c.kind.as_str(), c.value.as_str(), c.placeholder))
+            .collect();
+        assert_eq!(vec![
+            (Some("intro"), Some("Introduction"), "richText", "Click here to enter text.", true),
+            (Some("customer_name"), Some("Customer name"), "text", "Enter the name", true),
+            (Some("due_date"), None, "date", "2024-01-31", false),
+            (None, Some("Status"), "dropDownList", "Open", false),
+            (Some("agree"), None, "checkbox", "☐", false),
+            (Some("customer_name"), None, "text", "Old Name", false),
+        ], controls);
+        assert_eq!("word/header1.xml", rep.controls[5].part);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_fill_controls() -> DocxResult<()> {
+        let mut pkg = DocxPackage::from_dir("./src/test/test_tree14")?;
+
+        let vals = record(&[("customer_name", "Jane & Co"), ("Introduction", "First line\nSecond\tline"),
+            ("due_date", "2024-03-01"), ("Status", "closed"), ("agree", "yes"), ("unknown", "x")]);
+        let mut rep = CollectingReporter::default();
+        ControlUtil::fill_controls(&mut pkg, "form.docx", &vals, &mut rep)?;
+        let repls: Vec<_> = rep.replacements.iter()
+            .map(|r| (r.attribute.as_deref().unwrap_or(""), r.old.as_str(), r.new.as_str()))
+            .collect();
+        assert_eq!(vec![
+            ("Introduction", "Click here to enter text.", "First line\nSecond\tline"),
+            ("customer_name", "Enter the name", "Jane & Co"),
+            ("due_date", "2024-01-31", "2024-03-01"),
+            ("Status", "Open", "Closed"),
+            ("customer_name", "Old Name", "Jane & Co"),
+        ], repls);
+
+        let mut rep = CollectingReporter::default();
+        XMLUtil::cat(&pkg, "form.docx", &mut rep)?;
+        let texts: Vec<_> = rep.paragraphs.iter().map(|p| p.text.as_str()).collect();
+        assert_eq!(vec!["First line", "Secondline", "Dear Jane & Co,", "Due on 2024-03-01, status Closed", "☐ I agree",
+            "Customer: Jane & Co"], texts);
+
+        let doc = document(&pkg);
+        assert!(!doc.contains("showingPlcHdr"), "{}", doc);
+        assert!(!doc.contains("PlaceholderText"), "{}", doc);
+        assert!(doc.contains(r#"<w:p><w:pPr><w:pStyle w:val="BodyText"/></w:pPr><w:r><w:t xml:space="preserve">Second</w:t><w:tab/>"#),
+            "{}", doc);
+        assert!(doc.contains(r#"<w:r><w:rPr><w:b/></w:rPr><w:t xml:space="preserve">Jane &amp; Co</w:t></w:r>"#), "{}", doc);
+        assert!(doc.contains(r#"<w:date w:fullDate="2024-03-01T00:00:00Z">"#), "{}", doc);
+        assert!(doc.contains(r#"<w:dropDownList w:lastValue="closed">"#), "{}", doc);
+
+        let header = part_str(&pkg, "word/header1.xml");
+        assert!(header.contains(r#"<w:r><w:rPr><w:i/></w:rPr><w:t xml:space="preserve">Jane &amp; Co</w:t></w:r>"#),
+            "{}", header);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_fill_controls_default_namespace() -> DocxResult<()> {
+        // The elements are in the default namespace, the attributes use another prefix
+        let mut pkg = DocxPackage::from_dir("./src/test/test_tree18")?;
+
+        let vals = record(&[("Introduction", "First\nSecond"), ("customer_name", "Alice"), ("due_date", "2024-03-01"),
+            ("status", "closed")]);
+        ControlUtil::fill_controls(&mut pkg, "default-ns.docx", &vals, &mut CollectingReporter::default())?;
+
+        let doc = document(&pkg);
+        assert!(!doc.contains("<:") && !doc.contains(" :"), "{}", doc);
+        assert!(doc.contains(r#"<r><t xml:space="preserve">Alice</t></r>"#), "{}", doc);
+        assert!(doc.contains(r#"<p><r><t xml:space="preserve">First</t></r></p>"#), "{}", doc);
+        assert!(doc.contains(&format!(r#"<date xmlns:w="{}" w:fullDate="2024-03-01T00:00:00Z">"#, WORDDOC_NS)), "{}", doc);
+        assert!(doc.contains(&format!(r#"<dropDownList xmlns:w="{}" w:lastValue="closed">"#, WORDDOC_NS)), "{}", doc);
+
+        let mut rep = CollectingReporter::default();
+        XMLUtil::cat(&pkg, "default-ns.docx", &mut rep)?;
+        let texts: Vec<_> = rep.paragraphs.iter().map(|p| p.text.as_str()).collect();
+        assert_eq!(vec!["First", "Second", "Dear Alice,", "Due on 2024-03-01, status Closed", "Name: «foo»"], texts);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_values() -> DocxResult<()> {
+        let vals = ControlUtil::parse_values("# Customer\ncustomer_name = Jane Doe\n\naddress=1 Main St\\nSpringfield\r\n\
+            path=C:\\\\data\\x\n")?;
+        assert_eq!(record(&[("customer_name", "Jane Doe"), ("address", "1 Main St\nSpringfield"),
+            ("path", "C:\\data\\x")]), vals);
+
+        assert!(matches!(ControlUtil::parse_values("a=1\nb\n"), Err(DocxError::InvalidData(_))));
+        Ok(())
+    }
+}
@@ -0,0 +1,599 @@
+use quick_xml::events::{BytesStart, Event};
+use quick_xml::name::QName;
+use quick_xml::reader::NsReader;
+use std::collections::HashMap;
+
+use crate::docx_package::DocxPackage;
+use crate::error::{DocxError, DocxResult};
+use crate::xml_util::{StoryType, XMLUtil};
+
+const OFFICE_RELATIONSHIPS_NS: &str = "http://schemas.openxmlformats.org/officeDocument/2006/relationships";
+const DRAWINGML_NS: &str = "http://schemas.openxmlformats.org/drawingml/2006/main";
+const WP_DRAWING_NS: &str = "http://schemas.openxmlformats.org/drawingml/2006/wordprocessingDrawing";
+
+/// Fonts that are taken to mark a run as code.
+const MONOSPACE_FONTS: [&str; 7] = ["Consolas", "Courier", "Courier New", "Lucida Console", "Menlo", "Monaco",
+    "Source Code Pro"];
+
+/// A block of content of a document.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Block {
+    /// A paragraph with the styleId of its paragraph style and the runs of text in it
+    Paragraph { kind: ParagraphKind, style: Option<String>, runs: Vec<Run> },
+
+    /// A table as rows of cells. The cells of a row are mapped onto the grid of the table, so a cell that
+    /// spans multiple columns or rows covers the cells to the right of it or below it.
+    Table { rows: Vec<Vec<Cell>> },
+}
+
+/// A cell of a table.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Cell {
+    pub blocks: Vec<Block>,
+    pub col_span: usize,
+    pub row_span: usize,
+
+    /// Set if the cell is covered by a cell to the left of it or above it that spans multiple columns or rows
+    pub covered: bool,
+}
+
+impl Cell {
+    fn new(blocks: Vec<Block>, col_span: usize) -> Self {
+        Cell { blocks, col_span, row_span: 1, covered: false }
+    }
+
+    fn covered() -> Self {
+        Cell { covered: true, ..Cell::new(vec![], 1) }
+    }
+}
+
+/// The structural role of a paragraph, derived from its style and numbering.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ParagraphKind {
+    Normal,
+
+    /// A heading with its level, from 1 to 6. The title of the document is a heading of level 1.
+    Heading(usize),
+
+    Quote,
+
+    /// An item of a numbered or bulleted list, with its level of nesting starting at 0
+    ListItem { ordered: bool, level: usize },
+}
+
+/// A run of text with the same formatting. A run can also hold an image or mark the position of a bookmark,
+/// these runs have no text.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Run {
+    /// The text, tabs and line breaks in the run are kept as `\t` and `\n`
+    pub text: String,
+    pub bold: bool,
+    pub italic: bool,
+    pub underline: bool,
+
+    /// Set if the run has a code character style or a monospace font
+    pub code: bool,
+
+    /// The styleId of the character style of the run
+    pub style: Option<String>,
+
+    /// The target of the hyperlink the run is part of, if any
+    pub link: Option<String>,
+
+    pub image: Option<Image>,
+
+    /// The name of the bookmark that starts at the run
+    pub bookmark: Option<String>,
+}
+
+impl Run {
+    fn same_format(&self, other: &Run) -> bool {
+        self.bold == other.bold && self.italic == other.italic && self.underline == other.underline
+            && self.code == other.code && self.style == other.style && self.link == other.link
+    }
+
+    fn is_text(&self) -> bool {
+        self.image.is_none() && self.bookmark.is_none()
+    }
+}
+
+/// An image in the document.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Image {
+    /// The name of the part that holds the image, e.g. `word/media/image1.png`
+    pub part: String,
+
+    /// The alternative text of the image
+    pub description: Option<String>,
+}
+
+/// A paragraph that is being read.
+#[derive(Default)]
+struct ParagraphBuilder {
+    style: Option<String>,
+    num_id: Option<String>,
+    level: usize,
+    runs: Vec<Run>,
+}
+
+/// A table cell that is being read.
+struct CellBuilder {
+    blocks: Vec<Block>,
+    span: usize,
+    merged: bool,
+}
+
+/// Reads the content of a part into blocks.
+struct ModelReader<'a> {
+    part: &'a str,
+    styles: &'a HashMap<String, String>,
+    numbering: &'a HashMap<(String, usize), bool>,
+    rels: HashMap<String, String>,
+
+    blocks: Vec<Block>,
+    tables: Vec<Vec<Vec<Cell>>>,
+    cells: Vec<CellBuilder>,
+    paragraph: Option<ParagraphBuilder>,
+    run: Option<Run>,
+    link: Option<String>,
+    image: Option<Image>,
+
+    /// Bookmarks that start before the next paragraph
+    bookmarks: Vec<String>,
+    inside_ppr: bool,
+    inside_text: bool,
+
+    /// The depth of the elements that are being skipped, such as text boxes
+    skip: usize,
+}
+
+pub struct DocumentModel;
+
+impl DocumentModel {
+    /// Read the main document of `pkg` into a list of blocks. Paragraph styles are mapped to headings and quotes by
+    /// their name, numbered paragraphs become list items. Only direct formatting of the runs is taken into account.
+    /// Text boxes, linked images and the previous properties of tracked formatting changes are skipped.
+    pub fn read(pkg: &DocxPackage) -> DocxResult<Vec<Block>> {
+        let styles = Self::get_styles(pkg)?;
+        let numbering = Self::get_numbering(pkg)?;
+
+        let mut blocks = vec![];
+        for part in XMLUtil::get_story_parts(pkg, &[StoryType::Main])? {
+            let mut model = ModelReader {
+                part: &part,
+                styles: &styles,
+                numbering: &numbering,
+                rels: XMLUtil::get_relationships(pkg, &part)?,
+                blocks: vec![],
+                tables: vec![],
+                cells: vec![],
+                paragraph: None,
+                run: None,
+                link: None,
+                image: None,
+                bookmarks: vec![],
+                inside_ppr: false,
+                inside_text: false,
+                skip: 0,
+            };
+            model.read(pkg)?;
+            blocks.append(&mut model.blocks);
+        }
+        Ok(blocks)
+    }
+
+    /// Returns a map from styleId to the lowercase display name of the style.
+    fn get_styles(pkg: &DocxPackage) -> DocxResult<HashMap<String, String>> {
+        if pkg.get_part("word/styles.xml").is_none() {
+            return Ok(HashMap::new());
+        }
+
+        Ok(XMLUtil::get_all_styles(pkg)?.into_iter()
+            .map(|(name, id)| (id, name.to_lowercase()))
+            .collect())
+    }
+
+    /// Returns a map from numId and level to whether the list at that level is numbered, as opposed to bulleted.
+    fn get_numbering(pkg: &DocxPackage) -> DocxResult<HashMap<(String, usize), bool>> {
+        let part = "word/numbering.xml";
+        let mut numbering = HashMap::new();
+        if pkg.get_part(part).is_none() {
+            return Ok(numbering);
+        }
+
+        let mut reader = XMLUtil::get_reader(pkg, part)?;
+        let mut buf = Vec::new();
+
+        // The number format of each level of the abstract numbering definitions
+        let mut formats: HashMap<(String, usize), String> = HashMap::new();
+        // The abstract numbering definition of each numId
+        let mut nums: HashMap<String, String> = HashMap::new();
+
+        let mut abstract_num = None;
+        let mut level = None;
+        let mut num = None;
+        loop {
+            match reader.read_event_into(&mut buf) {
+                Err(e) => return Err(DocxError::xml(part, reader.error_position(), e)),
+                Ok(Event::Eof) => break,
+                Ok(Event::Start(e)) | Ok(Event::Empty(e)) => {
+                    let attr = |name| XMLUtil::get_attribute(&e, &reader, name, part).ok();
+                    if XMLUtil::is_w(&reader, e.name(), "abstractNum") {
+                        abstract_num = attr("abstractNumId");
+                    } else if XMLUtil::is_w(&reader, e.name(), "lvl") {
+                        level = attr("ilvl").and_then(|l| l.parse::<usize>().ok());
+                    } else if XMLUtil::is_w(&reader, e.name(), "numFmt") {
+                        if let (Some(an), Some(l), Some(fmt)) = (&abstract_num, level, attr("val")) {
+                            formats.insert((an.clone(), l), fmt);
+                        }
+                    } else if XMLUtil::is_w(&reader, e.name(), "num") {
+                        num = attr("numId");
+                    } else if XMLUtil::is_w(&reader, e.name(), "abstractNumId") {
+                        if let (Some(n), Some(an)) = (&num, attr("val")) {
+                            nums.insert(n.clone(), an);
+                        }
+                    }
+                },
+                Ok(Event::End(e)) => {
+                    if XMLUtil::is_w(&reader, e.name(), "abstractNum") {
+                        abstract_num = None;
+                    } else if XMLUtil::is_w(&reader, e.name(), "lvl") {
+                        level = None;
+                    } else if XMLUtil::is_w(&reader, e.name(), "num") {
+                        num = None;
+                    }
+                },
+                _ => ()
+            }
+        }
+
+        for (num_id, abstract_id) in nums {
+            for ((an, l), fmt) in &formats {
+                if *an == abstract_id {
+                    numbering.insert((num_id.clone(), *l), fmt != "bullet" && fmt != "none");
+                }
+            }
+        }
+        Ok(numbering)
+    }
+}
+
+impl ModelReader<'_> {
+    fn read(&mut self, pkg: &DocxPackage) -> DocxResult<()> {
+        let mut reader = XMLUtil::get_reader(pkg, self.part)?;
+        let mut buf = Vec::new();
+        loop {
+            match reader.read_event_into(&mut buf) {
+                Err(e) => return Err(DocxError::xml(self.part, reader.error_position(), e)),
+                Ok(Event::Eof) => break,
+                Ok(Event::Start(e)) => self.start(&reader, &e),
+                Ok(Event::Empty(e)) => {
+                    self.start(&reader, &e);
+                    self.end(&reader, e.name());
+                },
+                Ok(Event::End(e)) => self.end(&reader, e.name()),
+                Ok(Event::Text(t)) if self.inside_text && self.skip == 0 => {
+                    if let Some(run) = &mut self.run {
+                        let val = t.unescape().map_err(|e| DocxError::xml(self.part, reader.buffer_position(), e))?;
+                        run.text.push_str(&val);
+                    }
+                },
+                _ => ()
+            }
+        }
+        Ok(())
+    }
+
+    fn start(&mut self, reader: &NsReader<&[u8]>, e: &BytesStart) {
+        let is = |local| XMLUtil::is_w(reader, e.name(), local);
+        let attr = |name| XMLUtil::get_attribute(e, reader, name, self.part).ok();
+
+        if self.skip > 0 || is("txbxContent") || is("rPrChange") || is("pPrChange") {
+            self.skip += 1;
+        } else if is("tbl") {
+            self.tables.push(vec![]);
+        } else if is("tr") {
+            if let Some(table) = self.tables.last_mut() {
+                table.push(vec![]);
+            }
+        } else if is("gridBefore") {
+            // Cells of the grid that are skipped at the start of the row
+            let skipped = attr("val").and_then(|v| v.parse().ok()).unwrap_or(0);
+            if let Some(row) = self.tables.last_mut().and_then(|t| t.last_mut()) {
+                row.resize_with(row.len() + skipped, || Cell::new(vec![], 1));
+            }
+        } else if is("tc") {
+            self.cells.push(CellBuilder { blocks: vec![], span: 1, merged: false });
+        } else if is("gridSpan") {
+            if let Some(cell) = self.cells.last_mut() {
+                cell.span = attr("val").and_then(|v| v.parse().ok()).unwrap_or(1).max(1);
+            }
+        } else if is("vMerge") {
+            if let Some(cell) = self.cells.last_mut() {
+                cell.merged = attr("val").as_deref() != Some("restart");
+            }
+        } else if is("p") {
+            let runs = self.bookmarks.drain(..)
+                .map(|b| Run { bookmark: Some(b), ..Run::default() })
+                .collect();
+            self.paragraph = Some(ParagraphBuilder { runs, ..ParagraphBuilder::default() });
+        } else if is("bookmarkStart") {
+            // _GoBack marks the last edit position in Word
+            if let Some(name) = attr("name").filter(|n| n != "_GoBack") {
+                match &mut self.paragraph {
+                    Some(para) => para.runs.push(Run { bookmark: Some(name), ..Run::default() }),
+                    None => self.bookmarks.push(name),
+                }
+            }
+        } else if is("pPr") {
+            self.inside_ppr = true;
+        } else if is("hyperlink") {
+            let id = e.attributes().flatten()
+                .find(|a| XMLUtil::is_attribute(reader, a.key, Some(OFFICE_RELATIONSHIPS_NS), "id"))
+                .map(|a| String::from_utf8_lossy(&a.value).to_string());
+            self.link = match id {
+                Some(id) => self.rels.get(&id).cloned(),
+                None => attr("anchor").map(|a| format!("#{}", a)),
+            };
+        } else if is("r") {
+            self.run = Some(Run { link: self.link.clone(), ..Run::default() });
+        } else if is("t") {
+            self.inside_text = true;
+        } else if is("drawing") {
+            self.image = Some(Image::default());
+        } else if let Some(image) = &mut self.image {
+            if XMLUtil::is_element(reader, e.name(), WP_DRAWING_NS, "docPr") {
+                image.description = e.attributes().flatten()
+                    .find(|a| XMLUtil::is_attribute(reader, a.key, None, "descr"))
+                    .and_then(|a| a.unescape_value().ok().map(|v| v.to_string()));
+            } else if XMLUtil::is_element(reader, e.name(), DRAWINGML_NS, "blip") {
+                let target = e.attributes().flatten()
+                    .find(|a| XMLUtil::is_attribute(reader, a.key, Some(OFFICE_RELATIONSHIPS_NS), "embed"))
+                    .and_then(|a| self.rels.get(String::from_utf8_lossy(&a.value).as_ref()));
+                if let Some(t) = target {
+                    image.part = XMLUtil::resolve_target(self.part, t);
+                }
+            }
+        } else if let Some(run) = &mut self.run {
+            if is("b") {
+                run.bold = Self::is_on(attr("val"));
+            } else if is("i") {
+                run.italic = Self::is_on(attr("val"));
+            } else if is("u") {
+                run.underline = attr("val").is_none_or(|v| v != "none");
+            } else if is("rStyle") {
+                run.style = attr("val");
+                let name = run.style.as_ref().and_then(|id| self.styles.get(id));
+                run.code |= name.is_some_and(|n| n.contains("code"));
+            } else if is("rFonts") {
+                run.code |= attr("ascii").is_some_and(|f| MONOSPACE_FONTS.contains(&f.as_str()));
+            } else if is("tab") {
+                run.text.push('\t');
+            } else if is("br") || is("cr") {
+                run.text.push('\n');
+            } else if is("noBreakHyphen") {
+                run.text.push('-');
+            }
+        } else if self.inside_ppr {
+            if let Some(para) = &mut self.paragraph {
+                if is("pStyle") {
+                    para.style = attr("val");
+                } else if is("numId") {
+                    para.num_id = attr("val");
+                } else if is("ilvl") {
+                    para.level = attr("val").and_then(|v| v.parse().ok()).unwrap_or(0);
+                }
+            }
+        }
+    }
+
+    fn end(&mut self, reader: &NsReader<&[u8]>, name: QName) {
+        let is = |local| XMLUtil::is_w(reader, name, local);
+
+        if self.skip > 0 {
+            self.skip -= 1;
+        } else if is("tbl") {
+            if let Some(mut rows) = self.tables.pop() {
+                let width = rows.iter().map(|r| r.len()).max().unwrap_or(0);
+                for row in &mut rows {
+                    row.resize_with(width, || Cell::new(vec![], 1));
+                }
+                self.container().push(Block::Table { rows });
+            }
+        } else if is("tc") {
+            if let (Some(cell), Some(table)) = (self.cells.pop(), self.tables.last_mut()) {
+                Self::add_cell(table, cell);
+            }
+        } else if is("p") {
+            if let Some(para) = self.paragraph.take() {
+                let kind = self.paragraph_kind(&para);
+                self.container().push(Block::Paragraph { kind, style: para.style, runs: para.runs });
+            }
+        } else if is("pPr") {
+            self.inside_ppr = false;
+        } else if is("hyperlink") {
+            self.link = None;
+        } else if is("drawing") {
+            if let (Some(image), Some(para)) = (self.image.take(), &mut self.paragraph) {
+                if !image.part.is_empty() {
+                    let link = self.run.as_ref().and_then(|r| r.link.clone());
+                    para.runs.push(Run { image: Some(image), link, ..Run::default() });
+                }
+            }
+        } else if is("r") {
+            if let (Some(run), Some(para)) = (self.run.take(), &mut self.paragraph) {
+                match para.runs.last_mut() {
+                    _ if run.text.is_empty() => (),
+                    Some(last) if last.is_text() && last.same_format(&run) => last.text.push_str(&run.text),
+                    _ => para.runs.push(run),
+                }
+            }
+        } else if is("t") {
+            self.inside_text = false;
+        }
+    }
+
+    /// Add `cell` to the last row of `table`. A cell that continues a vertical merge is covered by the cell
+    /// above it that starts the merge.
+    fn add_cell(table: &mut [Vec<Cell>], cell: CellBuilder) {
+        let Some((row, previous)) = table.split_last_mut() else {
+            return;
+        };
+        let col = row.len();
+
+        if cell.merged {
+            let start = previous.iter_mut().rev()
+                .filter_map(|r| r.get_mut(col))
+                .find(|c| !c.covered);
+            if let Some(start) = start {
+                start.row_span += 1;
+            }
+            row.resize_with(col + cell.span, Cell::covered);
+        } else {
+            row.push(Cell::new(cell.blocks, cell.span));
+            row.resize_with(col + cell.span, Cell::covered);
+        }
+    }
+
+    /// The blocks that are currently being added to, either those of a table cell or of the document.
+    fn container(&mut self) -> &mut Vec<Block> {
+        match self.cells.last_mut() {
+            Some(cell) => &mut cell.blocks,
+            None => &mut self.blocks,
+        }
+    }
+
+    fn paragraph_kind(&self, para: &ParagraphBuilder) -> ParagraphKind {
+        let style = para.style.as_ref().and_then(|s| self.styles.get(s)).map(|s| s.as_str()).unwrap_or("");
+        if style == "title" {
+            return ParagraphKind::Heading(1);
+        }
+        if let Some(level) = style.strip_prefix("heading ").and_then(|l| l.parse::<usize>().ok()) {
+            return ParagraphKind::Heading(level.clamp(1, 6));
+        }
+        if style == "quote" || style == "intense quote" {
+            return ParagraphKind::Quote;
+        }
+
+        match para.num_id.as_ref().and_then(|n| self.numbering.get(&(n.clone(), para.level))) {
+            Some(&ordered) => ParagraphKind::ListItem { ordered, level: para.level },
+            None => ParagraphKind::Normal,
+        }
+    }
+
+    /// Check the value of a toggle property such as `<w:b/>`, which is on if it has no value.
+    fn is_on(val: Option<String>) -> bool {
+        !matches!(val.as_deref(), Some("0") | Some("false") | Some("off"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Block, DocumentModel, Image, ParagraphKind, Run};
+    use crate::docx_package::DocxPackage;
+    use crate::error::DocxResult;
+
+    #[test]
+    fn test_read_lists() -> DocxResult<()> {
+        let pkg = DocxPackage::from_dir("./src/test/test_tree4")?;
+        let blocks = DocumentModel::read(&pkg)?;
+
+        let Block::Paragraph { kind, style, runs } = &blocks[1] else {
+            panic!("Expected a paragraph: {:?}", blocks[1]);
+        };
+        assert_eq!(ParagraphKind::ListItem { ordered: false, level: 0 }, *kind);
+        assert_eq!(Some("ListParagraph"), style.as_deref());
+        assert_eq!(vec![Run { text: "your-somewhere".to_string(), style: Some("Hyperlink".to_string()),
+            link: Some("http://www.example.com/somewhere".to_string()), ..Run::default() }], *runs);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_read() -> DocxResult<()> {
+        let pkg = DocxPackage::from_dir("./src/test/test_tree12")?;
+        let blocks = DocumentModel::read(&pkg)?;
+
+        let kinds: Vec<ParagraphKind> = blocks.iter().filter_map(|b| match b {
+            Block::Paragraph { kind, .. } => Some(*kind),
+            _ => None
+        }).collect();
+        assert_eq!(vec![
+            ParagraphKind::Heading(1),
+            ParagraphKind::Heading(2),
+            ParagraphKind::Normal,
+            ParagraphKind::ListItem { ordered: false, level: 0 },
+            ParagraphKind::ListItem { ordered: false, level: 1 },
+            ParagraphKind::ListItem { ordered: true, level: 0 },
+            ParagraphKind::ListItem { ordered: true, level: 0 },
+            ParagraphKind::Quote,
+            ParagraphKind::Normal,
+            ParagraphKind::Normal,
+            ParagraphKind::Normal,
+        ], kinds);
+
+        let Block::Paragraph { runs, .. } = &blocks[1] else {
+            panic!("Expected a paragraph: {:?}", blocks[1]);
+        };
+        assert_eq!(Some("install"), runs[0].bookmark.as_deref());
+
+        let Block::Paragraph { runs, .. } = &blocks[8] else {
+            panic!("Expected a paragraph: {:?}", blocks[8]);
+        };
+        assert_eq!(vec![Run { image: Some(Image { part: "word/media/image1.png".to_string(),
+            description: Some("A green dot".to_string()) }), ..Run::default() }], *runs);
+
+        let Block::Paragraph { runs, .. } = &blocks[9] else {
+            panic!("Expected a paragraph: {:?}", blocks[9]);
+        };
+        assert_eq!(Run { text: "installation".to_string(), underline: true, link: Some("#install".to_string()),
+            ..Run::default() }, runs[1]);
+
+        let Block::Paragraph { runs, .. } = &blocks[2] else {
+            panic!("Expected a paragraph: {:?}", blocks[2]);
+        };
+        assert_eq!(vec![
+            Run { text: "Some ".to_string(), ..Run::default() },
+            Run { text: "bold".to_string(), bold: true, ..Run::default() },
+            Run { text: ", ".to_string(), ..Run::default() },
+            Run { text: "italic".to_string(), italic: true, ..Run::default() },
+            Run { text: " and ".to_string(), ..Run::default() },
+            Run { text: "cargo ".to_string(), code: true, style: Some("HTMLCode".to_string()), ..Run::default() },
+            Run { text: "build".to_string(), code: true, ..Run::default() },
+            Run { text: " text, see ".to_string(), ..Run::default() },
+            Run { text: "the site".to_string(), style: Some("Hyperlink".to_string()),
+                link: Some("https://example.com/docs".to_string()), ..Run::default() },
+            Run { text: ".".to_string(), ..Run::default() },
+        ], *runs);
+
+        let Some(Block::Table { rows }) = blocks.iter().find(|b| matches!(b, Block::Table { .. })) else {
+            panic!("Expected a table: {:?}", blocks);
+        };
+        assert_eq!(3, rows.len());
+        assert!(rows.iter().all(|r| r.len() == 2));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_read_table_spans() -> DocxResult<()> {
+        let pkg = DocxPackage::from_dir("./src/test/test_tree11")?;
+        let blocks = DocumentModel::read(&pkg)?;
+
+        let Some(Block::Table { rows }) = blocks.iter().find(|b| matches!(b, Block::Table { .. })) else {
+            panic!("Expected a table: {:?}", blocks);
+        };
+        let spans: Vec<Vec<(usize, usize, bool)>> = rows.iter()
+            .map(|r| r.iter().map(|c| (c.col_span, c.row_span, c.covered)).collect())
+            .collect();
+        assert_eq!(vec![
+            vec![(1, 1, false), (2, 1, false), (1, 1, true)],
+            vec![(1, 2, false), (1, 1, false), (1, 1, false)],
+            vec![(1, 1, true), (1, 1, false), (1, 1, false)],
+            vec![(1, 1, false), (1, 1, false), (1, 1, false)],
+            vec![(3, 1, false), (1, 1, true), (1, 1, true)],
+        ], spans);
+
+        Ok(())
+    }
+}
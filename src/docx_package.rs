@@ -0,0 +1,260 @@
+use std::collections::HashSet;
+use std::fs;
+use std::io::{Cursor, Read, Seek, Write};
+use std::path::Path;
+use walkdir::WalkDir;
+
+use crate::error::DocxResult;
+use crate::file_util::FileUtil;
+use crate::zip_util::{ZipEntry, ZipLimits, ZipUtil};
+
+/// A .docx file held in memory. Each part of the document is kept as bytes, keyed by its name
+/// inside the zip file, e.g. `word/document.xml`, together with the zip metadata that it was read with.
+/// Operations can read and replace parts without any files being written to disk until the
+/// package is saved.
+#[derive(Clone)]
+pub struct DocxPackage {
+    entries: Vec<ZipEntry>,
+    comment: Vec<u8>,
+    modified: HashSet<String>,
+
+    /// The .docx file that the package was read from, used to copy the unchanged parts when writing.
+    source: Option<Vec<u8>>,
+}
+
+impl DocxPackage {
+    /// Read the .docx file `file` into memory, applying the default `ZipLimits`.
+    pub fn open(file: &str) -> DocxResult<Self> {
+        Self::open_with_limits(file, &ZipLimits::default())
+    }
+
+    /// Read the .docx file `file` into memory, applying `limits`.
+    pub fn open_with_limits(file: &str, limits: &ZipLimits) -> DocxResult<Self> {
+        let f = fs::File::open(file)?;
+        Self::read_with_limits(f, limits)
+    }
+
+    /// Read a .docx file from `reader` into memory, applying the default `ZipLimits`.
+    pub fn read<R: Read + Seek>(reader: R) -> DocxResult<Self> {
+        Self::read_with_limits(reader, &ZipLimits::default())
+    }
+
+    /// Read a .docx file from `reader` into memory, applying `limits`.
+    pub fn read_with_limits<R: Read + Seek>(mut reader: R, limits: &ZipLimits) -> DocxResult<Self> {
+        let mut source = Vec::new();
+        reader.read_to_end(&mut source)?;
+
+        let (entries, comment) = ZipUtil::read_entries_with_limits(Cursor::new(&source), limits)?;
+
+        Ok(DocxPackage { entries, comment, modified: HashSet::new(), source: Some(source) })
+    }
+
+    /// Read the parts of a .docx file that was previously unzipped into `dir`.
+    pub fn from_dir(dir: &str) -> DocxResult<Self> {
+        let mut entries = vec![];
+
+        for entry in WalkDir::new(dir).sort_by_file_name().into_iter()
+                .filter_map(|e| e.ok())
+                .filter(|e| e.file_type().is_file()) {
+            let sub_path = FileUtil::get_sub_path(entry.path(), dir);
+            let name = sub_path.replace('\\', "/");
+
+            entries.push(ZipEntry::new(&name, fs::read(entry.path())?));
+        }
+
+        // Some consumers of .docx files require the content types to be the first entry
+        if let Some(idx) = entries.iter().position(|e| e.name == "[Content_Types].xml") {
+            let ct = entries.remove(idx);
+            entries.insert(0, ct);
+        }
+
+        Ok(DocxPackage { entries, comment: vec![], modified: HashSet::new(), source: None })
+    }
+
+    /// Write the package as a .docx file to `file`.
+    pub fn save(&self, file: &str) -> DocxResult<()> {
+        let mut data = Vec::new();
+        self.write(Cursor::new(&mut data))?;
+
+        let mut f = fs::File::create(file)?;
+        f.write_all(&data)?;
+        Ok(())
+    }
+
+    /// Write the package as a .docx file to `writer`. Parts that were not changed are copied from the
+    /// file that the package was read from as-is.
+    pub fn write<W: Write + Seek>(&self, writer: W) -> DocxResult<()> {
+        match &self.source {
+            Some(src) => ZipUtil::copy_entries(&self.entries, &self.comment, Cursor::new(src), writer),
+            None => ZipUtil::write_entries(&self.entries, &self.comment, writer),
+        }
+    }
+
+    /// Write the parts that were modified back into `dir`, the directory that the .docx file
+    /// was unzipped in.
+    pub fn write_to_dir(&self, dir: &str) -> DocxResult<()> {
+        for entry in &self.entries {
+            if self.modified.contains(&entry.name) {
+                let path = Path::new(dir).join(FileUtil::normalize_path(&entry.name));
+                if let Some(p) = path.parent() {
+                    fs::create_dir_all(p)?;
+                }
+                fs::write(path, &entry.data)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// The names of all the parts in the package, in the order they appear in the zip file.
+    pub fn part_names(&self) -> Vec<String> {
+        self.entries.iter()
+            .filter(|e| !e.is_dir())
+            .map(|e| e.name.clone())
+            .collect()
+    }
+
+    /// Obtain the content of the part with `name`, if it exists.
+    pub fn get_part(&self, name: &str) -> Option<&[u8]> {
+        self.entries.iter()
+            .find(|e| e.name == name)
+            .map(|e| e.data.as_slice())
+    }
+
+    /// Replace the content of the part with `name`. If the part does not exist yet it is added.
+    pub fn set_part(&mut self, name: &str, data: Vec<u8>) {
+        match self.entries.iter_mut().find(|e| e.name == name) {
+            Some(entry) => {
+                entry.data = data;
+                entry.source_index = None;
+            },
+            None => self.entries.push(ZipEntry::new(name, data)),
+        }
+        self.modified.insert(name.to_string());
+    }
+
+    /// The comments of the entries that have one, as pairs of entry name and comment, and the comment of
+    /// the zip file. A package read from a directory has no comments, unless they are set with `set_comment`.
+    pub fn comments(&self) -> (Vec<(&str, &str)>, &[u8]) {
+        let entries = self.entries.iter()
+            .filter(|e| !e.comment.is_empty())
+            .map(|e| (e.name.as_str(), e.comment.as_str()))
+            .collect();
+        (entries, &self.comment)
+    }
+
+    /// Set the comment of the entry with `name`, if it exists. The comment is written when the package is saved.
+    pub fn set_comment(&mut self, name: &str, comment: &str) {
+        if let Some(entry) = self.entries.iter_mut().find(|e| e.name == name) {
+            entry.comment = comment.to_string();
+        }
+    }
+
+    /// Returns `true` if any of the parts have been changed since the package was read.
+    pub fn is_modified(&self) -> bool {
+        !self.modified.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::DocxPackage;
+    use crate::error::DocxResult;
+    use std::fs;
+    use std::io::{Cursor, Read};
+    use testdir::testdir;
+    use zip::ZipArchive;
+
+    #[test]
+    fn test_open_and_save() -> DocxResult<()> {
+        let pkg = DocxPackage::open("./src/test/test_doc1.docx")?;
+
+        let names = pkg.part_names();
+        assert_eq!(11, names.len());
+        assert_eq!("[Content_Types].xml", names[0]);
+        assert!(names.contains(&"word/document.xml".to_string()));
+        assert!(!pkg.is_modified());
+
+        let outfile = testdir!().join("out.docx");
+        pkg.save(&outfile.to_string_lossy())?;
+
+        let pkg2 = DocxPackage::open(&outfile.to_string_lossy())?;
+        assert_eq!(names, pkg2.part_names());
+        for name in names {
+            assert_eq!(pkg.get_part(&name), pkg2.get_part(&name), "{}", name);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_set_part() -> DocxResult<()> {
+        let mut pkg = DocxPackage::from_dir("./src/test/test_tree2")?;
+        assert!(pkg.get_part("word/document.xml").is_some());
+        assert!(pkg.get_part("word/nonexistent.xml").is_none());
+
+        pkg.set_part("word/some_random_file.txt", b"changed".to_vec());
+        assert!(pkg.is_modified());
+        assert_eq!(Some(&b"changed"[..]), pkg.get_part("word/some_random_file.txt"));
+
+        let outdir = testdir!();
+        pkg.write_to_dir(&outdir.to_string_lossy())?;
+        assert_eq!("changed", fs::read_to_string(outdir.join("word/some_random_file.txt"))?);
+        assert!(!outdir.join("word/document.xml").exists(), "Unmodified parts should not be written");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_comments() -> DocxResult<()> {
+        let mut pkg = DocxPackage::open("./src/test/test_comments.zip")?;
+        assert_eq!((vec![("foo.txt", "A comment on foo")], &b"Archive comment"[..]), pkg.comments());
+
+        // The comments are kept when a part is changed
+        let name = pkg.part_names()[1].clone();
+        pkg.set_part(&name, b"changed".to_vec());
+        pkg.set_comment(&name, "A new comment");
+        let mut out = Vec::new();
+        pkg.write(Cursor::new(&mut out))?;
+
+        let pkg = DocxPackage::read(Cursor::new(&out))?;
+        assert_eq!((vec![("foo.txt", "A comment on foo"), (name.as_str(), "A new comment")], &b"Archive comment"[..]),
+            pkg.comments());
+
+        let pkg = DocxPackage::from_dir("./src/test/test_tree2")?;
+        assert_eq!((vec![], &b""[..]), pkg.comments());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_save_copies_unchanged_parts() -> DocxResult<()> {
+        let src = fs::read("./src/test/test_doc1.docx")?;
+        let mut pkg = DocxPackage::read(Cursor::new(&src))?;
+        pkg.set_part("word/document.xml", b"<w:document/>".to_vec());
+
+        let mut out = Vec::new();
+        pkg.write(Cursor::new(&mut out))?;
+
+        let mut org = ZipArchive::new(Cursor::new(&src))?;
+        let mut res = ZipArchive::new(Cursor::new(&out))?;
+        assert_eq!(org.len(), res.len());
+        for i in 0..org.len() {
+            let mut org_raw = Vec::new();
+            let mut org_file = org.by_index_raw(i)?;
+            org_file.read_to_end(&mut org_raw)?;
+            let mut res_raw = Vec::new();
+            let mut res_file = res.by_index_raw(i)?;
+            res_file.read_to_end(&mut res_raw)?;
+
+            assert_eq!(org_file.name(), res_file.name());
+            if org_file.name() == "word/document.xml" {
+                assert_ne!(org_raw, res_raw);
+            } else {
+                assert_eq!(org_raw, res_raw, "{} should be copied as-is", org_file.name());
+                assert_eq!(org_file.crc32(), res_file.crc32());
+            }
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,123 @@
+use std::error::Error;
+use std::fmt;
+use std::io;
+use zip::result::ZipError;
+
+/// The errors that can occur while working with a .docx file.
+#[derive(Debug)]
+pub enum DocxError {
+    /// Reading or writing a file failed.
+    Io(io::Error),
+
+    /// The .docx (zip) container could not be read or written.
+    Zip(ZipError),
+
+    /// An XML part of the document could not be parsed. `part` is the name of the part,
+    /// for example `word/document.xml` and `position` is the byte offset in that part
+    /// where the problem was found.
+    Xml {
+        part: String,
+        position: u64,
+        source: quick_xml::Error,
+    },
+
+    /// The regular expression provided is not valid.
+    Regex(regex::Error),
+
+    /// A style was requested that is not defined in the document. `known_styles` contains
+    /// the display names of all the styles that are defined.
+    MissingStyle {
+        style: String,
+        known_styles: Vec<String>,
+    },
+
+    /// The `[Content_Types].xml` does not declare the content type that is needed.
+    MissingContentType(String),
+
+    /// A part that is needed is not present in the document.
+    MissingPart(String),
+
+    /// A required attribute was not found on an element.
+    MissingAttribute {
+        part: String,
+        element: String,
+        attribute: String,
+    },
+
+    /// The zip file exceeds one of the `ZipLimits`. The message describes the limit that was exceeded.
+    ZipLimit(String),
+
+    /// The zip file contains more than one entry with this name.
+    DuplicateEntry(String),
+
+    /// A data file, such as the records for a merge, could not be parsed. The message describes the problem.
+    InvalidData(String),
+
+    /// Processing a document failed unexpectedly, for example because of a bug that caused a panic.
+    /// The message describes what went wrong.
+    Internal(String),
+}
+
+pub type DocxResult<T> = Result<T, DocxError>;
+
+impl DocxError {
+    /// Create an XML error for `part` at byte offset `position`.
+    pub fn xml(part: &str, position: u64, source: impl Into<quick_xml::Error>) -> Self {
+        DocxError::Xml { part: part.to_string(), position, source: source.into() }
+    }
+}
+
+impl fmt::Display for DocxError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DocxError::Io(e) => write!(f, "I/O error: {}", e),
+            DocxError::Zip(e) => write!(f, "Zip error: {}", e),
+            DocxError::Xml { part, position, source } =>
+                write!(f, "Error reading {} at position {}: {}", part, position, source),
+            DocxError::Regex(e) => write!(f, "Invalid regular expression: {}", e),
+            DocxError::MissingStyle { style, known_styles } =>
+                write!(f, "Style '{}' not found. Known styles (case insensitive): {:?}", style, known_styles),
+            DocxError::MissingContentType(ct) => write!(f, "Content type not declared: {}", ct),
+            DocxError::MissingPart(p) => write!(f, "Part not found in document: {}", p),
+            DocxError::MissingAttribute { part, element, attribute } =>
+                write!(f, "Attribute {} not found on element {} in {}", attribute, element, part),
+            DocxError::ZipLimit(msg) => write!(f, "Zip file exceeds limit: {}", msg),
+            DocxError::DuplicateEntry(name) => write!(f, "Zip file contains duplicate entry: {}", name),
+            DocxError::InvalidData(msg) => write!(f, "Invalid data: {}", msg),
+            DocxError::Internal(msg) => write!(f, "Internal error: {}", msg),
+        }
+    }
+}
+
+impl Error for DocxError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            DocxError::Io(e) => Some(e),
+            DocxError::Zip(e) => Some(e),
+            DocxError::Xml { source, .. } => Some(source),
+            DocxError::Regex(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for DocxError {
+    fn from(e: io::Error) -> Self {
+        DocxError::Io(e)
+    }
+}
+
+impl From<ZipError> for DocxError {
+    fn from(e: ZipError) -> Self {
+        match e {
+            ZipError::Io(ioe) => DocxError::Io(ioe),
+            _ => DocxError::Zip(e),
+        }
+    }
+}
+
+impl From<regex::Error> for DocxError {
+    fn from(e: regex::Error) -> Self {
+        DocxError::Regex(e)
+    }
+}
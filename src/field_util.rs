@@ -0,0 +1,511 @@
+use regex::Regex;
+
+use crate::docx_package::DocxPackage;
+use crate::error::DocxResult;
+use crate::reporter::{DocxReporter, Field, Replacement};
+use crate::xml_tree::{Element, Node, XmlTree, PROPERTIES};
+use crate::xml_util::{StoryType, XMLUtil};
+
+/// The text of the instruction and the result of a field.
+#[derive(Default)]
+struct FieldText {
+    instruction: String,
+    result: String,
+}
+
+/// A field that is open while reading a part.
+struct OpenField {
+    index: usize,
+
+    /// If the instruction of the field is being read, rather than its result
+    instruction: bool,
+
+    /// The segment of the instruction that is being read, if any
+    segment: Option<usize>,
+}
+
+/// Reads the fields in a part, in the order in which they start. The instruction of a field includes the
+/// instructions of the fields nested in it between braces, its result includes the results of nested fields.
+#[derive(Default)]
+struct FieldReader {
+    fields: Vec<FieldText>,
+    open: Vec<OpenField>,
+
+    /// The parts of the instructions of complex fields between the fields nested in them
+    segments: Vec<String>,
+
+    /// The segment that each `w:instrText` element belongs to, in document order
+    instr_texts: Vec<Option<usize>>,
+}
+
+/// Replaces text in the instructions of the fields in a part.
+struct InstructionReplacer<'a> {
+    regex: &'a Regex,
+    replace: &'a str,
+    instr_texts: std::vec::IntoIter<Option<usize>>,
+
+    /// The new text of the segments that change, set on the first `w:instrText` element of the segment
+    segments: Vec<Option<String>>,
+    written: Vec<bool>,
+}
+
+/// Replaces fields in a part by their results.
+struct Unlinker {
+    /// If the fields, in the order in which they start, are to be unlinked
+    selected: Vec<bool>,
+    next: usize,
+
+    /// The fields that are open, with whether they are unlinked and if their instruction is being read
+    open: Vec<(bool, bool)>,
+
+    /// The fields that were replaced by their results
+    unlinked: Vec<usize>,
+}
+
+pub struct FieldUtil;
+
+impl FieldUtil {
+    /// Report the fields in all the story parts of the document to `reporter`, with their instructions and the
+    /// results that were last calculated by the word processor. Both simple fields, `w:fldSimple`, and complex
+    /// fields, which are marked by `w:fldChar` elements, are reported, including fields nested in other fields.
+    pub fn list_fields(pkg: &DocxPackage, src_file: &str, reporter: &mut dyn DocxReporter) -> DocxResult<()> {
+        for part in XMLUtil::get_story_parts(pkg, &StoryType::ALL)? {
+            if let Some(xml) = pkg.get_part(&part) {
+                let reader = FieldReader::read_part(&XmlTree::parse(xml, &part)?);
+                for field in reader.fields {
+                    let instruction = field.instruction.trim();
+                    reporter.on_field(&Field {
+                        src_file: src_file.to_string(),
+                        part: part.clone(),
+                        kind: Self::field_type(instruction),
+                        instruction: instruction.to_string(),
+                        result: field.result,
+                    });
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Search for regex `pattern` in the instructions of the fields in the story parts of the types in `stories`
+    /// and replace all occurrences with `replacement`, which can refer to the capture groups of the pattern. The
+    /// results of the fields are not updated, the word processor does that when it updates the fields. Matches
+    /// can't span the fields nested in an instruction. Each field that changes is reported to `reporter`.
+    pub fn replace_instructions(pkg: &mut DocxPackage, src_file: &str, pattern: &str, replacement: &str,
+            stories: &[StoryType], reporter: &mut dyn DocxReporter) -> DocxResult<()> {
+        let regex = Regex::new(pattern)?;
+        for part in XMLUtil::get_story_parts(pkg, stories)? {
+            let mut nodes = match pkg.get_part(&part) {
+                Some(xml) => XmlTree::parse(xml, &part)?,
+                None => continue
+            };
+
+            let before = FieldReader::read_part(&nodes);
+            let segments: Vec<Option<String>> = before.segments.iter().map(|s| {
+                let new = regex.replace_all(s, replacement);
+                (new != *s).then(|| new.to_string())
+            }).collect();
+            let mut replacer = InstructionReplacer {
+                regex: &regex, replace: replacement, instr_texts: before.instr_texts.into_iter(),
+                written: vec![false; segments.len()], segments,
+            };
+            replacer.replace_nodes(&mut nodes);
+
+            let after = FieldReader::read_part(&nodes);
+            let mut changed = false;
+            for (old, new) in before.fields.iter().zip(&after.fields) {
+                if old.instruction != new.instruction {
+                    changed = true;
+                    reporter.on_replacement(&Replacement {
+                        src_file: src_file.to_string(), part: part.clone(), paragraph: None, attribute: None,
+                        old: old.instruction.trim().to_string(), new: new.instruction.trim().to_string(),
+                    });
+                }
+            }
+            if changed {
+                pkg.set_part(&part, XmlTree::write(nodes)?);
+            }
+        }
+        Ok(())
+    }
+
+    /// Replace the fields in all the story parts of the document by their results, so that the text no longer
+    /// changes when the fields are updated. If `types` is not empty only the fields of these types, such as
+    /// `MERGEFIELD` or `DATE`, are unlinked. Fields nested in the instruction of a field that is unlinked are
+    /// removed with it. Each field that is unlinked is reported to `reporter` as a replacement of its instruction
+    /// by its result.
+    pub fn unlink_fields(pkg: &mut DocxPackage, src_file: &str, types: &[String], reporter: &mut dyn DocxReporter)
+            -> DocxResult<()> {
+        for part in XMLUtil::get_story_parts(pkg, &StoryType::ALL)? {
+            let nodes = match pkg.get_part(&part) {
+                Some(xml) => XmlTree::parse(xml, &part)?,
+                None => continue
+            };
+
+            let reader = FieldReader::read_part(&nodes);
+            let selected = reader.fields.iter().map(|f| {
+                let kind = Self::field_type(&f.instruction);
+                types.is_empty() || types.iter().any(|t| t.eq_ignore_ascii_case(&kind))
+            }).collect();
+            let mut unlinker = Unlinker { selected, next: 0, open: vec![], unlinked: vec![] };
+            let nodes = unlinker.unlink_nodes(nodes);
+            if unlinker.unlinked.is_empty() {
+                continue;
+            }
+
+            for index in unlinker.unlinked {
+                let field = &reader.fields[index];
+                reporter.on_replacement(&Replacement {
+                    src_file: src_file.to_string(), part: part.clone(), paragraph: None, attribute: None,
+                    old: field.instruction.trim().to_string(), new: field.result.clone(),
+                });
+            }
+            pkg.set_part(&part, XmlTree::write(nodes)?);
+        }
+        Ok(())
+    }
+
+    /// The type of a field with `instruction`, which is its first word in upper case, e.g. `MERGEFIELD`.
+    pub fn field_type(instruction: &str) -> String {
+        instruction.split_whitespace().next().unwrap_or_default().to_uppercase()
+    }
+}
+
+impl FieldReader {
+    fn read_part(nodes: &[Node]) -> FieldReader {
+        let mut reader = FieldReader::default();
+        reader.read(nodes);
+        reader
+    }
+
+    fn read(&mut self, nodes: &[Node]) {
+        for node in nodes {
+            let Node::Element(el) = node else {
+                continue;
+            };
+
+            if el.is("fldChar") {
+                match el.attrs.get("fldCharType").map(String::as_str) {
+                    Some("begin") => self.begin(),
+                    Some("separate") => {
+                        if let Some(field) = self.open.last_mut() {
+                            field.instruction = false;
+                            field.segment = None;
+                        }
+                    },
+                    Some("end") => self.end(),
+                    _ => ()
+                }
+            } else if el.is("fldSimple") {
+                self.begin();
+                self.add_text(el.attrs.get("instr").map(String::as_str).unwrap_or_default(), true);
+                if let Some(field) = self.open.last_mut() {
+                    field.instruction = false;
+                }
+                self.read(&el.children);
+                self.end();
+            } else if el.is("instrText") {
+                let text = el.text();
+                let segment = match self.open.last_mut() {
+                    Some(field) if field.instruction => {
+                        let segment = *field.segment.get_or_insert(self.segments.len());
+                        if segment == self.segments.len() {
+                            self.segments.push(String::new());
+                        }
+                        self.segments[segment].push_str(&text);
+                        Some(segment)
+                    },
+                    _ => None,
+                };
+                self.instr_texts.push(segment);
+                self.add_text(&text, true);
+            } else if el.is_any(&["t", "tab", "br", "cr"]) {
+                // Text in the instruction of a field, such as the result of a nested field that was unlinked,
+                // is part of the instruction
+                let text = if el.is("t") { el.text() } else if el.is("tab") { "\t".to_string() } else { "\n".to_string() };
+                let instruction = self.open.last().is_some_and(|f| f.instruction);
+                self.add_text(&text, instruction);
+            } else if !el.is_any(&PROPERTIES) {
+                // A field that is still open continues in the next paragraph
+                if el.is("p") && !self.open.is_empty() {
+                    self.add_text("\n", false);
+                }
+                self.read(&el.children);
+            }
+        }
+    }
+
+    fn begin(&mut self) {
+        self.add_text("{", true);
+        if let Some(field) = self.open.last_mut() {
+            field.segment = None;
+        }
+        self.open.push(OpenField { index: self.fields.len(), instruction: true, segment: None });
+        self.fields.push(FieldText::default());
+    }
+
+    fn end(&mut self) {
+        self.open.pop();
+        if let Some(field) = self.open.last_mut() {
+            field.segment = None;
+        }
+        self.add_text("}", true);
+    }
+
+    /// Add `text` to the instruction, if `instruction` is set, or to the result of the innermost open field, and
+    /// to the fields it is nested in as long as it is part of their instruction or result as well.
+    fn add_text(&mut self, text: &str, instruction: bool) {
+        for field in self.open.iter().rev() {
+            if field.instruction != instruction {
+                break;
+            }
+            let f = &mut self.fields[field.index];
+            if instruction {
+                f.instruction.push_str(text);
+            } else {
+                f.result.push_str(text);
+            }
+        }
+    }
+}
+
+impl InstructionReplacer<'_> {
+    fn replace_nodes(&mut self, nodes: &mut [Node]) {
+        for node in nodes {
+            let Node::Element(el) = node else {
+                continue;
+            };
+
+            if el.is("instrText") {
+                let Some(segment) = self.instr_texts.next().flatten() else {
+                    continue;
+                };
+                if let Some(new) = &self.segments[segment] {
+                    // The first element of the segment gets the new text of the whole segment
+                    let text = if self.written[segment] { String::new() } else { new.clone() };
+                    el.set_text(&text);
+                    self.written[segment] = true;
+                }
+            } else {
+                if el.is("fldSimple") {
+                    if let Some(instr) = el.attrs.get("instr") {
+                        let new = self.regex.replace_all(instr, self.replace).to_string();
+                        if new != *instr {
+                            el.set_attribute("instr", Some(&new));
+                        }
+                    }
+                }
+                self.replace_nodes(&mut el.children);
+            }
+        }
+    }
+}
+
+impl Unlinker {
+    /// If the content that is being read is removed, because it is part of the instruction of a field that
+    /// is unlinked.
+    fn removing(open: &[(bool, bool)]) -> bool {
+        open.iter().any(|(unlinked, instruction)| *unlinked && *instruction)
+    }
+
+    /// Start the next field. Returns `true` if it is unlinked.
+    fn begin(&mut self, instruction: bool) -> bool {
+        let selected = self.selected.get(self.next).copied().unwrap_or(false);
+        if selected && !Self::removing(&self.open) {
+            self.unlinked.push(self.next);
+        }
+        self.next += 1;
+        self.open.push((selected, instruction));
+        selected
+    }
+
+    fn unlink_nodes(&mut self, nodes: Vec<Node>) -> Vec<Node> {
+        let mut result = vec![];
+        for node in nodes {
+            let Node::Element(mut el) = node else {
+                result.push(node);
+                continue;
+            };
+
+            if el.is("r") {
+                let has_content = |el: &Element| el.children.iter().any(|n| matches!(n, Node::Element(e) if !e.is("rPr")));
+                let had_content = has_content(&el);
+                for child in std::mem::take(&mut el.children) {
+                    let keep = match &child {
+                        Node::Element(c) if c.is("fldChar") => !self.field_char(c),
+                        Node::Element(c) if c.is("rPr") => true,
+                        _ => !Self::removing(&self.open),
+                    };
+                    if keep {
+                        el.children.push(child);
+                    }
+                }
+                // Runs that only held the markers or the instruction of a field are removed
+                if has_content(&el) || !had_content {
+                    result.push(Node::Element(el));
+                }
+            } else if el.is("fldSimple") {
+                let removed = Self::removing(&self.open);
+                let unlinked = self.begin(false);
+                let children = self.unlink_nodes(std::mem::take(&mut el.children));
+                self.open.pop();
+                if unlinked && !removed {
+                    result.extend(children);
+                } else if !removed {
+                    el.children = children;
+                    result.push(Node::Element(el));
+                }
+            } else {
+                el.children = self.unlink_nodes(std::mem::take(&mut el.children));
+                result.push(Node::Element(el));
+            }
+        }
+        result
+    }
+
+    /// Handle the field character `el`. Returns `true` if it is removed.
+    fn field_char(&mut self, el: &Element) -> bool {
+        match el.attrs.get("fldCharType").map(String::as_str) {
+            Some("begin") => {
+                let removed = Self::removing(&self.open);
+                self.begin(true) || removed
+            },
+            Some("separate") => {
+                let outer = Self::removing(&self.open[..self.open.len().saturating_sub(1)]);
+                match self.open.last_mut() {
+                    Some(field) => {
+                        field.1 = false;
+                        field.0 || outer
+                    },
+                    None => false,
+                }
+            },
+            Some("end") => {
+                let field = self.open.pop();
+                field.is_some_and(|f| f.0) || Self::removing(&self.open)
+            },
+            _ => Self::removing(&self.open),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::FieldUtil;
+    use crate::docx_package::DocxPackage;
+    use crate::error::DocxResult;
+    use crate::reporter::CollectingReporter;
+    use crate::test_util::document;
+    use crate::xml_util::{StoryType, XMLUtil, WORDDOC_NS};
+
+    fn texts(pkg: &DocxPackage) -> DocxResult<Vec<String>> {
+        let mut rep = CollectingReporter::default();
+        XMLUtil::cat(pkg, "fields.docx", &mut rep)?;
+        Ok(rep.paragraphs.into_iter().map(|p| p.text).collect())
+    }
+
+    #[test]
+    fn test_list_fields() -> DocxResult<()> {
+        let pkg = DocxPackage::from_dir("./src/test/test_tree15")?;
+
+        let mut rep = CollectingReporter::default();
+        FieldUtil::list_fields(&pkg, "fields.docx", &mut rep)?;
+        let fields: Vec<_> = rep.fields.iter()
+            .map(|f| (f.kind.as_str(), f.instruction.as_str(), f.result.as_str()))
+            .collect();
+        assert_eq!(vec![
+            ("MERGEFIELD", r"MERGEFIELD customer_name \* MERGEFORMAT", "«customer_name»"),
+            ("DATE", r#"DATE \@ "yyyy-MM-dd""#, "2024-01-31"),
+            ("HYPERLINK", r#"HYPERLINK "https://old.example.com/docs""#, "the site"),
+            ("IF", r#"IF { MERGEFIELD vip } = "yes" "Thank you" "Welcome""#, "Welcome"),
+            ("MERGEFIELD", "MERGEFIELD vip", "«vip»"),
+            ("PAGE", "PAGE", ""),
+            ("PAGE", "PAGE", "1"),
+        ], fields);
+        assert_eq!("word/header1.xml", rep.fields[6].part);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_replace_instructions() -> DocxResult<()> {
+        let mut pkg = DocxPackage::from_dir("./src/test/test_tree15")?;
+
+        let mut rep = CollectingReporter::default();
+        FieldUtil::replace_instructions(&mut pkg, "fields.docx", r"https://old\.example\.com/(\w+)",
+            "https://example.com/$1", &StoryType::ALL, &mut rep)?;
+        FieldUtil::replace_instructions(&mut pkg, "fields.docx", "MERGEFIELD customer_name",
+            "MERGEFIELD name", &StoryType::ALL, &mut rep)?;
+        FieldUtil::replace_instructions(&mut pkg, "fields.docx", "yyyy-MM-dd", "d MMMM yyyy",
+            &StoryType::ALL, &mut rep)?;
+        let repls: Vec<_> = rep.replacements.iter().map(|r| (r.old.as_str(), r.new.as_str())).collect();
+        assert_eq!(vec![
+            (r#"HYPERLINK "https://old.example.com/docs""#, r#"HYPERLINK "https://example.com/docs""#),
+            (r"MERGEFIELD customer_name \* MERGEFORMAT", r"MERGEFIELD name \* MERGEFORMAT"),
+            (r#"DATE \@ "yyyy-MM-dd""#, r#"DATE \@ "d MMMM yyyy""#),
+        ], repls);
+
+        let doc = document(&pkg);
+        assert!(doc.contains(r#"<w:instrText xml:space="preserve"> MERGEFIELD name \* MERGEFORMAT </w:instrText>"#), "{}", doc);
+        assert!(doc.contains(r#"<w:instrText xml:space="preserve"></w:instrText>"#), "{}", doc);
+        assert!(doc.contains(r#"<w:fldSimple w:instr=" DATE \@ &quot;d MMMM yyyy&quot; ">"#), "{}", doc);
+
+        // The text of the document is not affected
+        let mut rep = CollectingReporter::default();
+        FieldUtil::replace_instructions(&mut pkg, "fields.docx", "Dear", "Hello", &StoryType::ALL, &mut rep)?;
+        assert!(rep.replacements.is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_replace_instructions_default_namespace() -> DocxResult<()> {
+        let mut pkg = DocxPackage::from_dir("./src/test/test_tree18")?;
+
+        let mut rep = CollectingReporter::default();
+        FieldUtil::replace_instructions(&mut pkg, "default-ns.docx", "MERGEFIELD foo", "MERGEFIELD bar",
+            &StoryType::ALL, &mut rep)?;
+        assert_eq!(1, rep.replacements.len());
+
+        let doc = document(&pkg);
+        assert!(doc.contains(&format!(r#"<fldSimple xmlns:w="{}" w:instr=" MERGEFIELD bar ">"#, WORDDOC_NS)), "{}", doc);
+
+        let mut rep = CollectingReporter::default();
+        FieldUtil::list_fields(&pkg, "default-ns.docx", &mut rep)?;
+        assert_eq!("MERGEFIELD bar", rep.fields[0].instruction);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_unlink_fields() -> DocxResult<()> {
+        let mut pkg = DocxPackage::from_dir("./src/test/test_tree15")?;
+
+        let mut rep = CollectingReporter::default();
+        FieldUtil::unlink_fields(&mut pkg, "fields.docx", &["mergefield".to_string()], &mut rep)?;
+        let repls: Vec<_> = rep.replacements.iter().map(|r| (r.old.as_str(), r.new.as_str())).collect();
+        assert_eq!(vec![
+            (r"MERGEFIELD customer_name \* MERGEFORMAT", "«customer_name»"),
+            ("MERGEFIELD vip", "«vip»"),
+        ], repls);
+
+        let mut rep = CollectingReporter::default();
+        FieldUtil::list_fields(&pkg, "fields.docx", &mut rep)?;
+        let instructions: Vec<_> = rep.fields.iter().map(|f| f.instruction.as_str()).collect();
+        assert_eq!(vec![r#"DATE \@ "yyyy-MM-dd""#, r#"HYPERLINK "https://old.example.com/docs""#,
+            r#"IF «vip» = "yes" "Thank you" "Welcome""#, "PAGE", "PAGE"], instructions);
+        assert!(document(&pkg).contains(r#"<w:r><w:rPr><w:b/></w:rPr><w:t>«customer_name»</w:t></w:r>"#));
+
+        let mut rep = CollectingReporter::default();
+        FieldUtil::unlink_fields(&mut pkg, "fields.docx", &[], &mut rep)?;
+        assert_eq!(5, rep.replacements.len());
+        assert_eq!(vec!["Dear «customer_name»,", "Date: 2024-01-31", "See the site", "Welcome, page ", "Page 1"],
+            texts(&pkg)?);
+
+        let doc = document(&pkg);
+        assert!(!doc.contains("fldChar") && !doc.contains("instrText") && !doc.contains("fldSimple"), "{}", doc);
+        assert!(doc.contains(r#"<w:r><w:rPr><w:rStyle w:val="Hyperlink"/></w:rPr><w:t>the site</w:t></w:r>"#), "{}", doc);
+
+        Ok(())
+    }
+}
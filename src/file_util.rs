@@ -1,4 +1,20 @@
+use glob::{MatchOptions, Pattern};
+use std::io;
 use std::path::{MAIN_SEPARATOR, MAIN_SEPARATOR_STR, Path};
+use walkdir::WalkDir;
+
+use crate::error::DocxResult;
+
+/// A document found by `FileUtil::find_documents`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct InputFile {
+    /// The path of the document.
+    pub path: String,
+
+    /// The path of the document relative to the directory it was found in, or just its file name
+    /// if the document was specified directly.
+    pub rel_path: String,
+}
 
 pub struct FileUtil {
 }
@@ -17,31 +33,87 @@ impl FileUtil {
     pub fn get_sub_path(path: &Path, base_dir: &str) -> String {
       let nbase_dir = FileUtil::normalize_path(base_dir);
 
-      let base;
-      if nbase_dir.ends_with(MAIN_SEPARATOR_STR) {
-          base = nbase_dir;
+      let base = if nbase_dir.ends_with(MAIN_SEPARATOR_STR) {
+          nbase_dir
       } else {
-          base = nbase_dir + MAIN_SEPARATOR_STR;
-      }
-
-      let sub_path;
+          nbase_dir + MAIN_SEPARATOR_STR
+      };
 
       let full_path = path.to_string_lossy();
       let nfull_path = FileUtil::normalize_path(&full_path);
-      if nfull_path.starts_with(&base) {
-          sub_path = &nfull_path[base.len()..];
-      } else {
-          sub_path = &nfull_path;
-      }
+      let sub_path = nfull_path.strip_prefix(&base).unwrap_or(&nfull_path);
 
       sub_path.to_owned()
   }
+
+    /// Find the documents to process for `path`. If `path` is a file it is returned as-is, if it's a
+    /// directory then the files in it are returned, including those in subdirectories if `recursive`
+    /// is set. The file names of the documents must match one of the `include` patterns, or `*.docx`
+    /// ignoring case for files found in a directory if no `include` patterns are given, and may not
+    /// match any of the `exclude` patterns. Problems reading a directory are returned as errors in the
+    /// result, the other documents found are still returned.
+    pub fn find_documents(path: &str, recursive: bool, include: &[Pattern], exclude: &[Pattern])
+            -> Vec<DocxResult<InputFile>> {
+        let p = Path::new(path);
+        if !p.is_dir() {
+            let name = p.file_name()
+                .map(|n| n.to_string_lossy().to_string())
+                .unwrap_or_else(|| path.to_string());
+
+            if Self::matches(&name, include, exclude, None) {
+                return vec![Ok(InputFile { path: path.to_string(), rel_path: name })];
+            } else {
+                return vec![];
+            }
+        }
+
+        let default_include = Pattern::new("*.docx").ok();
+        let max_depth = if recursive { usize::MAX } else { 1 };
+
+        let mut res = Vec::new();
+        for entry in WalkDir::new(path).max_depth(max_depth).sort_by_file_name() {
+            let entry = match entry {
+                Ok(e) => e,
+                Err(e) => {
+                    res.push(Err(io::Error::other(e.to_string()).into()));
+                    continue;
+                }
+            };
+            if !entry.file_type().is_file() {
+                continue;
+            }
+
+            let name = entry.file_name().to_string_lossy();
+            if Self::matches(&name, include, exclude, default_include.as_ref()) {
+                res.push(Ok(InputFile {
+                    path: entry.path().to_string_lossy().to_string(),
+                    rel_path: Self::get_sub_path(entry.path(), path),
+                }));
+            }
+        }
+        res
+    }
+
+    fn matches(name: &str, include: &[Pattern], exclude: &[Pattern], default_include: Option<&Pattern>) -> bool {
+        let included = if include.is_empty() {
+            let options = MatchOptions { case_sensitive: false, ..MatchOptions::new() };
+            default_include.is_none_or(|p| p.matches_with(name, options))
+        } else {
+            include.iter().any(|p| p.matches(name))
+        };
+
+        included && !exclude.iter().any(|p| p.matches(name))
+    }
 }
 
 #[cfg(test)]
 mod tests {
-    use super::FileUtil;
+    use super::{FileUtil, InputFile};
+    use crate::error::DocxResult;
+    use glob::Pattern;
+    use std::fs;
     use std::path::Path;
+    use testdir::testdir;
 
     #[test]
     fn test_get_sub_path() {
@@ -63,4 +135,34 @@ mod tests {
         let p = Path::new("/elsewhere/cloud.docx");
         assert_eq!(FileUtil::normalize_path("/elsewhere/cloud.docx"), FileUtil::get_sub_path(p, b));
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_find_documents() {
+        let dir = testdir!();
+        fs::create_dir_all(dir.join("sub")).unwrap();
+        for f in ["a.docx", "b.txt", "~$a.docx", "sub/c.docx", "sub/d.DOCX"] {
+            fs::write(dir.join(f), "").unwrap();
+        }
+        let d = dir.to_string_lossy();
+
+        let rel_paths = |res: Vec<DocxResult<InputFile>>| -> Vec<String> {
+            res.into_iter().map(|r| r.unwrap().rel_path).collect()
+        };
+
+        assert_eq!(vec!["a.docx", "~$a.docx"], rel_paths(FileUtil::find_documents(&d, false, &[], &[])));
+
+        let exclude = [Pattern::new("~$*").unwrap()];
+        assert_eq!(vec!["a.docx".to_string(), FileUtil::normalize_path("sub/c.docx"), FileUtil::normalize_path("sub/d.DOCX")],
+            rel_paths(FileUtil::find_documents(&d, true, &[], &exclude)));
+
+        let include = [Pattern::new("*.txt").unwrap(), Pattern::new("*.DOCX").unwrap()];
+        assert_eq!(vec!["b.txt".to_string(), FileUtil::normalize_path("sub/d.DOCX")],
+            rel_paths(FileUtil::find_documents(&d, true, &include, &[])));
+
+        // Files that are specified directly don't need to have the .docx extension, but are excluded
+        let f = dir.join("b.txt").to_string_lossy().to_string();
+        let res = rel_paths(FileUtil::find_documents(&f, false, &[], &[]));
+        assert_eq!(vec!["b.txt"], res);
+        assert!(FileUtil::find_documents(&f, false, &[], &[Pattern::new("*.txt").unwrap()]).is_empty());
+    }
+}
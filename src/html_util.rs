@@ -0,0 +1,326 @@
+use crate::docx_package::DocxPackage;
+use crate::document_model::{Block, Cell, DocumentModel, ParagraphKind, Run};
+use crate::error::DocxResult;
+use crate::xml_util::XMLUtil;
+
+const BASE64_CHARS: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+/// Writes the blocks of a document as HTML.
+struct HtmlWriter<'a> {
+    pkg: &'a DocxPackage,
+    media_dir: Option<&'a str>,
+    out: String,
+
+    /// The lists that are open, with whether they are ordered, from the outermost one
+    lists: Vec<bool>,
+}
+
+pub struct HtmlUtil;
+
+impl HtmlUtil {
+    /// Convert the main document of `pkg` to a standalone HTML document with `title`. Paragraphs become
+    /// headings, block quotes, list items or paragraphs as read by `DocumentModel`, with the styleId of
+    /// their style as class. Images are embedded as data URIs, unless `media_dir` is specified. In that
+    /// case they refer to the file with the same name in that directory, see `media_parts()`.
+    pub fn to_html(pkg: &DocxPackage, title: &str, media_dir: Option<&str>) -> DocxResult<String> {
+        let blocks = DocumentModel::read(pkg)?;
+
+        let mut writer = HtmlWriter { pkg, media_dir, out: String::new(), lists: vec![] };
+        writer.out.push_str("<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n");
+        writer.out.push_str(&format!("<title>{}</title>\n</head>\n<body>\n", Self::escape(title)));
+        writer.blocks(&blocks)?;
+        writer.out.push_str("</body>\n</html>\n");
+        Ok(writer.out)
+    }
+
+    /// The names of the parts of `pkg` that hold the images of the document, under `word/media`.
+    pub fn media_parts(pkg: &DocxPackage) -> Vec<String> {
+        pkg.part_names().into_iter().filter(|p| p.starts_with("word/media/")).collect()
+    }
+
+    /// Whether `href` can be used as the target of a link: a relative URL, a fragment or an http, https or
+    /// mailto URL. Other schemes, such as javascript: and data:, could run code when the link is followed.
+    fn is_safe_href(href: &str) -> bool {
+        // Browsers ignore whitespace and control characters in the scheme
+        let href: String = href.chars().filter(|c| !c.is_whitespace() && !c.is_control()).collect();
+        match href.split_once(':') {
+            Some((scheme, _)) if !scheme.contains(['/', '?', '#']) =>
+                ["http", "https", "mailto"].iter().any(|s| scheme.eq_ignore_ascii_case(s)),
+            _ => true,
+        }
+    }
+
+    /// Escape `text` for use in HTML content and attribute values.
+    fn escape(text: &str) -> String {
+        let mut out = String::with_capacity(text.len());
+        for c in text.chars() {
+            match c {
+                '&' => out.push_str("&amp;"),
+                '<' => out.push_str("&lt;"),
+                '>' => out.push_str("&gt;"),
+                '"' => out.push_str("&quot;"),
+                _ => out.push(c),
+            }
+        }
+        out
+    }
+
+    fn base64(data: &[u8]) -> String {
+        let mut out = String::with_capacity(data.len().div_ceil(3) * 4);
+        for chunk in data.chunks(3) {
+            let n = chunk.iter().enumerate().fold(0u32, |n, (i, b)| n | (*b as u32) << (16 - 8 * i));
+            for i in 0..4 {
+                if i <= chunk.len() {
+                    out.push(BASE64_CHARS[(n >> (18 - 6 * i) & 0x3f) as usize] as char);
+                } else {
+                    out.push('=');
+                }
+            }
+        }
+        out
+    }
+}
+
+impl HtmlWriter<'_> {
+    fn blocks(&mut self, blocks: &[Block]) -> DocxResult<()> {
+        for block in blocks {
+            match block {
+                Block::Paragraph { kind, style, runs } => {
+                    if runs.is_empty() {
+                        continue;
+                    }
+                    let class = style.as_ref()
+                        .map(|s| format!(" class=\"{}\"", HtmlUtil::escape(s)))
+                        .unwrap_or_default();
+
+                    if let ParagraphKind::ListItem { ordered, level } = kind {
+                        self.list_item(*ordered, *level);
+                        self.out.push_str(&format!("<li{}>", class));
+                        self.runs(runs)?;
+                        continue;
+                    }
+
+                    self.close_lists(0);
+                    match kind {
+                        ParagraphKind::Heading(level) => {
+                            self.out.push_str(&format!("<h{}{}>", level, class));
+                            self.runs(runs)?;
+                            self.out.push_str(&format!("</h{}>\n", level));
+                        },
+                        ParagraphKind::Quote => {
+                            self.out.push_str(&format!("<blockquote{}><p>", class));
+                            self.runs(runs)?;
+                            self.out.push_str("</p></blockquote>\n");
+                        },
+                        _ => {
+                            self.out.push_str(&format!("<p{}>", class));
+                            self.runs(runs)?;
+                            self.out.push_str("</p>\n");
+                        }
+                    }
+                },
+                Block::Table { rows } => {
+                    self.close_lists(0);
+                    self.table(rows)?;
+                }
+            }
+        }
+        self.close_lists(0);
+        Ok(())
+    }
+
+    /// Open and close lists so that the next list item is in a list of type `ordered` at `level`.
+    fn list_item(&mut self, ordered: bool, level: usize) {
+        if self.lists.get(level).is_some_and(|o| *o != ordered) {
+            self.close_lists(level);
+        } else {
+            self.close_lists(level + 1);
+        }
+
+        if self.lists.len() == level + 1 {
+            self.out.push_str("</li>\n");
+        }
+        while self.lists.len() < level + 1 {
+            // A list nested deeper than the next level is put in an item of its own
+            let nested = self.lists.len() < level;
+            let o = ordered && !nested;
+            self.out.push_str(if o { "<ol>\n" } else { "<ul>\n" });
+            self.lists.push(o);
+            if nested {
+                self.out.push_str("<li>\n");
+            }
+        }
+    }
+
+    /// Close the lists nested deeper than `level`.
+    fn close_lists(&mut self, level: usize) {
+        while self.lists.len() > level {
+            let ordered = self.lists.pop().unwrap_or_default();
+            self.out.push_str(if ordered { "</li>\n</ol>\n" } else { "</li>\n</ul>\n" });
+        }
+    }
+
+    fn table(&mut self, rows: &[Vec<Cell>]) -> DocxResult<()> {
+        self.out.push_str("<table>\n");
+        for row in rows {
+            self.out.push_str("<tr>");
+            for cell in row.iter().filter(|c| !c.covered) {
+                self.out.push_str("<td");
+                if cell.col_span > 1 {
+                    self.out.push_str(&format!(" colspan=\"{}\"", cell.col_span));
+                }
+                if cell.row_span > 1 {
+                    self.out.push_str(&format!(" rowspan=\"{}\"", cell.row_span));
+                }
+                self.out.push_str(">\n");
+                self.blocks(&cell.blocks)?;
+                self.out.push_str("</td>");
+            }
+            self.out.push_str("</tr>\n");
+        }
+        self.out.push_str("</table>\n");
+        Ok(())
+    }
+
+    fn runs(&mut self, runs: &[Run]) -> DocxResult<()> {
+        let mut i = 0;
+        while i < runs.len() {
+            // Runs with the same hyperlink form a single anchor, links with an unsafe target are left out
+            let count = runs[i..].iter().take_while(|r| r.link == runs[i].link).count();
+            let link = runs[i].link.as_ref().filter(|href| HtmlUtil::is_safe_href(href));
+            if let Some(href) = link {
+                self.out.push_str(&format!("<a href=\"{}\">", HtmlUtil::escape(href)));
+            }
+            for run in &runs[i..i + count] {
+                self.run(run)?;
+            }
+            if link.is_some() {
+                self.out.push_str("</a>");
+            }
+            i += count;
+        }
+        Ok(())
+    }
+
+    fn run(&mut self, run: &Run) -> DocxResult<()> {
+        if let Some(name) = &run.bookmark {
+            self.out.push_str(&format!("<a id=\"{}\"></a>", HtmlUtil::escape(name)));
+        }
+        if let Some(image) = &run.image {
+            let src = match self.media_dir {
+                Some(dir) => format!("{}/{}", dir, image.part.rsplit('/').next().unwrap_or(&image.part)),
+                None => {
+                    let content_type = XMLUtil::get_content_type(self.pkg, &image.part)?
+                        .unwrap_or_else(|| "application/octet-stream".to_string());
+                    let data = self.pkg.get_part(&image.part).unwrap_or_default();
+                    format!("data:{};base64,{}", content_type, HtmlUtil::base64(data))
+                }
+            };
+            let alt = image.description.as_deref().unwrap_or("");
+            self.out.push_str(&format!("<img src=\"{}\" alt=\"{}\">", HtmlUtil::escape(&src), HtmlUtil::escape(alt)));
+        }
+        if run.text.is_empty() {
+            return Ok(());
+        }
+
+        let mut tags = vec![];
+        if let Some(style) = &run.style {
+            self.out.push_str(&format!("<span class=\"{}\">", HtmlUtil::escape(style)));
+            tags.push("span");
+        }
+        for (on, tag) in [(run.bold, "strong"), (run.italic, "em"), (run.underline, "u"), (run.code, "code")] {
+            if on {
+                self.out.push_str(&format!("<{}>", tag));
+                tags.push(tag);
+            }
+        }
+        self.out.push_str(&HtmlUtil::escape(&run.text).replace('\n', "<br>"));
+        for tag in tags.iter().rev() {
+            self.out.push_str(&format!("</{}>", tag));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::HtmlUtil;
+    use crate::docx_package::DocxPackage;
+    use crate::error::DocxResult;
+    use crate::test_util::part_str;
+
+    #[test]
+    fn test_to_html() -> DocxResult<()> {
+        let pkg = DocxPackage::from_dir("./src/test/test_tree12")?;
+        let html = HtmlUtil::to_html(&pkg, "Release notes", None)?;
+
+        assert!(html.starts_with("<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>Release notes</title>"));
+        assert!(html.contains("<h1 class=\"Title\">Release notes</h1>\n"));
+        assert!(html.contains("<h2 class=\"Heading2\"><a id=\"install\"></a>Installation</h2>\n"));
+        assert!(html.contains("<p>Some <strong>bold</strong>, <em>italic</em> and \
+            <span class=\"HTMLCode\"><code>cargo </code></span><code>build</code> text, see \
+            <a href=\"https://example.com/docs\"><span class=\"Hyperlink\">the site</span></a>.</p>\n"));
+        assert!(html.contains("<ul>\n<li class=\"ListParagraph\">First item<ul>\n\
+            <li class=\"ListParagraph\">Nested item</li>\n</ul>\n</li>\n</ul>\n\
+            <ol>\n<li class=\"ListParagraph\">Step one</li>\n<li class=\"ListParagraph\">Step two</li>\n</ol>\n"));
+        assert!(html.contains("<blockquote class=\"Quote\"><p>Quoted<br>text</p></blockquote>\n"));
+        assert!(html.contains("<p><img src=\"data:image/png;base64,iVBORw0KGgo"));
+        assert!(html.contains("alt=\"A green dot\"></p>\n"));
+        assert!(html.contains("<p>Back to <a href=\"#install\"><u>installation</u></a></p>\n"));
+        assert!(html.contains("<tr><td>\n<p>Timeout</p>\n</td><td>\n<p>30 | 60</p>\n<p>seconds</p>\n</td></tr>\n"));
+        assert!(html.ends_with("</table>\n</body>\n</html>\n"));
+
+        let html = HtmlUtil::to_html(&pkg, "Release notes", Some("notes_media"))?;
+        assert!(html.contains("<p><img src=\"notes_media/image1.png\" alt=\"A green dot\"></p>\n"));
+        assert_eq!(vec!["word/media/image1.png"], HtmlUtil::media_parts(&pkg));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_to_html_unsafe_links() -> DocxResult<()> {
+        let mut pkg = DocxPackage::from_dir("./src/test/test_tree12")?;
+        let rels = part_str(&pkg, "word/_rels/document.xml.rels");
+        for target in ["javascript:alert(1)", " JavaScript:alert(1)", "java\tscript:alert(1)", "data:text/html,hi"] {
+            let new_rels = rels.replace("https://example.com/docs", target);
+            pkg.set_part("word/_rels/document.xml.rels", new_rels.into_bytes());
+
+            let html = HtmlUtil::to_html(&pkg, "Release notes", None)?;
+            assert!(html.contains("text, see <span class=\"Hyperlink\">the site</span>.</p>\n"), "{}: {}", target, html);
+            assert!(html.contains("<a href=\"#install\">"), "{}", target);
+        }
+
+        assert!(HtmlUtil::is_safe_href("https://example.com/a:b"));
+        assert!(HtmlUtil::is_safe_href("MAILTO:jane@example.com"));
+        assert!(HtmlUtil::is_safe_href("docs/page.html?at=12:00"));
+        assert!(HtmlUtil::is_safe_href("#install"));
+        assert!(!HtmlUtil::is_safe_href("vbscript:msgbox"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_to_html_spans() -> DocxResult<()> {
+        let pkg = DocxPackage::from_dir("./src/test/test_tree11")?;
+        let html = HtmlUtil::to_html(&pkg, "Tables", None)?;
+
+        assert!(html.contains("<tr><td>\n<p>Product</p>\n</td><td colspan=\"2\">\n<p>Price</p>\n</td></tr>\n"));
+        assert!(html.contains("<tr><td rowspan=\"2\">\n<p>Widget</p>\n</td>"));
+        assert!(html.contains("<tr><td colspan=\"3\">\n<p>Total</p>\n</td></tr>\n"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_base64() {
+        assert_eq!("", HtmlUtil::base64(b""));
+        assert_eq!("Zg==", HtmlUtil::base64(b"f"));
+        assert_eq!("Zm8=", HtmlUtil::base64(b"fo"));
+        assert_eq!("Zm9v", HtmlUtil::base64(b"foo"));
+        assert_eq!("Zm9vYmFy", HtmlUtil::base64(b"foobar"));
+    }
+
+    #[test]
+    fn test_escape() {
+        assert_eq!("a &lt;b&gt; &amp; &quot;c&quot;", HtmlUtil::escape("a <b> & \"c\""));
+    }
+}
@@ -1,3 +1,18 @@
+pub mod control_util;
+pub mod docx_package;
+pub mod document_model;
+pub mod error;
+pub mod field_util;
 pub mod file_util;
+pub mod html_util;
+pub mod link_util;
+pub mod markdown_util;
+pub mod merge_util;
+pub mod reporter;
+pub mod revision_util;
+pub mod table_util;
+#[cfg(test)]
+mod test_util;
+mod xml_tree;
 pub mod xml_util;
 pub mod zip_util;
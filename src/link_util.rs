@@ -0,0 +1,593 @@
+use quick_xml::events::{BytesStart, Event};
+use quick_xml::name::{Namespace, QName, ResolveResult};
+use quick_xml::reader::NsReader;
+use regex::Regex;
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+use std::sync::LazyLock;
+
+use crate::docx_package::DocxPackage;
+use crate::error::{DocxError, DocxResult};
+use crate::field_util::FieldUtil;
+use crate::reporter::{DocxReporter, Link, LinkProblem};
+use crate::xml_util::{Relationship, StoryType, XMLUtil};
+
+const OFFICE_RELATIONSHIPS_NS: &str = "http://schemas.openxmlformats.org/officeDocument/2006/relationships";
+
+/// The types of relationships that are used by referring to their id from the content of the part. Other types,
+/// such as the styles of the document, belong to the part as a whole.
+const REFERENCED_TYPES: [&str; 11] = ["hyperlink", "image", "header", "footer", "oleObject", "package", "chart",
+    "diagramData", "aFChunk", "subDocument", "video"];
+
+/// The schemes of URLs that must have a host.
+const HOST_SCHEMES: [&str; 5] = ["http", "https", "ftp", "ws", "wss"];
+
+/// Matches the scheme at the start of a URL, capturing its name.
+static SCHEME: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"^([A-Za-z][A-Za-z0-9+.-]*):").expect("valid scheme regex"));
+
+/// A field that is open while reading a part.
+struct OpenField {
+    instruction: String,
+
+    /// If the result of the field is being read, rather than its instruction
+    result: bool,
+
+    /// The link for the field, once it is known to be a hyperlink
+    link: Option<usize>,
+}
+
+/// Reads the links in the text of a part.
+struct LinkReader<'a> {
+    src_file: &'a str,
+    part: &'a str,
+    rels: HashMap<String, Relationship>,
+
+    /// The links in the order in which they start, with the paragraph each of them starts in
+    links: Vec<(Link, Option<usize>)>,
+
+    /// The relationship ids that elements refer to, with the paragraph they are in
+    references: Vec<(String, Option<usize>)>,
+    bookmarks: Vec<String>,
+
+    /// The `w:hyperlink` elements that are open, as indexes in `links`
+    hyperlinks: Vec<usize>,
+    fields: Vec<OpenField>,
+
+    /// The paragraphs that are open, with whether they contain text. Paragraphs are numbered in the order in which
+    /// they start, `indexes` holds the index of each paragraph among the paragraphs with text once it has ended.
+    paragraphs: Vec<(usize, bool)>,
+    indexes: Vec<Option<usize>>,
+    text_paragraphs: usize,
+
+    inside_text: bool,
+    inside_instruction: bool,
+}
+
+pub struct LinkUtil;
+
+impl LinkUtil {
+    /// Report the links in the text of all the story parts of the document to `reporter`, with the text they
+    /// are attached to and the index of their paragraph. These are the `w:hyperlink` elements, which either refer
+    /// to a relationship of the part or jump to a bookmark with `w:anchor`, and the `HYPERLINK` fields.
+    pub fn links(pkg: &DocxPackage, src_file: &str, reporter: &mut dyn DocxReporter) -> DocxResult<()> {
+        for part in XMLUtil::get_story_parts(pkg, &StoryType::ALL)? {
+            if let Some(reader) = LinkReader::read_part(pkg, src_file, &part)? {
+                for link in reader.links() {
+                    reporter.on_link(&link);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Check the links in all the story parts of the document and report the problems to `reporter`, without
+    /// accessing the network. These are links to bookmarks that don't exist, references to relationships that are
+    /// missing from the relationship part, relationships that nothing refers to, external targets that are not
+    /// valid URLs and relative file targets that don't exist in `base_dir`, the directory of the document. Files
+    /// are not checked if `base_dir` is `None`.
+    pub fn check_links(pkg: &DocxPackage, src_file: &str, base_dir: Option<&Path>, reporter: &mut dyn DocxReporter)
+            -> DocxResult<()> {
+        let parts = XMLUtil::get_story_parts(pkg, &StoryType::ALL)?;
+        let mut readers = vec![];
+        for part in &parts {
+            readers.extend(LinkReader::read_part(pkg, src_file, part)?);
+        }
+        // Links in headers and notes can jump to bookmarks in the main document
+        let bookmarks: HashSet<&str> = readers.iter().flat_map(|r| r.bookmarks.iter().map(String::as_str)).collect();
+
+        for reader in &readers {
+            let mut report = |link: Link, problem: &str| {
+                reporter.on_link_problem(&LinkProblem { link, problem: problem.to_string() });
+            };
+            let links = reader.links();
+            let text = |id: &str| links.iter().find(|l| l.id.as_deref() == Some(id)).and_then(|l| l.text.clone());
+
+            // The paragraph of the first reference to each relationship
+            let mut referenced = HashMap::new();
+            for (id, paragraph) in &reader.references {
+                if referenced.contains_key(id.as_str()) {
+                    continue;
+                }
+                let paragraph = reader.paragraph_index(*paragraph);
+                referenced.insert(id.as_str(), paragraph);
+                if !reader.rels.contains_key(id) {
+                    let link = Link { id: Some(id.clone()), text: text(id), paragraph, ..reader.link(String::new()) };
+                    report(link, "missing relationship");
+                }
+            }
+
+            let mut ids: Vec<&String> = reader.rels.keys().collect();
+            ids.sort_by_key(|id| (id.len(), id.as_str()));
+            for id in ids {
+                let rel = &reader.rels[id];
+                let link = Link {
+                    id: Some(id.clone()), rel_type: rel.rel_type.clone(), target_mode: rel.target_mode.clone(),
+                    text: text(id), paragraph: referenced.get(id.as_str()).copied().flatten(),
+                    external: rel.is_external(), ..reader.link(rel.target.clone())
+                };
+                let rel_type = rel.rel_type.as_deref().unwrap_or_default();
+                let explicit = REFERENCED_TYPES.iter().any(|t| XMLUtil::is_rel_type(rel_type, t));
+                if explicit && !referenced.contains_key(id.as_str()) {
+                    report(link.clone(), "unused relationship");
+                }
+                if rel.is_external() {
+                    if let Some(problem) = Self::target_problem(&rel.target, base_dir) {
+                        report(link, problem);
+                    }
+                }
+            }
+
+            for link in links.iter().cloned() {
+                if link.id.is_some() {
+                    // Checked with the relationships
+                    continue;
+                }
+                let problem = match link.target.strip_prefix('#') {
+                    Some(bookmark) if !bookmarks.contains(bookmark) => Some("missing bookmark"),
+                    Some(_) => None,
+                    None => Self::target_problem(&link.target, base_dir),
+                };
+                if let Some(p) = problem {
+                    report(link, p);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// The problem with the external `target` of a link, if any. Targets with a scheme must be valid URLs,
+    /// relative targets must be files that exist in `base_dir`.
+    fn target_problem(target: &str, base_dir: Option<&Path>) -> Option<&'static str> {
+        match SCHEME.captures(target) {
+            // A single letter is the drive of an absolute Windows path
+            Some(caps) if caps[1].len() > 1 => Self::url_problem(target, &caps[1].to_lowercase()),
+            Some(_) => None,
+            None if target.is_empty() => Some("empty target"),
+            // Network paths can't be checked
+            None if target.starts_with("\\\\") || target.starts_with("//") => None,
+            None => {
+                let dir = base_dir?;
+                let path = target.split('#').next().unwrap_or_default().replace('\\', "/");
+                match Self::percent_decode(&path) {
+                    Some(p) if dir.join(&p).exists() => None,
+                    Some(_) => Some("file not found"),
+                    None => Some("invalid percent-encoding"),
+                }
+            }
+        }
+    }
+
+    /// The problem with the syntax of `url`, which has `scheme`, if any.
+    fn url_problem(url: &str, scheme: &str) -> Option<&'static str> {
+        if url.chars().any(|c| c.is_whitespace() || c.is_control() || "\"<>^`{|}".contains(c)) {
+            return Some("invalid character in URL");
+        }
+        if Self::percent_decode(url).is_none() {
+            return Some("invalid percent-encoding");
+        }
+
+        let rest = &url[scheme.len() + 1..];
+        if HOST_SCHEMES.contains(&scheme) {
+            let Some(authority) = rest.strip_prefix("//") else {
+                return Some("missing host");
+            };
+            let authority = authority.split(['/', '?', '#']).next().unwrap_or_default();
+            let host_port = authority.rsplit('@').next().unwrap_or_default();
+            // IPv6 addresses are written between brackets
+            let (host, port) = match host_port.strip_prefix('[') {
+                Some(ip) => match ip.split_once(']') {
+                    Some((h, p)) if h.chars().all(|c| c.is_ascii_hexdigit() || c == ':' || c == '.') =>
+                        (h, p.strip_prefix(':').or(Some(p).filter(|p| !p.is_empty()))),
+                    _ => return Some("invalid host"),
+                },
+                None => match host_port.rsplit_once(':') {
+                    Some((h, p)) => (h, Some(p)),
+                    None => (host_port, None),
+                },
+            };
+            let valid_host = host_port.starts_with('[') || host.chars().all(|c| c.is_alphanumeric() || "-._%".contains(c));
+            if host.is_empty() {
+                return Some("missing host");
+            }
+            if !valid_host {
+                return Some("invalid host");
+            }
+            if port.is_some_and(|p| !p.is_empty() && p.parse::<u16>().is_err()) {
+                return Some("invalid port");
+            }
+        } else if scheme == "mailto" {
+            let address = rest.split('?').next().unwrap_or_default();
+            if !address.split(',').all(|a| a.split_once('@').is_some_and(|(u, d)| !u.is_empty() && !d.is_empty())) {
+                return Some("invalid e-mail address");
+            }
+        }
+        None
+    }
+
+    /// Decode the percent-encoded characters in `text`, or return `None` if the encoding is not valid.
+    fn percent_decode(text: &str) -> Option<String> {
+        let mut bytes = vec![];
+        let mut rest = text.as_bytes();
+        while let Some((&b, tail)) = rest.split_first() {
+            if b == b'%' {
+                let hex = tail.get(..2).and_then(|h| std::str::from_utf8(h).ok())?;
+                bytes.push(u8::from_str_radix(hex, 16).ok()?);
+                rest = &tail[2..];
+            } else {
+                bytes.push(b);
+                rest = tail;
+            }
+        }
+        Some(String::from_utf8_lossy(&bytes).to_string())
+    }
+
+    /// The target of a `HYPERLINK` field with `instruction`, which is its address followed by the bookmark of
+    /// the `\l` switch as a fragment, and whether the target is outside of the document.
+    fn field_target(instruction: &str) -> (String, bool) {
+        let mut address = String::new();
+        let mut bookmark = None;
+
+        // Arguments are separated by spaces or quoted, in which case a backslash escapes the next character
+        let mut args = vec![];
+        let mut chars = instruction.chars().peekable();
+        while let Some(c) = chars.next() {
+            if c.is_whitespace() {
+                continue;
+            }
+            let mut arg = String::new();
+            if c == '"' {
+                while let Some(c) = chars.next() {
+                    match c {
+                        '"' => break,
+                        '\\' => arg.extend(chars.next()),
+                        c => arg.push(c),
+                    }
+                }
+                args.push((arg, true));
+            } else {
+                arg.push(c);
+                while let Some(c) = chars.next_if(|c| !c.is_whitespace()) {
+                    arg.push(c);
+                }
+                args.push((arg, false));
+            }
+        }
+
+        let mut args = args.into_iter().skip(1);
+        while let Some((arg, quoted)) = args.next() {
+            if quoted || !arg.starts_with('\\') {
+                if address.is_empty() {
+                    address = arg;
+                }
+                continue;
+            }
+            match arg.to_lowercase().as_str() {
+                "\\l" => bookmark = args.next().map(|(a, _)| a),
+                // Switches with an argument: the target frame and the screen tip
+                "\\t" | "\\o" => {
+                    args.next();
+                },
+                _ => ()
+            }
+        }
+
+        let external = !address.is_empty();
+        match bookmark {
+            Some(b) => (format!("{}#{}", address, b), external),
+            None => (address, external),
+        }
+    }
+}
+
+impl<'a> LinkReader<'a> {
+    /// Read the links in `part` of `pkg`, or return `None` if the part doesn't exist.
+    fn read_part(pkg: &DocxPackage, src_file: &'a str, part: &'a str) -> DocxResult<Option<LinkReader<'a>>> {
+        if pkg.get_part(part).is_none() {
+            return Ok(None);
+        }
+
+        let mut reader = LinkReader {
+            src_file, part,
+            rels: XMLUtil::get_relationship_details(pkg, part)?,
+            links: vec![],
+            references: vec![],
+            bookmarks: vec![],
+            hyperlinks: vec![],
+            fields: vec![],
+            paragraphs: vec![],
+            indexes: vec![],
+            text_paragraphs: 0,
+            inside_text: false,
+            inside_instruction: false,
+        };
+        reader.read(pkg)?;
+        Ok(Some(reader))
+    }
+
+    /// The links that were read, with the index of their paragraph.
+    fn links(&self) -> Vec<Link> {
+        self.links.iter()
+            .map(|(link, paragraph)| Link { paragraph: self.paragraph_index(*paragraph), ..link.clone() })
+            .collect()
+    }
+
+    /// The index of `paragraph` among the paragraphs with text, if it has text.
+    fn paragraph_index(&self, paragraph: Option<usize>) -> Option<usize> {
+        paragraph.and_then(|p| self.indexes[p])
+    }
+
+    /// A link of the part to `target` without any other properties.
+    fn link(&self, target: String) -> Link {
+        Link {
+            src_file: self.src_file.to_string(), part: self.part.to_string(),
+            id: None, rel_type: None, target, target_mode: None, text: None, paragraph: None, external: false,
+        }
+    }
+
+    fn read(&mut self, pkg: &DocxPackage) -> DocxResult<()> {
+        let mut reader = XMLUtil::get_reader(pkg, self.part)?;
+        let mut buf = Vec::new();
+        loop {
+            match reader.read_event_into(&mut buf) {
+                Err(e) => return Err(DocxError::xml(self.part, reader.error_position(), e)),
+                Ok(Event::Eof) => break,
+                Ok(Event::Start(e)) => self.start(&reader, &e),
+                Ok(Event::Empty(e)) => {
+                    self.start(&reader, &e);
+                    self.end(&reader, e.name());
+                },
+                Ok(Event::End(e)) => self.end(&reader, e.name()),
+                Ok(Event::Text(t)) if self.inside_text || self.inside_instruction => {
+                    let val = t.unescape().map_err(|e| DocxError::xml(self.part, reader.buffer_position(), e))?;
+                    if self.inside_instruction {
+                        self.add_instruction(&val);
+                    } else {
+                        self.add_text(&val);
+                    }
+                },
+                _ => ()
+            }
+        }
+        Ok(())
+    }
+
+    fn start(&mut self, reader: &NsReader<&[u8]>, e: &BytesStart) {
+        let is = |local| XMLUtil::is_w(reader, e.name(), local);
+        let attr = |name| XMLUtil::get_attribute(e, reader, name, self.part).ok();
+
+        let r_ns = ResolveResult::Bound(Namespace(OFFICE_RELATIONSHIPS_NS.as_bytes()));
+        for a in e.attributes().flatten() {
+            if reader.resolve_attribute(a.key).0 == r_ns {
+                let paragraph = self.paragraphs.last().map(|(p, _)| *p);
+                self.references.push((String::from_utf8_lossy(&a.value).to_string(), paragraph));
+            }
+        }
+
+        if is("p") {
+            self.paragraphs.push((self.indexes.len(), false));
+            self.indexes.push(None);
+        } else if is("hyperlink") {
+            let id = e.attributes().flatten()
+                .find(|a| XMLUtil::is_attribute(reader, a.key, Some(OFFICE_RELATIONSHIPS_NS), "id"))
+                .map(|a| String::from_utf8_lossy(&a.value).to_string());
+            let index = self.hyperlink(id, attr("anchor"));
+            self.hyperlinks.push(index);
+        } else if is("fldSimple") {
+            let instruction = attr("instr").unwrap_or_default();
+            let link = self.field_link(&instruction);
+            self.fields.push(OpenField { instruction, result: true, link });
+        } else if is("fldChar") {
+            match attr("fldCharType").as_deref() {
+                Some("begin") => self.fields.push(OpenField { instruction: String::new(), result: false, link: None }),
+                Some("separate") => {
+                    if let Some(field) = self.fields.pop() {
+                        let link = self.field_link(&field.instruction);
+                        self.fields.push(OpenField { result: true, link, ..field });
+                    }
+                },
+                Some("end") => {
+                    // A field without a result is still a link
+                    if let Some(field) = self.fields.pop().filter(|f| !f.result) {
+                        self.field_link(&field.instruction);
+                    }
+                },
+                _ => ()
+            }
+        } else if is("bookmarkStart") {
+            self.bookmarks.extend(attr("name"));
+        } else if is("instrText") {
+            self.inside_instruction = true;
+        } else if is("t") {
+            self.inside_text = true;
+        } else if is("br") || is("cr") || is("tab") {
+            self.add_text(" ");
+        }
+    }
+
+    fn end(&mut self, reader: &NsReader<&[u8]>, name: QName) {
+        let is = |local| XMLUtil::is_w(reader, name, local);
+
+        if is("p") {
+            if let Some((paragraph, true)) = self.paragraphs.pop() {
+                self.indexes[paragraph] = Some(self.text_paragraphs);
+                self.text_paragraphs += 1;
+            }
+        } else if is("hyperlink") {
+            self.hyperlinks.pop();
+        } else if is("fldSimple") {
+            self.fields.pop();
+        } else if is("instrText") {
+            self.inside_instruction = false;
+        } else if is("t") {
+            self.inside_text = false;
+        }
+    }
+
+    /// Add the link for a `w:hyperlink` element with relationship `id` and bookmark `anchor`. Returns its index.
+    fn hyperlink(&mut self, id: Option<String>, anchor: Option<String>) -> usize {
+        let rel = id.as_ref().and_then(|id| self.rels.get(id));
+        let target = match (rel, anchor) {
+            (Some(r), Some(a)) => format!("{}#{}", r.target, a),
+            (Some(r), None) => r.target.clone(),
+            (None, Some(a)) if id.is_none() => format!("#{}", a),
+            (None, _) => String::new(),
+        };
+        let link = Link {
+            rel_type: rel.and_then(|r| r.rel_type.clone()),
+            target_mode: rel.and_then(|r| r.target_mode.clone()),
+            external: rel.is_some_and(|r| r.is_external()),
+            id, text: Some(String::new()), ..self.link(target)
+        };
+        self.add_link(link)
+    }
+
+    /// Add the link for a field with `instruction` if it is a `HYPERLINK` field. Returns its index.
+    fn field_link(&mut self, instruction: &str) -> Option<usize> {
+        if FieldUtil::field_type(instruction) != "HYPERLINK" {
+            return None;
+        }
+
+        let (target, external) = LinkUtil::field_target(instruction);
+        let link = Link { text: Some(String::new()), external, ..self.link(target) };
+        Some(self.add_link(link))
+    }
+
+    fn add_link(&mut self, link: Link) -> usize {
+        self.links.push((link, self.paragraphs.last().map(|(p, _)| *p)));
+        self.links.len() - 1
+    }
+
+    fn add_instruction(&mut self, text: &str) {
+        if let Some(field) = self.fields.last_mut().filter(|f| !f.result) {
+            field.instruction.push_str(text);
+        }
+    }
+
+    /// Add `text` to the paragraph and to the text of the links it is part of. Text in the instruction of a
+    /// field, such as the result of a nested field, is part of that instruction.
+    fn add_text(&mut self, text: &str) {
+        if text.is_empty() {
+            return;
+        }
+        if let Some((_, has_text)) = self.paragraphs.last_mut() {
+            *has_text = true;
+        }
+        if self.fields.last().is_some_and(|f| !f.result) {
+            self.add_instruction(text);
+            return;
+        }
+
+        let fields = self.fields.iter().rev().take_while(|f| f.result).filter_map(|f| f.link);
+        let links: Vec<usize> = self.hyperlinks.iter().copied().chain(fields).collect();
+        for index in links {
+            if let Some(t) = &mut self.links[index].0.text {
+                t.push_str(text);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::LinkUtil;
+    use std::path::Path;
+    use crate::docx_package::DocxPackage;
+    use crate::error::DocxResult;
+    use crate::reporter::CollectingReporter;
+
+    #[test]
+    fn test_links() -> DocxResult<()> {
+        let pkg = DocxPackage::from_dir("./src/test/test_tree16")?;
+
+        let mut rep = CollectingReporter::default();
+        LinkUtil::links(&pkg, "links.docx", &mut rep)?;
+
+        let links: Vec<_> = rep.links.iter()
+            .map(|l| (l.part.as_str(), l.paragraph, l.text.as_deref().unwrap_or_default(), l.target.as_str(), l.external))
+            .collect();
+        assert_eq!(vec![
+            ("word/document.xml", Some(1), "the docs", "https://example.com/docs", true),
+            ("word/document.xml", Some(3), "the details", "#details", false),
+            ("word/document.xml", Some(4), "the field", "https://example.com/a?x=1&y=2#top", true),
+            ("word/document.xml", Some(5), "jump", "#details", false),
+            ("word/document.xml", Some(6), "the report", "files/report.pdf", true),
+            ("word/header1.xml", Some(0), "Home", "https://example.com/", true),
+        ], links);
+
+        let link = &rep.links[0];
+        assert_eq!(Some("rId2"), link.id.as_deref());
+        assert_eq!(Some("http://schemas.openxmlformats.org/officeDocument/2006/relationships/hyperlink"),
+            link.rel_type.as_deref());
+        assert_eq!(Some("External"), link.target_mode.as_deref());
+        assert_eq!(None, rep.links[2].id);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_check_links() -> DocxResult<()> {
+        let pkg = DocxPackage::from_dir("./src/test/test_tree17")?;
+
+        let mut rep = CollectingReporter::default();
+        LinkUtil::check_links(&pkg, "broken.docx", Some(Path::new("./src/test")), &mut rep)?;
+
+        let problems: Vec<_> = rep.link_problems.iter()
+            .map(|p| (p.link.part.as_str(), p.link.paragraph, p.link.id.as_deref(), p.link.target.as_str(), p.problem.as_str()))
+            .collect();
+        assert_eq!(vec![
+            ("word/document.xml", Some(4), Some("rId9"), "", "missing relationship"),
+            ("word/document.xml", Some(6), Some("rId4"), "files/missing%20report.pdf", "file not found"),
+            ("word/document.xml", Some(7), Some("rId5"), "http://exa mple.com/", "invalid character in URL"),
+            ("word/document.xml", None, Some("rId6"), "https://example.com/unused", "unused relationship"),
+            ("word/document.xml", Some(3), None, "#nowhere", "missing bookmark"),
+            ("word/document.xml", Some(8), None, "https://", "missing host"),
+            ("word/header1.xml", Some(0), Some("rId1"), "mailto:info", "invalid e-mail address"),
+        ], problems);
+
+        // A document without problems, files are not checked without a directory
+        let mut rep = CollectingReporter::default();
+        LinkUtil::check_links(&DocxPackage::from_dir("./src/test/test_tree16")?, "links.docx", None, &mut rep)?;
+        assert_eq!(0, rep.link_problems.len());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_url_problem() {
+        assert_eq!(None, LinkUtil::url_problem("https://user@example.com:8080/a%20b?q=1#top", "https"));
+        assert_eq!(None, LinkUtil::url_problem("http://[::1]:80/", "http"));
+        assert_eq!(None, LinkUtil::url_problem("mailto:a@example.com,b@example.com?subject=Hi", "mailto"));
+        assert_eq!(Some("invalid host"), LinkUtil::url_problem("https://exa_mple!.com/", "https"));
+        assert_eq!(Some("invalid port"), LinkUtil::url_problem("https://example.com:99999/", "https"));
+        assert_eq!(Some("missing host"), LinkUtil::url_problem("http:example.com", "http"));
+        assert_eq!(Some("invalid percent-encoding"), LinkUtil::url_problem("https://example.com/%zz", "https"));
+    }
+
+    #[test]
+    fn test_field_target() {
+        assert_eq!(("https://example.com/".to_string(), true),
+            LinkUtil::field_target(r#"HYPERLINK "https://example.com/" \o "Tip" \t "_blank""#));
+        assert_eq!(("#_Toc1".to_string(), false), LinkUtil::field_target(r#" HYPERLINK \l "_Toc1" "#));
+        assert_eq!((r"C:\docs\a.pdf".to_string(), true), LinkUtil::field_target(r#"HYPERLINK "C:\\docs\\a.pdf""#));
+    }
+}
@@ -0,0 +1,264 @@
+use crate::docx_package::DocxPackage;
+use crate::document_model::{Block, Cell, DocumentModel, ParagraphKind, Run};
+use crate::error::DocxResult;
+
+pub struct MarkdownUtil;
+
+impl MarkdownUtil {
+    /// Convert the main document of `pkg` to Markdown. Headings, quotes and lists are taken from the
+    /// paragraph styles and numbering as read by `DocumentModel`, tables become pipe tables with the
+    /// first row as header.
+    pub fn to_markdown(pkg: &DocxPackage) -> DocxResult<String> {
+        let blocks = DocumentModel::read(pkg)?;
+        Ok(Self::blocks(&blocks))
+    }
+
+    fn blocks(blocks: &[Block]) -> String {
+        let mut out = String::new();
+
+        // The widths of the markers of the list items that the next item can be nested in,
+        // and the type and number of the last item at each level of the list
+        let mut markers: Vec<usize> = vec![];
+        let mut numbers: Vec<(bool, usize)> = vec![];
+
+        for block in blocks {
+            let (kind, text) = match block {
+                Block::Paragraph { kind, runs, .. } => {
+                    let text = Self::runs(runs, "\\\n");
+                    if text.trim().is_empty() {
+                        continue;
+                    }
+                    (Some(*kind), text)
+                },
+                Block::Table { rows } => {
+                    if rows.is_empty() {
+                        continue;
+                    }
+                    (None, Self::table(rows))
+                }
+            };
+
+            let in_list = !markers.is_empty();
+            if let Some(ParagraphKind::ListItem { ordered, level }) = kind {
+                if !in_list && !out.is_empty() {
+                    out.push('\n');
+                }
+
+                numbers.resize(level + 1, (ordered, 0));
+                if numbers[level].0 != ordered {
+                    numbers[level] = (ordered, 0);
+                }
+                numbers[level].1 += 1;
+                // Levels that were skipped get the width of a bullet
+                markers.resize(level, 2);
+
+                let marker = if ordered { format!("{}. ", numbers[level].1) } else { "- ".to_string() };
+                let indent = " ".repeat(markers.iter().sum());
+                out.push_str(&indent);
+                out.push_str(&marker);
+                out.push_str(&text.replace('\n', &format!("\n{}{}", indent, " ".repeat(marker.len()))));
+                out.push('\n');
+                markers.push(marker.len());
+                continue;
+            }
+
+            markers.clear();
+            numbers.clear();
+            if !out.is_empty() {
+                out.push('\n');
+            }
+            match kind {
+                Some(ParagraphKind::Heading(level)) => {
+                    out.push_str(&format!("{} {}", "#".repeat(level), text.replace("\\\n", " ")));
+                },
+                Some(ParagraphKind::Quote) => {
+                    out.push_str("> ");
+                    out.push_str(&text.replace('\n', "\n> "));
+                },
+                Some(_) => out.push_str(&Self::escape_block_start(&text)),
+                None => out.push_str(&text),
+            }
+            out.push('\n');
+        }
+        out
+    }
+
+    /// Format `rows` as a pipe table. The paragraphs in a cell are separated by `<br>`.
+    fn table(rows: &[Vec<Cell>]) -> String {
+        let mut out = String::new();
+        for (i, row) in rows.iter().enumerate() {
+            let cells: Vec<String> = row.iter().map(|c| Self::cell(&c.blocks).replace('|', "\\|")).collect();
+            out.push_str(&format!("| {} |\n", cells.join(" | ")));
+            if i == 0 {
+                out.push_str(&format!("|{}\n", " --- |".repeat(row.len())));
+            }
+        }
+        out.pop();
+        out
+    }
+
+    /// The text of the paragraphs in a table cell, including those in nested tables.
+    fn cell(blocks: &[Block]) -> String {
+        let mut paras = vec![];
+        for block in blocks {
+            match block {
+                Block::Paragraph { runs, .. } => paras.push(Self::runs(runs, "<br>")),
+                Block::Table { rows } => {
+                    paras.extend(rows.iter().flatten().map(|c| Self::cell(&c.blocks)));
+                }
+            }
+        }
+        paras.retain(|p| !p.trim().is_empty());
+        paras.join("<br>")
+    }
+
+    /// Format `runs` as inline Markdown, with `line_break` for the line breaks in the text.
+    fn runs(runs: &[Run], line_break: &str) -> String {
+        // Merge the runs that only differ in formatting that Markdown doesn't have
+        let mut merged: Vec<Run> = vec![];
+        for run in runs.iter().filter(|r| !r.text.is_empty()) {
+            match merged.last_mut() {
+                Some(last) if (last.bold, last.italic, last.code, &last.link)
+                        == (run.bold, run.italic, run.code, &run.link) => last.text.push_str(&run.text),
+                _ => merged.push(run.clone()),
+            }
+        }
+        let runs = &merged;
+
+        let mut out = String::new();
+        let mut i = 0;
+        while i < runs.len() {
+            // Runs with the same hyperlink form a single link
+            let link = &runs[i].link;
+            let count = runs[i..].iter().take_while(|r| r.link == *link).count();
+            let text: String = runs[i..i + count].iter().map(Self::run).collect();
+            match link {
+                Some(url) => out.push_str(&format!("[{}]({})", text, url.replace(' ', "%20")
+                    .replace('(', "%28").replace(')', "%29"))),
+                None => out.push_str(&text),
+            }
+            i += count;
+        }
+        out.replace('\n', line_break)
+    }
+
+    fn run(run: &Run) -> String {
+        let text = if run.code {
+            // The fence has to be longer than any sequence of backticks in the code
+            let longest = run.text.split(|c| c != '`').map(|s| s.len()).max().unwrap_or(0);
+            let fence = "`".repeat(longest + 1);
+            let pad = if run.text.starts_with('`') || run.text.ends_with('`') { " " } else { "" };
+            format!("{}{}{}{}{}", fence, pad, run.text, pad, fence)
+        } else {
+            Self::escape(&run.text)
+        };
+
+        let marker = match (run.bold, run.italic) {
+            (true, true) => "***",
+            (true, false) => "**",
+            (false, true) => "*",
+            (false, false) => return text,
+        };
+
+        // Emphasis can't start or end with whitespace, so keep it outside the markers
+        let trimmed = text.trim();
+        if trimmed.is_empty() {
+            return text;
+        }
+        let start = text.len() - text.trim_start().len();
+        format!("{}{}{}{}{}", &text[..start], marker, trimmed, marker, &text[start + trimmed.len()..])
+    }
+
+    /// Escape the characters in `text` that would otherwise be read as inline Markdown.
+    fn escape(text: &str) -> String {
+        let mut out = String::with_capacity(text.len());
+        for c in text.chars() {
+            match c {
+                '\\' | '`' | '*' | '_' | '[' | ']' | '<' => {
+                    out.push('\\');
+                    out.push(c);
+                },
+                '\t' => out.push(' '),
+                _ => out.push(c),
+            }
+        }
+        out
+    }
+
+    /// Escape the start of a paragraph that would otherwise be read as a heading, quote or list item.
+    fn escape_block_start(text: &str) -> String {
+        if text.starts_with(['#', '>', '-', '+', '=']) {
+            return format!("\\{}", text);
+        }
+
+        let digits = text.chars().take_while(|c| c.is_ascii_digit()).count();
+        if digits > 0 && text[digits..].starts_with(['.', ')']) {
+            return format!("{}\\{}", &text[..digits], &text[digits..]);
+        }
+        text.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::MarkdownUtil;
+    use crate::docx_package::DocxPackage;
+    use crate::document_model::Run;
+    use crate::error::DocxResult;
+
+    #[test]
+    fn test_to_markdown() -> DocxResult<()> {
+        let pkg = DocxPackage::from_dir("./src/test/test_tree12")?;
+        let md = MarkdownUtil::to_markdown(&pkg)?;
+
+        assert_eq!("# Release notes
+
+## Installation
+
+Some **bold**, *italic* and `cargo build` text, see [the site](https://example.com/docs).
+
+- First item
+  - Nested item
+1. Step one
+2. Step two
+
+> Quoted\\
+> text
+
+Back to [installation](#install)
+
+| Name | Value |
+| --- | --- |
+| Timeout | 30 \\| 60<br>seconds |
+| Mode | **fast** |
+", md);
+        Ok(())
+    }
+
+    #[test]
+    fn test_to_markdown_lists() -> DocxResult<()> {
+        let pkg = DocxPackage::from_dir("./src/test/test_tree4")?;
+        let md = MarkdownUtil::to_markdown(&pkg)?;
+
+        assert!(md.starts_with("Just some links here\n\n- [your-somewhere](http://www.example.com/somewhere)\n"), "{}", md);
+        Ok(())
+    }
+
+    #[test]
+    fn test_runs() {
+        let runs = vec![
+            Run { text: "a *star* and ".to_string(), ..Run::default() },
+            Run { text: "bold ".to_string(), bold: true, ..Run::default() },
+            Run { text: "x`y".to_string(), code: true, ..Run::default() },
+            Run { text: "\nnext".to_string(), italic: true, ..Run::default() },
+        ];
+        assert_eq!("a \\*star\\* and **bold** ``x`y``\\\n*next*", MarkdownUtil::runs(&runs, "\\\n"));
+    }
+
+    #[test]
+    fn test_escape_block_start() {
+        assert_eq!("\\# not a heading", MarkdownUtil::escape_block_start("# not a heading"));
+        assert_eq!("2024\\. was a year", MarkdownUtil::escape_block_start("2024. was a year"));
+        assert_eq!("Plain text", MarkdownUtil::escape_block_start("Plain text"));
+    }
+}
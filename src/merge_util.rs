@@ -0,0 +1,255 @@
+use regex::Regex;
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::sync::LazyLock;
+
+use crate::docx_package::DocxPackage;
+use crate::error::{DocxError, DocxResult};
+use crate::reporter::{CollectingReporter, DocxReporter};
+use crate::xml_util::XMLUtil;
+
+/// The fields of a record of a data file, by name.
+pub type Record = BTreeMap<String, String>;
+
+/// Matches a placeholder such as `{{ customer_name }}`, capturing the name of the field.
+const PLACEHOLDER: &str = r"\{\{\s*([^{}]+?)\s*\}\}";
+
+static PLACEHOLDER_REGEX: LazyLock<Regex> = LazyLock::new(|| Regex::new(PLACEHOLDER).expect("valid placeholder regex"));
+
+pub struct MergeUtil;
+
+impl MergeUtil {
+    /// Read the records from the data file `file`. Files ending in `.json` must contain an array of objects,
+    /// other files are read as CSV, or as TSV if they end in `.tsv`, with the names of the fields on the first line.
+    pub fn read_records(file: &str) -> DocxResult<Vec<Record>> {
+        let data = fs::read_to_string(file)?;
+        let lower = file.to_lowercase();
+        if lower.ends_with(".json") {
+            Self::parse_json(&data)
+        } else {
+            Self::parse_delimited(&data, if lower.ends_with(".tsv") { '\t' } else { ',' })
+        }
+    }
+
+    /// Parse `data` as a JSON array of objects. Values that are not strings are converted to their JSON text,
+    /// except for `null` which becomes an empty string.
+    pub fn parse_json(data: &str) -> DocxResult<Vec<Record>> {
+        let json: serde_json::Value = serde_json::from_str(data)
+            .map_err(|e| DocxError::InvalidData(e.to_string()))?;
+        let serde_json::Value::Array(items) = json else {
+            return Err(DocxError::InvalidData("expected an array of records".to_string()));
+        };
+
+        items.into_iter().enumerate().map(|(i, item)| {
+            let serde_json::Value::Object(fields) = item else {
+                return Err(DocxError::InvalidData(format!("record {} is not an object", i + 1)));
+            };
+            Ok(Self::record(fields))
+        }).collect()
+    }
+
+    /// Convert the `fields` of a JSON object to a record, the same way as `parse_json()`.
+    pub(crate) fn record(fields: serde_json::Map<String, serde_json::Value>) -> Record {
+        fields.into_iter().map(|(k, v)| {
+            let value = match v {
+                serde_json::Value::String(s) => s,
+                serde_json::Value::Null => String::new(),
+                other => other.to_string(),
+            };
+            (k, value)
+        }).collect()
+    }
+
+    /// Parse `data` as values separated by `delimiter`, quoted as described in RFC 4180 where needed.
+    /// The first line holds the names of the fields. Empty lines are skipped.
+    pub fn parse_delimited(data: &str, delimiter: char) -> DocxResult<Vec<Record>> {
+        let data = data.strip_prefix('\u{feff}').unwrap_or(data);
+
+        let mut lines: Vec<Vec<String>> = vec![];
+        let mut line: Vec<String> = vec![];
+        let mut value = String::new();
+        let mut quoted = false;
+        let mut chars = data.chars().peekable();
+        while let Some(c) = chars.next() {
+            match c {
+                '"' if quoted => {
+                    if chars.peek() == Some(&'"') {
+                        chars.next();
+                        value.push('"');
+                    } else {
+                        quoted = false;
+                    }
+                },
+                '"' if value.is_empty() => quoted = true,
+                '\r' if !quoted && chars.peek() == Some(&'\n') => (),
+                '\n' if !quoted => {
+                    line.push(std::mem::take(&mut value));
+                    lines.push(std::mem::take(&mut line));
+                },
+                c if c == delimiter && !quoted => line.push(std::mem::take(&mut value)),
+                c => value.push(c),
+            }
+        }
+        if quoted {
+            return Err(DocxError::InvalidData("unterminated quoted value".to_string()));
+        }
+        if !value.is_empty() || !line.is_empty() {
+            line.push(value);
+            lines.push(line);
+        }
+        lines.retain(|l| l.len() > 1 || l.first().is_some_and(|v| !v.is_empty()));
+
+        let mut lines = lines.into_iter();
+        let Some(names) = lines.next() else {
+            return Ok(vec![]);
+        };
+        lines.enumerate().map(|(i, values)| {
+            if values.len() != names.len() {
+                return Err(DocxError::InvalidData(format!("line {} has {} values, expected {}",
+                    i + 2, values.len(), names.len())));
+            }
+            Ok(names.iter().cloned().zip(values).collect())
+        }).collect()
+    }
+
+    /// Replace the placeholders such as `{{customer_name}}` in all the story parts of `pkg` with the values of
+    /// the fields in `record`. Placeholders that are split over multiple runs of text are replaced as well.
+    /// Returns the names of the placeholders in the document that have no value in `record`.
+    pub fn merge(pkg: &mut DocxPackage, src_file: &str, record: &Record, reporter: &mut dyn DocxReporter)
+            -> DocxResult<Vec<String>> {
+        let mut placeholders = CollectingReporter::default();
+        XMLUtil::grep_xml(pkg, src_file, PLACEHOLDER, &mut placeholders)?;
+        let names = Self::placeholders(placeholders.matches.iter().map(|p| p.text.as_str()));
+
+        let mut missing = vec![];
+        let mut values = HashMap::new();
+        for name in names {
+            match record.get(&name) {
+                Some(value) => { values.insert(name, value.clone()); },
+                None => missing.push(name),
+            }
+        }
+
+        // Replace all the placeholders that have a value in a single pass over the document
+        if !values.is_empty() {
+            let names: Vec<String> = values.keys().map(|n| regex::escape(n)).collect();
+            let regex = Regex::new(&format!(r"\{{\{{\s*({})\s*\}}\}}", names.join("|")))?;
+            XMLUtil::replace_xml_values(pkg, src_file, regex, values, reporter)?;
+        }
+        Ok(missing)
+    }
+
+    /// Fill in the placeholders in `pattern` with the values of the fields in `record` and `{{#}}` with `number`,
+    /// to give the file name of a merged document. Characters that can't be used in file names are replaced by `_`.
+    pub fn file_name(pattern: &str, record: &Record, number: usize) -> String {
+        PLACEHOLDER_REGEX.replace_all(pattern, |caps: &regex::Captures| {
+            let value = match &caps[1] {
+                "#" => number.to_string(),
+                name => record.get(name).cloned().unwrap_or_default(),
+            };
+            value.replace(|c: char| c.is_control() || "/\\:*?\"<>|".contains(c), "_")
+        }).to_string()
+    }
+
+    /// The distinct names of the placeholders in `texts`, in the order they first appear.
+    fn placeholders<'a>(texts: impl Iterator<Item = &'a str>) -> Vec<String> {
+        let mut names: Vec<String> = vec![];
+        for text in texts {
+            for caps in PLACEHOLDER_REGEX.captures_iter(text) {
+                if !names.iter().any(|n| n == &caps[1]) {
+                    names.push(caps[1].to_string());
+                }
+            }
+        }
+        names
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::MergeUtil;
+    use crate::docx_package::DocxPackage;
+    use crate::error::{DocxError, DocxResult};
+    use crate::reporter::CollectingReporter;
+    use crate::test_util::record;
+    use crate::xml_util::XMLUtil;
+
+    #[test]
+    fn test_merge() -> DocxResult<()> {
+        let mut pkg = DocxPackage::from_dir("./src/test/test_tree13")?;
+
+        let rec = record(&[("customer_name", "Jane Doe"), ("amount", "$1,200"), ("unused", "x")]);
+        let mut rep = CollectingReporter::default();
+        let missing = MergeUtil::merge(&mut pkg, "letter.docx", &rec, &mut rep)?;
+        assert_eq!(vec!["due_date"], missing);
+
+        let mut rep = CollectingReporter::default();
+        XMLUtil::cat(&pkg, "letter.docx", &mut rep)?;
+        let texts: Vec<String> = rep.paragraphs.into_iter().map(|p| p.text).collect();
+        assert_eq!(vec![
+            "Dear Jane Doe,",
+            "Your invoice of $1,200 is due on {{due_date}}.",
+            "Kind regards to Jane Doe",
+            "Invoice for Jane Doe",
+        ], texts);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_merge_single_pass() -> DocxResult<()> {
+        let mut pkg = DocxPackage::from_dir("./src/test/test_tree13")?;
+
+        // A value that looks like a placeholder is not filled in itself
+        let rec = record(&[("customer_name", "{{amount}}"), ("amount", "$5")]);
+        let mut rep = CollectingReporter::default();
+        MergeUtil::merge(&mut pkg, "letter.docx", &rec, &mut rep)?;
+        assert_eq!(4, rep.replacements.len());
+
+        let mut rep = CollectingReporter::default();
+        XMLUtil::cat(&pkg, "letter.docx", &mut rep)?;
+        let texts: Vec<String> = rep.paragraphs.into_iter().map(|p| p.text).collect();
+        assert_eq!(vec![
+            "Dear {{amount}},",
+            "Your invoice of $5 is due on {{due_date}}.",
+            "Kind regards to {{amount}}",
+            "Invoice for {{amount}}",
+        ], texts);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_delimited() -> DocxResult<()> {
+        let records = MergeUtil::parse_delimited(
+            "\u{feff}name,address\r\n\"Doe, Jane\",\"1 Main St\nSpringfield\"\r\n\nBob,\"Say \"\"hi\"\"\"\n", ',')?;
+        assert_eq!(vec![
+            record(&[("name", "Doe, Jane"), ("address", "1 Main St\nSpringfield")]),
+            record(&[("name", "Bob"), ("address", "Say \"hi\"")]),
+        ], records);
+
+        let records = MergeUtil::parse_delimited("a\tb\n1\t2", '\t')?;
+        assert_eq!(vec![record(&[("a", "1"), ("b", "2")])], records);
+
+        assert!(matches!(MergeUtil::parse_delimited("a,b\n1\n", ','), Err(DocxError::InvalidData(_))));
+        assert!(matches!(MergeUtil::parse_delimited("a\n\"1\n", ','), Err(DocxError::InvalidData(_))));
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_json() -> DocxResult<()> {
+        let records = MergeUtil::parse_json(r#"[{"name": "Jane", "amount": 12.5, "vip": true, "note": null}]"#)?;
+        assert_eq!(vec![record(&[("name", "Jane"), ("amount", "12.5"), ("vip", "true"), ("note", "")])], records);
+
+        assert!(matches!(MergeUtil::parse_json(r#"{"name": "Jane"}"#), Err(DocxError::InvalidData(_))));
+        assert!(matches!(MergeUtil::parse_json("[1]"), Err(DocxError::InvalidData(_))));
+        Ok(())
+    }
+
+    #[test]
+    fn test_file_name() {
+        let rec = record(&[("customer_name", "Jane/Doe")]);
+        assert_eq!("letter_Jane_Doe_7.docx", MergeUtil::file_name("letter_{{customer_name}}_{{#}}.docx", &rec, 7));
+        assert_eq!("_.docx", MergeUtil::file_name("{{missing}}_.docx", &rec, 1));
+    }
+}
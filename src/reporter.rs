@@ -0,0 +1,186 @@
+/// A paragraph of text found in a part of the document. `index` is the position of the paragraph
+/// among the paragraphs in the part that contain text and `style` is the id of its paragraph style.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Paragraph {
+    pub src_file: String,
+    pub part: String,
+    pub index: usize,
+    pub style: Option<String>,
+    pub text: String,
+}
+
+/// A replacement made in a part of the document. For replacements in the text `old` and `new` hold
+/// the text of the whole paragraph before and after the change. If the replacement was made in the value
+/// of an attribute, then `attribute` holds the name of that attribute and `old` and `new` its values.
+/// `paragraph` is the index of the paragraph affected, counted the same way as `Paragraph::index`,
+/// if the replacement was made inside a paragraph with text.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Replacement {
+    pub src_file: String,
+    pub part: String,
+    pub paragraph: Option<usize>,
+    pub attribute: Option<String>,
+    pub old: String,
+    pub new: String,
+}
+
+/// A link found in part `part` of the document, which is either a relationship part or a story part. `id`,
+/// `rel_type` and `target_mode` hold the `Id`, `Type` and `TargetMode` attributes of the relationship, if the link
+/// has one. For links in the text of the document `text` holds the text the link is attached to and `paragraph`
+/// the index of its paragraph, counted the same way as `Paragraph::index`. `external` is set if the target is
+/// outside of the document.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Link {
+    pub src_file: String,
+    pub part: String,
+    pub id: Option<String>,
+    pub rel_type: Option<String>,
+    pub target: String,
+    pub target_mode: Option<String>,
+    pub text: Option<String>,
+    pub paragraph: Option<usize>,
+    pub external: bool,
+}
+
+/// A problem found when checking the links of the document. `link` is the link or relationship that has the
+/// problem and `problem` describes it, e.g. `missing bookmark`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct LinkProblem {
+    pub link: Link,
+    pub problem: String,
+}
+
+/// A tracked change that was accepted or rejected in part `part` of the document. `kind` is the local name
+/// of the revision element, e.g. `ins`, `del` or `rPrChange`, and `author` and `date` hold its `w:author`
+/// and `w:date` attributes, if present.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Revision {
+    pub src_file: String,
+    pub part: String,
+    pub kind: String,
+    pub author: Option<String>,
+    pub date: Option<String>,
+    pub accepted: bool,
+}
+
+/// A table found in part `part` of the document. `index` is the position of the table among all the tables
+/// in the document, counting nested tables in the order they start. `rows` holds the text of the cells, with
+/// a column for each column of the table grid.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Table {
+    pub src_file: String,
+    pub part: String,
+    pub index: usize,
+    pub rows: Vec<Vec<String>>,
+}
+
+/// A content control, `w:sdt`, found in part `part` of the document. `tag` and `alias` hold the values of its
+/// `w:tag` and `w:alias` properties, if present, and `kind` the local name of the property that gives its type,
+/// e.g. `text`, `richText`, `date` or `dropDownList`. `value` is the text of its content, which is the placeholder
+/// text of the control if `placeholder` is set.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Control {
+    pub src_file: String,
+    pub part: String,
+    pub tag: Option<String>,
+    pub alias: Option<String>,
+    pub kind: String,
+    pub value: String,
+    pub placeholder: bool,
+}
+
+/// A field found in part `part` of the document. `instruction` is the field code, such as `MERGEFIELD name`, with
+/// the instructions of nested fields between braces, and `kind` is its first word in upper case. `result` is the
+/// text of the result of the field as it was last updated by the word processor.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Field {
+    pub src_file: String,
+    pub part: String,
+    pub kind: String,
+    pub instruction: String,
+    pub result: String,
+}
+
+/// Receives the results of the operations in `XMLUtil`. Implement this trait to process the results,
+/// for example to print them or to collect them in a data structure. All methods have an empty default
+/// implementation so that only the ones of interest need to be implemented.
+pub trait DocxReporter {
+    /// Called for each paragraph of text when listing the text of a document.
+    fn on_paragraph(&mut self, _para: &Paragraph) {}
+
+    /// Called for each paragraph that matches a search.
+    fn on_match(&mut self, _para: &Paragraph) {}
+
+    /// Called for each replacement made in the document.
+    fn on_replacement(&mut self, _repl: &Replacement) {}
+
+    /// Called for each link found in the document.
+    fn on_link(&mut self, _link: &Link) {}
+
+    /// Called for each problem found when checking the links of the document.
+    fn on_link_problem(&mut self, _problem: &LinkProblem) {}
+
+    /// Called for each tracked change that was accepted or rejected.
+    fn on_revision(&mut self, _rev: &Revision) {}
+
+    /// Called for each table in the document.
+    fn on_table(&mut self, _table: &Table) {}
+
+    /// Called for each content control in the document.
+    fn on_control(&mut self, _control: &Control) {}
+
+    /// Called for each field in the document.
+    fn on_field(&mut self, _field: &Field) {}
+}
+
+/// A `DocxReporter` that collects all results it receives.
+#[derive(Debug, Default)]
+pub struct CollectingReporter {
+    pub paragraphs: Vec<Paragraph>,
+    pub matches: Vec<Paragraph>,
+    pub replacements: Vec<Replacement>,
+    pub links: Vec<Link>,
+    pub link_problems: Vec<LinkProblem>,
+    pub revisions: Vec<Revision>,
+    pub tables: Vec<Table>,
+    pub controls: Vec<Control>,
+    pub fields: Vec<Field>,
+}
+
+impl DocxReporter for CollectingReporter {
+    fn on_paragraph(&mut self, para: &Paragraph) {
+        self.paragraphs.push(para.clone());
+    }
+
+    fn on_match(&mut self, para: &Paragraph) {
+        self.matches.push(para.clone());
+    }
+
+    fn on_replacement(&mut self, repl: &Replacement) {
+        self.replacements.push(repl.clone());
+    }
+
+    fn on_link(&mut self, link: &Link) {
+        self.links.push(link.clone());
+    }
+
+    fn on_link_problem(&mut self, problem: &LinkProblem) {
+        self.link_problems.push(problem.clone());
+    }
+
+    fn on_revision(&mut self, rev: &Revision) {
+        self.revisions.push(rev.clone());
+    }
+
+    fn on_table(&mut self, table: &Table) {
+        self.tables.push(table.clone());
+    }
+
+    fn on_control(&mut self, control: &Control) {
+        self.controls.push(control.clone());
+    }
+
+    fn on_field(&mut self, field: &Field) {
+        self.fields.push(field.clone());
+    }
+}
@@ -0,0 +1,425 @@
+use quick_xml::events::BytesStart;
+use std::collections::HashSet;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::docx_package::DocxPackage;
+use crate::error::DocxResult;
+use crate::reporter::{DocxReporter, Revision};
+use crate::xml_tree::{Element, Node, XmlTree};
+use crate::xml_util::{StoryType, XMLUtil};
+
+/// Revisions of the content, the content is inserted or removed as a whole.
+const INSERTIONS: [&str; 2] = ["ins", "moveTo"];
+const DELETIONS: [&str; 2] = ["del", "moveFrom"];
+
+/// Revisions of properties. These hold the properties from before the change.
+const PROPERTY_CHANGES: [&str; 8] = ["rPrChange", "pPrChange", "sectPrChange", "tblPrChange",
+    "tblPrExChange", "trPrChange", "tcPrChange", "tblGridChange"];
+
+/// The children of properties that are not part of the previous properties held by a property change,
+/// and if they go before those properties.
+const KEPT_PROPERTIES: [(&str, &[&str], bool); 4] = [
+    ("pPr", &["rPr", "sectPr"], false),
+    ("rPr", &["ins", "del", "moveFrom", "moveTo"], true),
+    ("sectPr", &["headerReference", "footerReference"], true),
+    ("trPr", &["ins", "del"], false),
+];
+
+/// The markers of the range of content that was moved, with the matching end markers.
+const MOVE_RANGES: [(&str, &str); 2] = [("moveFromRangeStart", "moveFromRangeEnd"),
+    ("moveToRangeStart", "moveToRangeEnd")];
+
+/// Selects the tracked changes to accept or reject. A revision is selected if it matches all the
+/// criteria that are specified. The dates are compared as ISO 8601 strings, so a date such as `2024-03-01`
+/// can be used as well as a full date and time.
+#[derive(Clone, Debug, Default)]
+pub struct RevisionFilter {
+    /// Only select revisions by this author.
+    pub author: Option<String>,
+
+    /// Only select revisions made on or after this date.
+    pub after: Option<String>,
+
+    /// Only select revisions made before this date.
+    pub before: Option<String>,
+}
+
+impl RevisionFilter {
+    fn matches(&self, el: &Element) -> bool {
+        let author = el.attrs.get("author");
+        let date = el.attrs.get("date");
+
+        self.author.as_ref().is_none_or(|a| author == Some(a))
+            && self.after.as_ref().is_none_or(|a| date.is_some_and(|d| d >= a))
+            && self.before.as_ref().is_none_or(|b| date.is_some_and(|d| d < b))
+    }
+}
+
+/// The author and date to record on the tracked changes made by an operation.
+#[derive(Clone, Debug)]
+pub struct TrackChanges {
+    pub author: String,
+
+    /// The date of the changes in ISO 8601 format, e.g. `2024-03-01T12:00:00Z`
+    pub date: String,
+}
+
+impl TrackChanges {
+    /// Track changes made by `author` at the current time.
+    pub fn new(author: &str) -> Self {
+        let secs = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+        TrackChanges { author: author.to_string(), date: RevisionUtil::format_date(secs) }
+    }
+}
+
+/// Resolves the tracked changes in a part.
+struct Resolver<'a> {
+    accept: bool,
+    filter: &'a RevisionFilter,
+    src_file: &'a str,
+    part: &'a str,
+    revisions: Vec<Revision>,
+    removed_moves: HashSet<(String, String)>,
+}
+
+pub struct RevisionUtil;
+
+impl RevisionUtil {
+    /// Accept the tracked changes in all the story parts of the document that match `filter`. Inserted
+    /// content is kept, deleted content is removed and the previous properties of property changes are dropped.
+    /// Each change is reported to `reporter`.
+    pub fn accept_changes(pkg: &mut DocxPackage, src_file: &str, filter: &RevisionFilter,
+            reporter: &mut dyn DocxReporter) -> DocxResult<()> {
+        Self::resolve_changes(pkg, src_file, true, filter, reporter)
+    }
+
+    /// Reject the tracked changes in all the story parts of the document that match `filter`. Inserted
+    /// content is removed, deleted content is restored and property changes are reverted to the previous
+    /// properties. Each change is reported to `reporter`.
+    pub fn reject_changes(pkg: &mut DocxPackage, src_file: &str, filter: &RevisionFilter,
+            reporter: &mut dyn DocxReporter) -> DocxResult<()> {
+        Self::resolve_changes(pkg, src_file, false, filter, reporter)
+    }
+
+    /// Format `secs`, the number of seconds since the Unix epoch, as an ISO 8601 date and time in UTC,
+    /// which is the format of the `w:date` attribute.
+    pub fn format_date(secs: u64) -> String {
+        let days = (secs / 86400) as i64;
+        let time = secs % 86400;
+
+        // Convert the days since the epoch to a date in the proleptic Gregorian calendar
+        let z = days + 719468;
+        let era = z.div_euclid(146097);
+        let doe = z.rem_euclid(146097);
+        let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+        let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+        let mp = (5 * doy + 2) / 153;
+        let day = doy - (153 * mp + 2) / 5 + 1;
+        let month = if mp < 10 { mp + 3 } else { mp - 9 };
+        let year = yoe + era * 400 + i64::from(month <= 2);
+
+        format!("{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z", year, month, day,
+            time / 3600, time % 3600 / 60, time % 60)
+    }
+
+    fn resolve_changes(pkg: &mut DocxPackage, src_file: &str, accept: bool, filter: &RevisionFilter,
+            reporter: &mut dyn DocxReporter) -> DocxResult<()> {
+        for part in XMLUtil::get_story_parts(pkg, &StoryType::ALL)? {
+            let nodes = match pkg.get_part(&part) {
+                Some(xml) => XmlTree::parse(xml, &part)?,
+                None => continue
+            };
+
+            let mut resolver = Resolver {
+                accept, filter, src_file, part: &part, revisions: vec![], removed_moves: HashSet::new()
+            };
+            let nodes = resolver.resolve_nodes(nodes, false);
+            if resolver.revisions.is_empty() {
+                continue;
+            }
+
+            for rev in &resolver.revisions {
+                reporter.on_revision(rev);
+            }
+            pkg.set_part(&part, XmlTree::write(nodes)?);
+        }
+        Ok(())
+    }
+}
+
+impl Resolver<'_> {
+    /// Resolve the revisions in `nodes`. If `restore_deleted` is set the nodes are the content of a
+    /// deletion that is rejected, so deleted text becomes normal text again.
+    fn resolve_nodes(&mut self, nodes: Vec<Node>, restore_deleted: bool) -> Vec<Node> {
+        let mut result = vec![];
+
+        // A paragraph whose paragraph mark is removed, so that it is merged with the next paragraph
+        let mut merging: Option<Element> = None;
+        for node in nodes {
+            let el = match node {
+                Node::Element(el) => el,
+                other => {
+                    result.push(other);
+                    continue;
+                }
+            };
+
+            if el.is("p") {
+                let remove_mark = self.removes_mark(&el);
+                let mut para = self.resolve_element(el, restore_deleted);
+                if let Some(prev) = merging.take() {
+                    let at = usize::from(para.child("pPr").is_some());
+                    let content = prev.children.into_iter()
+                        .filter(|n| !matches!(n, Node::Element(e) if e.is("pPr")));
+                    para.children.splice(at..at, content);
+                }
+
+                if remove_mark {
+                    merging = Some(para);
+                } else {
+                    result.push(Node::Element(para));
+                }
+                continue;
+            }
+
+            if let Some(prev) = merging.take() {
+                result.push(Node::Element(prev));
+            }
+
+            if el.is("tr") && self.removes_mark(&el) {
+                if let Some(marker) = self.mark(&el) {
+                    self.report(marker);
+                }
+                continue;
+            }
+
+            if el.is_any(&INSERTIONS) || el.is_any(&DELETIONS) {
+                if !self.filter.matches(&el) {
+                    result.push(Node::Element(self.resolve_element(el, restore_deleted)));
+                    continue;
+                }
+
+                self.report(&el);
+                if el.empty {
+                    // A marker on a paragraph mark or a table row, handled by the paragraph or row itself
+                    continue;
+                }
+
+                let deletion = el.is_any(&DELETIONS);
+                if deletion != self.accept {
+                    result.extend(self.resolve_nodes(el.children, restore_deleted || deletion));
+                }
+            } else if el.is_any(&PROPERTY_CHANGES) {
+                if self.filter.matches(&el) {
+                    // When rejecting, the parent properties are replaced by resolve_element
+                    if self.accept {
+                        self.report(&el);
+                    }
+                } else {
+                    result.push(Node::Element(el));
+                }
+            } else if let Some((start, end)) = MOVE_RANGES.iter().find(|(s, e)| el.is(s) || el.is(e)) {
+                let id = el.attrs.get("id").cloned().unwrap_or_default();
+                let removed = if el.is(start) {
+                    let matches = self.filter.matches(&el);
+                    if matches {
+                        self.removed_moves.insert((end.to_string(), id));
+                    }
+                    matches
+                } else {
+                    self.removed_moves.contains(&(end.to_string(), id))
+                };
+                if !removed {
+                    result.push(Node::Element(el));
+                }
+            } else {
+                result.push(Node::Element(self.resolve_element(el, restore_deleted)));
+            }
+        }
+
+        if let Some(prev) = merging {
+            result.push(Node::Element(prev));
+        }
+        result
+    }
+
+    /// Resolve the revisions inside `el`.
+    fn resolve_element(&mut self, mut el: Element, restore_deleted: bool) -> Element {
+        if restore_deleted && (el.is("delText") || el.is("delInstrText")) {
+            let local = if el.is("delText") { "t" } else { "instrText" };
+            let name = el.start.name();
+            let new_name = match name.prefix() {
+                Some(p) => format!("{}:{}", String::from_utf8_lossy(p.as_ref()), local),
+                None => local.to_string(),
+            };
+            let start = BytesStart::new(new_name).with_attributes(el.start.attributes().flatten())
+                .into_owned();
+            el.start = start;
+            el.local = Some(local.to_string());
+        }
+
+        let mut children = std::mem::take(&mut el.children);
+        if !self.accept {
+            if let Some(previous) = self.previous_properties(&mut children) {
+                let (kept, before) = KEPT_PROPERTIES.iter()
+                    .find(|(parent, _, _)| el.is(parent))
+                    .map(|(_, kept, before)| (*kept, *before))
+                    .unwrap_or((&[], false));
+                let kept: Vec<Node> = children.into_iter()
+                    .filter(|n| matches!(n, Node::Element(e) if e.is_any(kept)))
+                    .collect();
+                children = if before {
+                    kept.into_iter().chain(previous).collect()
+                } else {
+                    previous.into_iter().chain(kept).collect()
+                };
+            }
+        }
+        el.children = self.resolve_nodes(children, restore_deleted);
+        el
+    }
+
+    /// If `children` contain a property change that is selected, remove it and return the properties from
+    /// before the change.
+    fn previous_properties(&mut self, children: &mut Vec<Node>) -> Option<Vec<Node>> {
+        let idx = children.iter().position(|n| matches!(n,
+            Node::Element(e) if e.is_any(&PROPERTY_CHANGES) && self.filter.matches(e)))?;
+
+        let change = match children.remove(idx) {
+            Node::Element(e) => e,
+            Node::Other(_) => unreachable!(),
+        };
+        self.report(&change);
+
+        let previous = change.children.into_iter().find_map(|n| match n {
+            Node::Element(e) if e.local.is_some() => Some(e.children),
+            _ => None,
+        });
+        Some(previous.unwrap_or_default())
+    }
+
+    /// Check if the paragraph mark of paragraph `el`, or table row `el`, is removed when resolving the
+    /// revisions. That is the case when accepting its deletion or rejecting its insertion.
+    fn removes_mark(&self, el: &Element) -> bool {
+        self.mark(el).is_some_and(|m| m.is(if self.accept { "del" } else { "ins" }))
+    }
+
+    /// The selected insertion or deletion marker of the paragraph mark of paragraph `el`, or of table row `el`.
+    fn mark<'e>(&self, el: &'e Element) -> Option<&'e Element> {
+        let props = if el.is("p") {
+            el.child("pPr").and_then(|p| p.child("rPr"))
+        } else {
+            el.child("trPr")
+        };
+
+        props?.children.iter().find_map(|n| match n {
+            Node::Element(e) if (e.is("ins") || e.is("del")) && self.filter.matches(e) => Some(e),
+            _ => None,
+        })
+    }
+
+    fn report(&mut self, el: &Element) {
+        self.revisions.push(Revision {
+            src_file: self.src_file.to_string(),
+            part: self.part.to_string(),
+            kind: el.local.clone().unwrap_or_default(),
+            author: el.attrs.get("author").cloned(),
+            date: el.attrs.get("date").cloned(),
+            accepted: self.accept,
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{RevisionFilter, RevisionUtil};
+    use crate::docx_package::DocxPackage;
+    use crate::error::DocxResult;
+    use crate::reporter::CollectingReporter;
+    use crate::test_util::document;
+    use crate::xml_util::XMLUtil;
+
+    fn texts(pkg: &DocxPackage) -> DocxResult<Vec<String>> {
+        let mut rep = CollectingReporter::default();
+        XMLUtil::cat(pkg, "revisions.docx", &mut rep)?;
+        Ok(rep.paragraphs.into_iter().map(|p| p.text).collect())
+    }
+
+    #[test]
+    fn test_accept_changes() -> DocxResult<()> {
+        let mut pkg = DocxPackage::from_dir("./src/test/test_tree10")?;
+
+        let mut rep = CollectingReporter::default();
+        RevisionUtil::accept_changes(&mut pkg, "revisions.docx", &RevisionFilter::default(), &mut rep)?;
+        assert!(rep.revisions.iter().all(|r| r.accepted));
+        assert_eq!(8, rep.revisions.len(), "{:?}", rep.revisions);
+
+        assert_eq!(vec!["The quick brown fox jumps.", "Merged paragraph text", "Moved text", "Kept row"],
+            texts(&pkg)?);
+        let doc = document(&pkg);
+        assert!(!doc.contains("w:ins") && !doc.contains("w:del") && !doc.contains("w:move"), "{}", doc);
+        assert!(!doc.contains("Change"), "{}", doc);
+        assert!(doc.contains("<w:b/>"), "{}", doc);
+        assert!(doc.contains(r#"<w:pStyle w:val="Heading1"/>"#), "{}", doc);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_reject_changes() -> DocxResult<()> {
+        let mut pkg = DocxPackage::from_dir("./src/test/test_tree10")?;
+
+        let mut rep = CollectingReporter::default();
+        RevisionUtil::reject_changes(&mut pkg, "revisions.docx", &RevisionFilter::default(), &mut rep)?;
+        assert!(rep.revisions.iter().all(|r| !r.accepted));
+        assert_eq!(9, rep.revisions.len(), "{:?}", rep.revisions);
+
+        assert_eq!(vec!["The slow brown fox jumps.", "Merged ", "paragraph text", "Moved text",
+            "Deleted row", "Kept row"], texts(&pkg)?);
+        let doc = document(&pkg);
+        assert!(!doc.contains("w:ins") && !doc.contains("w:del") && !doc.contains("w:move"), "{}", doc);
+        assert!(!doc.contains("Change"), "{}", doc);
+        assert!(!doc.contains("<w:b/>"), "{}", doc);
+        assert!(doc.contains(r#"<w:pStyle w:val="Normal"/>"#), "{}", doc);
+        assert!(doc.contains(r#"<w:t xml:space="preserve">slow </w:t>"#), "{}", doc);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_format_date() {
+        assert_eq!("1970-01-01T00:00:00Z", RevisionUtil::format_date(0));
+        assert_eq!("2000-02-29T12:34:56Z", RevisionUtil::format_date(951827696));
+        assert_eq!("2024-12-31T23:59:59Z", RevisionUtil::format_date(1735689599));
+    }
+
+    #[test]
+    fn test_filter_changes() -> DocxResult<()> {
+        let mut pkg = DocxPackage::from_dir("./src/test/test_tree10")?;
+
+        let filter = RevisionFilter { author: Some("Alice".to_string()), ..Default::default() };
+        let mut rep = CollectingReporter::default();
+        RevisionUtil::accept_changes(&mut pkg, "revisions.docx", &filter, &mut rep)?;
+        assert!(!rep.revisions.is_empty());
+        assert!(rep.revisions.iter().all(|r| r.author.as_deref() == Some("Alice")));
+
+        let doc = document(&pkg);
+        assert!(!doc.contains(r#"w:author="Alice""#), "{}", doc);
+        assert!(doc.contains(r#"w:author="Bob""#), "{}", doc);
+
+        let filter = RevisionFilter { after: Some("2024-02-01".to_string()), ..Default::default() };
+        let mut rep = CollectingReporter::default();
+        RevisionUtil::reject_changes(&mut pkg, "revisions.docx", &filter, &mut rep)?;
+        assert!(!rep.revisions.is_empty());
+        assert!(rep.revisions.iter().all(|r| r.date.as_deref() >= Some("2024-02-01")));
+
+        let filter = RevisionFilter { before: Some("2000-01-01".to_string()), ..Default::default() };
+        let mut rep = CollectingReporter::default();
+        let before = document(&pkg);
+        RevisionUtil::accept_changes(&mut pkg, "revisions.docx", &filter, &mut rep)?;
+        assert!(rep.revisions.is_empty());
+        assert_eq!(before, document(&pkg));
+
+        Ok(())
+    }
+}
@@ -0,0 +1,254 @@
+use quick_xml::events::{BytesStart, Event};
+use quick_xml::reader::NsReader;
+use std::collections::HashMap;
+
+use crate::docx_package::DocxPackage;
+use crate::error::{DocxError, DocxResult};
+use crate::reporter::{DocxReporter, Table};
+use crate::xml_util::{StoryType, XMLUtil};
+
+/// A table that is being read.
+struct TableBuilder {
+    index: usize,
+    rows: Vec<Vec<String>>,
+
+    /// The text of the cells that start a vertical merge, by column
+    merged: HashMap<usize, String>,
+}
+
+/// A table cell that is being read.
+struct CellBuilder {
+    text: String,
+    paragraphs: usize,
+    span: usize,
+
+    /// `Some(true)` if the cell starts a vertical merge and `Some(false)` if it continues one
+    vmerge: Option<bool>,
+}
+
+pub struct TableUtil;
+
+impl TableUtil {
+    /// Report the tables in all the story parts of the document to `reporter`. The cells of the tables are
+    /// mapped onto the grid of the table, so a cell that spans multiple columns, or that is merged with the
+    /// cells above it, is followed by empty cells. If `fill_merged` is set these cells get the text of the
+    /// merged cell instead. The paragraphs in a cell are separated by line breaks.
+    pub fn tables(pkg: &DocxPackage, src_file: &str, fill_merged: bool, reporter: &mut dyn DocxReporter)
+            -> DocxResult<()> {
+        let mut next_index = 0;
+        for part in XMLUtil::get_story_parts(pkg, &StoryType::ALL)? {
+            let mut tables = Self::read_tables(pkg, &part, fill_merged, &mut next_index)?;
+
+            // Nested tables are complete before the table they are in, report them in the order they start
+            tables.sort_by_key(|t| t.0);
+            for (index, rows) in tables {
+                reporter.on_table(&Table { src_file: src_file.to_string(), part: part.clone(), index, rows });
+            }
+        }
+        Ok(())
+    }
+
+    /// Format `row` as a line of values separated by `delimiter`. Values are quoted as described in RFC 4180
+    /// when needed, except with a tab as delimiter. Tab-separated values can't contain tabs or line breaks,
+    /// so these are replaced by spaces instead.
+    pub fn format_row(row: &[String], delimiter: char) -> String {
+        let values: Vec<String> = row.iter().map(|v| {
+            if delimiter == '\t' {
+                v.replace(['\t', '\r', '\n'], " ")
+            } else if v.contains([delimiter, '"', '\r', '\n']) {
+                format!("\"{}\"", v.replace('"', "\"\""))
+            } else {
+                v.clone()
+            }
+        }).collect();
+        values.join(&delimiter.to_string())
+    }
+
+    fn read_tables(pkg: &DocxPackage, part: &str, fill_merged: bool, next_index: &mut usize)
+            -> DocxResult<Vec<(usize, Vec<Vec<String>>)>> {
+        let mut reader = XMLUtil::get_reader(pkg, part)?;
+        let mut buf = Vec::new();
+
+        let mut result = vec![];
+        let mut tables: Vec<TableBuilder> = vec![];
+        let mut cells: Vec<CellBuilder> = vec![];
+        let mut inside_run = false;
+        let mut inside_text = false;
+        loop {
+            match reader.read_event_into(&mut buf) {
+                Err(e) => return Err(DocxError::xml(part, reader.error_position(), e)),
+                Ok(Event::Eof) => break,
+                Ok(Event::Start(e)) => {
+                    if Self::is_w(&reader, &e, "tbl") {
+                        tables.push(TableBuilder { index: *next_index, rows: vec![], merged: HashMap::new() });
+                        *next_index += 1;
+                    } else if Self::is_w(&reader, &e, "tr") {
+                        if let Some(table) = tables.last_mut() {
+                            table.rows.push(vec![]);
+                        }
+                    } else if Self::is_w(&reader, &e, "tc") {
+                        cells.push(CellBuilder { text: String::new(), paragraphs: 0, span: 1, vmerge: None });
+                    } else if Self::is_w(&reader, &e, "p") {
+                        if let Some(cell) = cells.last_mut() {
+                            if cell.paragraphs > 0 {
+                                cell.text.push('\n');
+                            }
+                            cell.paragraphs += 1;
+                        }
+                    } else if Self::is_w(&reader, &e, "r") {
+                        inside_run = true;
+                    } else if Self::is_w(&reader, &e, "t") {
+                        inside_text = true;
+                    } else {
+                        Self::read_empty(&reader, &e, part, inside_run, &mut tables, &mut cells);
+                    }
+                },
+                Ok(Event::Empty(e)) => Self::read_empty(&reader, &e, part, inside_run, &mut tables, &mut cells),
+                Ok(Event::End(e)) => {
+                    if XMLUtil::is_w(&reader, e.name(), "tbl") {
+                        if let Some(mut table) = tables.pop() {
+                            let width = table.rows.iter().map(|r| r.len()).max().unwrap_or(0);
+                            for row in &mut table.rows {
+                                row.resize(width, String::new());
+                            }
+                            result.push((table.index, table.rows));
+                        }
+                    } else if XMLUtil::is_w(&reader, e.name(), "tc") {
+                        if let (Some(cell), Some(table)) = (cells.pop(), tables.last_mut()) {
+                            Self::add_cell(table, cell, fill_merged);
+                        }
+                    } else if XMLUtil::is_w(&reader, e.name(), "r") {
+                        inside_run = false;
+                    } else if XMLUtil::is_w(&reader, e.name(), "t") {
+                        inside_text = false;
+                    }
+                },
+                Ok(Event::Text(t)) if inside_text => {
+                    if let Some(cell) = cells.last_mut() {
+                        let val = t.unescape().map_err(|e| DocxError::xml(part, reader.buffer_position(), e))?;
+                        cell.text.push_str(&val);
+                    }
+                },
+                _ => ()
+            }
+        }
+        Ok(result)
+    }
+
+    /// Handle the elements that can be empty and that affect the table structure or the text of a cell.
+    fn read_empty(reader: &NsReader<&[u8]>, e: &BytesStart, part: &str, inside_run: bool,
+            tables: &mut [TableBuilder], cells: &mut [CellBuilder]) {
+        if Self::is_w(reader, e, "gridBefore") {
+            // Cells of the grid that are skipped at the start of the row
+            let skipped = XMLUtil::get_attribute(e, reader, "val", part).ok()
+                .and_then(|v| v.parse().ok()).unwrap_or(0);
+            if let Some(row) = tables.last_mut().and_then(|t| t.rows.last_mut()) {
+                row.resize(row.len() + skipped, String::new());
+            }
+        } else if let Some(cell) = cells.last_mut() {
+            if Self::is_w(reader, e, "gridSpan") {
+                cell.span = XMLUtil::get_attribute(e, reader, "val", part).ok()
+                    .and_then(|v| v.parse().ok()).unwrap_or(1).max(1);
+            } else if Self::is_w(reader, e, "vMerge") {
+                let val = XMLUtil::get_attribute(e, reader, "val", part).ok();
+                cell.vmerge = Some(val.as_deref() == Some("restart"));
+            } else if inside_run && Self::is_w(reader, e, "tab") {
+                cell.text.push('\t');
+            } else if inside_run && (Self::is_w(reader, e, "br") || Self::is_w(reader, e, "cr")) {
+                cell.text.push('\n');
+            }
+        }
+    }
+
+    /// Add `cell` to the last row of `table`.
+    fn add_cell(table: &mut TableBuilder, mut cell: CellBuilder, fill_merged: bool) {
+        let Some(row) = table.rows.last_mut() else {
+            return;
+        };
+        let col = row.len();
+
+        // A cell ends with an empty paragraph after a nested table
+        cell.text.truncate(cell.text.trim_end_matches('\n').len());
+
+        let text = match cell.vmerge {
+            Some(true) => {
+                table.merged.insert(col, cell.text.clone());
+                cell.text
+            },
+            Some(false) if fill_merged => table.merged.get(&col).cloned().unwrap_or_default(),
+            Some(false) => String::new(),
+            None => {
+                table.merged.remove(&col);
+                cell.text
+            }
+        };
+
+        let spanned = if fill_merged { text.clone() } else { String::new() };
+        row.push(text);
+        row.resize(col + cell.span, spanned);
+    }
+
+    fn is_w(reader: &NsReader<&[u8]>, e: &BytesStart, local: &str) -> bool {
+        XMLUtil::is_w(reader, e.name(), local)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::TableUtil;
+    use crate::docx_package::DocxPackage;
+    use crate::error::DocxResult;
+    use crate::reporter::CollectingReporter;
+
+    fn rows(rows: &[&[&str]]) -> Vec<Vec<String>> {
+        rows.iter().map(|r| r.iter().map(|c| c.to_string()).collect()).collect()
+    }
+
+    #[test]
+    fn test_tables() -> DocxResult<()> {
+        let pkg = DocxPackage::from_dir("./src/test/test_tree11")?;
+
+        let mut rep = CollectingReporter::default();
+        TableUtil::tables(&pkg, "tables.docx", false, &mut rep)?;
+        assert_eq!(3, rep.tables.len());
+        assert_eq!(vec![0, 1, 2], rep.tables.iter().map(|t| t.index).collect::<Vec<_>>());
+
+        assert_eq!(rows(&[
+            &["Product", "Price", ""],
+            &["Widget", "10", "Small"],
+            &["", "12", "Large"],
+            &["", "Gadget", "5"],
+            &["Total", "", ""],
+        ]), rep.tables[0].rows);
+        assert_eq!(rows(&[&["Nested", "Table"]]), rep.tables[1].rows);
+        assert_eq!(rows(&[&["Name", "Value"], &["Timeout", "30\nseconds"]]), rep.tables[2].rows);
+        assert_eq!("word/header1.xml", rep.tables[2].part);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_tables_fill_merged() -> DocxResult<()> {
+        let pkg = DocxPackage::from_dir("./src/test/test_tree11")?;
+
+        let mut rep = CollectingReporter::default();
+        TableUtil::tables(&pkg, "tables.docx", true, &mut rep)?;
+        assert_eq!(rows(&[
+            &["Product", "Price", "Price"],
+            &["Widget", "10", "Small"],
+            &["Widget", "12", "Large"],
+            &["", "Gadget", "5"],
+            &["Total", "Total", "Total"],
+        ]), rep.tables[0].rows);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_format_row() {
+        let row = vec!["plain".to_string(), "a,b".to_string(), "say \"hi\"".to_string(), "two\nlines".to_string(),
+            "tab\there".to_string()];
+        assert_eq!("plain,\"a,b\",\"say \"\"hi\"\"\",\"two\nlines\",tab\there", TableUtil::format_row(&row, ','));
+        assert_eq!("plain\ta,b\tsay \"hi\"\ttwo lines\ttab here", TableUtil::format_row(&row, '\t'));
+    }
+}
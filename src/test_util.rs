@@ -0,0 +1,17 @@
+use crate::docx_package::DocxPackage;
+use crate::merge_util::Record;
+
+/// The content of `part` in `pkg` as a string.
+pub(crate) fn part_str(pkg: &DocxPackage, part: &str) -> String {
+    String::from_utf8(pkg.get_part(part).unwrap().to_vec()).unwrap()
+}
+
+/// The content of the main document part of `pkg` as a string.
+pub(crate) fn document(pkg: &DocxPackage) -> String {
+    part_str(pkg, "word/document.xml")
+}
+
+/// A record, or the values for content controls, with the field names and values in `fields`.
+pub(crate) fn record(fields: &[(&str, &str)]) -> Record {
+    fields.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect()
+}
@@ -0,0 +1,205 @@
+use quick_xml::events::{BytesStart, BytesText, Event};
+use quick_xml::name::{Namespace, ResolveResult};
+use quick_xml::reader::NsReader;
+use quick_xml::writer::Writer;
+use std::collections::HashMap;
+
+use crate::error::{DocxError, DocxResult};
+use crate::xml_util::WORDDOC_NS;
+
+/// The elements holding the properties of a paragraph, run or content control, which don't contain any text.
+pub(crate) const PROPERTIES: [&str; 3] = ["pPr", "rPr", "sdtPr"];
+
+/// An element of an XML part. `local` and `attrs` hold the local names of the element and its attributes
+/// if they are in the WordprocessingML namespace.
+#[derive(Clone)]
+pub(crate) struct Element {
+    pub(crate) start: BytesStart<'static>,
+    pub(crate) local: Option<String>,
+    pub(crate) attrs: HashMap<String, String>,
+    pub(crate) children: Vec<Node>,
+    pub(crate) empty: bool,
+}
+
+impl Element {
+    /// Create the element `local` in the WordprocessingML namespace, using `prefix` for that namespace. An empty
+    /// prefix is used where the namespace is the default namespace.
+    pub(crate) fn new(prefix: &str, local: &str, children: Vec<Node>) -> Element {
+        let start = match prefix {
+            "" => BytesStart::new(local.to_string()),
+            _ => BytesStart::new(format!("{}:{}", prefix, local)),
+        };
+        Element { start, local: Some(local.to_string()), attrs: HashMap::new(), empty: children.is_empty(), children }
+    }
+
+    /// The prefix of the name of the element, empty if it has none.
+    pub(crate) fn prefix(&self) -> String {
+        self.start.name().prefix().map(|p| String::from_utf8_lossy(p.as_ref()).to_string()).unwrap_or_default()
+    }
+
+    /// Set the WordprocessingML attribute `local` to `value`, or remove it if `value` is `None`. The attribute
+    /// is written with the same prefix as the element. Attributes don't take the default namespace, so for an
+    /// element without prefix the attribute is written with the `w` prefix, which is declared on the element.
+    pub(crate) fn set_attribute(&mut self, local: &str, value: Option<&str>) {
+        let name = String::from_utf8_lossy(self.start.name().as_ref()).to_string();
+        let attributes: Vec<_> = self.start.attributes().flatten()
+            .filter(|a| a.key.local_name().as_ref() != local.as_bytes() || a.key.prefix().is_none())
+            .collect();
+        let mut start = BytesStart::new(name).with_attributes(attributes).into_owned();
+
+        match value {
+            Some(v) => {
+                let mut prefix = self.prefix();
+                if prefix.is_empty() {
+                    prefix = "w".to_string();
+                    if !start.attributes().flatten().any(|a| a.key.as_ref() == b"xmlns:w") {
+                        start.push_attribute(("xmlns:w", WORDDOC_NS));
+                    }
+                }
+                start.push_attribute((format!("{}:{}", prefix, local).as_str(), v));
+                self.attrs.insert(local.to_string(), v.to_string());
+            },
+            None => {
+                self.attrs.remove(local);
+            }
+        }
+        self.start = start;
+    }
+
+    /// The text content of the element, such as the text of a `w:t` element.
+    pub(crate) fn text(&self) -> String {
+        let mut text = String::new();
+        for child in &self.children {
+            if let Node::Other(Event::Text(t)) = child {
+                let s = t.unescape().map(|s| s.to_string()).unwrap_or_else(|_| String::from_utf8_lossy(t).to_string());
+                text.push_str(&s);
+            }
+        }
+        text
+    }
+
+    /// Replace the content of the element with `text`, preserving its whitespace.
+    pub(crate) fn set_text(&mut self, text: &str) {
+        let preserved = self.start.attributes().flatten().any(|a| a.key.as_ref() == b"xml:space");
+        if !preserved {
+            self.start.push_attribute(("xml:space", "preserve"));
+        }
+        self.children = vec![Node::Other(Event::Text(BytesText::new(text).into_owned()))];
+        self.empty = false;
+    }
+
+    pub(crate) fn is(&self, local: &str) -> bool {
+        self.local.as_deref() == Some(local)
+    }
+
+    pub(crate) fn is_any(&self, locals: &[&str]) -> bool {
+        locals.iter().any(|l| self.is(l))
+    }
+
+    pub(crate) fn child(&self, local: &str) -> Option<&Element> {
+        self.children.iter().find_map(|n| match n {
+            Node::Element(el) if el.is(local) => Some(el),
+            _ => None,
+        })
+    }
+
+    pub(crate) fn child_mut(&mut self, local: &str) -> Option<&mut Element> {
+        self.children.iter_mut().find_map(|n| match n {
+            Node::Element(el) if el.is(local) => Some(el),
+            _ => None,
+        })
+    }
+
+    /// The first element `local` inside this element, searching depth first.
+    pub(crate) fn descendant(&self, local: &str) -> Option<&Element> {
+        self.children.iter().find_map(|n| match n {
+            Node::Element(el) if el.is(local) => Some(el),
+            Node::Element(el) => el.descendant(local),
+            _ => None,
+        })
+    }
+}
+
+#[derive(Clone)]
+pub(crate) enum Node {
+    Element(Element),
+    Other(Event<'static>),
+}
+
+/// Reads XML parts into a tree of nodes that can be modified and written back, for the operations that
+/// restructure the content rather than only change the text.
+pub(crate) struct XmlTree;
+
+impl XmlTree {
+    /// Read the XML in `xml`, which is the content of `part`, into a tree of nodes.
+    pub(crate) fn parse(xml: &[u8], part: &str) -> DocxResult<Vec<Node>> {
+        let mut reader = NsReader::from_reader(xml);
+        let mut buf = Vec::new();
+
+        let mut stack: Vec<Element> = vec![];
+        let mut nodes = vec![];
+        loop {
+            let node = match reader.read_event_into(&mut buf) {
+                Ok(Event::Start(e)) => {
+                    stack.push(Self::element(&reader, e.into_owned(), false));
+                    continue;
+                },
+                Ok(Event::Empty(e)) => Node::Element(Self::element(&reader, e.into_owned(), true)),
+                Ok(Event::End(_)) => match stack.pop() {
+                    Some(el) => Node::Element(el),
+                    None => continue
+                },
+                Ok(Event::Eof) => break,
+                Ok(e) => Node::Other(e.into_owned()),
+                Err(e) => return Err(DocxError::xml(part, reader.error_position(), e)),
+            };
+
+            match stack.last_mut() {
+                Some(parent) => parent.children.push(node),
+                None => nodes.push(node),
+            }
+        }
+        Ok(nodes)
+    }
+
+    /// Write `nodes` back to XML.
+    pub(crate) fn write(nodes: Vec<Node>) -> DocxResult<Vec<u8>> {
+        let mut writer = Writer::new(Vec::new());
+        Self::write_nodes(&mut writer, nodes)?;
+        Ok(writer.into_inner())
+    }
+
+    fn element(reader: &NsReader<&[u8]>, start: BytesStart<'static>, empty: bool) -> Element {
+        let w_ns = ResolveResult::Bound(Namespace(WORDDOC_NS.as_bytes()));
+
+        let (res, ln) = reader.resolve_element(start.name());
+        let local = (res == w_ns).then(|| String::from_utf8_lossy(ln.as_ref()).to_string());
+
+        let mut attrs = HashMap::new();
+        for a in start.attributes().flatten() {
+            let (res, ln) = reader.resolve_attribute(a.key);
+            if res == w_ns {
+                let value = a.unescape_value().map(|v| v.to_string())
+                    .unwrap_or_else(|_| String::from_utf8_lossy(&a.value).to_string());
+                attrs.insert(String::from_utf8_lossy(ln.as_ref()).to_string(), value);
+            }
+        }
+
+        Element { start, local, attrs, children: vec![], empty }
+    }
+
+    fn write_nodes(writer: &mut Writer<Vec<u8>>, nodes: Vec<Node>) -> DocxResult<()> {
+        for node in nodes {
+            match node {
+                Node::Element(el) if el.empty => writer.write_event(Event::Empty(el.start))?,
+                Node::Element(el) => {
+                    writer.write_event(Event::Start(el.start.clone()))?;
+                    Self::write_nodes(writer, el.children)?;
+                    writer.write_event(Event::End(el.start.to_end()))?;
+                },
+                Node::Other(e) => writer.write_event(e)?,
+            }
+        }
+        Ok(())
+    }
+}
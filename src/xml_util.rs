@@ -1,29 +1,33 @@
-use quick_xml::events::{Event, BytesStart, BytesText};
-use quick_xml::events::attributes::{Attr, Attribute, Attributes};
-use quick_xml::name::QName;
-use quick_xml::reader::Reader;
+use quick_xml::events::{Event, BytesEnd, BytesStart, BytesText};
+use quick_xml::events::attributes::{Attr, Attribute};
+use quick_xml::escape::escape;
+use quick_xml::name::{Namespace, QName, ResolveResult};
+use quick_xml::reader::NsReader;
 use quick_xml::writer::Writer;
-use regex::Regex;
+use regex::{Captures, Regex};
+use std::borrow::Cow;
 use std::collections::{BTreeMap, HashMap};
-use std::fs::{File, self};
-use std::io::{BufReader, BufWriter};
-use std::path::{Path, PathBuf, MAIN_SEPARATOR, MAIN_SEPARATOR_STR};
+use std::ops::Range;
 use std::str;
-use uuid::Uuid;
 use unicase::UniCase;
-use walkdir::WalkDir;
 
-use crate::file_util::FileUtil;
-use crate::zip_util::ZipUtil;
+use crate::docx_package::DocxPackage;
+use crate::error::{DocxError, DocxResult};
+use crate::reporter::{CollectingReporter, DocxReporter, Link, Paragraph, Replacement};
+use crate::revision_util::TrackChanges;
 
 #[cfg(windows)]
-const LINE_ENDING: &'static str = "\r\n";
+const LINE_ENDING: &str = "\r\n";
 #[cfg(not(windows))]
-const LINE_ENDING: &'static str = "\n";
+const LINE_ENDING: &str = "\n";
 
-const WORDDOC_MT: &'static str = "application/vnd.openxmlformats-officedocument.wordprocessingml.document.main+xml";
-const RELATION_MT: &'static str = "application/vnd.openxmlformats-package.relationships+xml";
-const WORDDOC_NS: &'static str = "http://schemas.openxmlformats.org/wordprocessingml/2006/main";
+const WORDDOC_MT: &str = "application/vnd.openxmlformats-officedocument.wordprocessingml.document.main+xml";
+const RELATION_MT: &str = "application/vnd.openxmlformats-package.relationships+xml";
+pub(crate) const WORDDOC_NS: &str = "http://schemas.openxmlformats.org/wordprocessingml/2006/main";
+const RELATIONSHIPS_NS: &str = "http://schemas.openxmlformats.org/package/2006/relationships";
+/// The relationship type of hyperlinks.
+pub const HYPERLINK_REL_TYPE: &str = "http://schemas.openxmlformats.org/officeDocument/2006/relationships/hyperlink";
+const CONTENT_TYPES_NS: &str = "http://schemas.openxmlformats.org/package/2006/content-types";
 
 #[derive(Clone, Debug)]
 enum Mode {
@@ -40,11 +44,14 @@ enum Mode {
     },
     Replace {
         regex: Regex,
-        replacement: String
+        replacement: ReplaceWith,
+        track: Option<TrackChanges>
     },
+    // Replace in the targets of the relationships of a certain type
     ReplaceAttribute {
         regex: Regex,
-        replacement: String
+        replacement: String,
+        rel_type: String
     },
     StyleChange {
         style: String,
@@ -52,258 +59,542 @@ enum Mode {
     }
 }
 
+/// What the matches of a search and replace are replaced with.
+#[derive(Clone, Debug)]
+enum ReplaceWith {
+    /// The same text for every match, inserted as is
+    Text(String),
+    /// The value for the text of the first capture group of the match, matches without a value are kept
+    Values(HashMap<String, String>),
+}
+
+impl ReplaceWith {
+    /// The replacement for the match with the capture groups `caps`.
+    fn for_match<'a>(&'a self, caps: &Captures) -> Cow<'a, str> {
+        match self {
+            ReplaceWith::Text(text) => Cow::Borrowed(text),
+            ReplaceWith::Values(values) => match caps.get(1).and_then(|name| values.get(name.as_str())) {
+                Some(value) => Cow::Borrowed(value),
+                None => Cow::Owned(caps[0].to_string()),
+            },
+        }
+    }
+}
+
+/// A relationship of a part to another part or to an external resource.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct Relationship {
+    pub(crate) rel_type: Option<String>,
+    pub(crate) target: String,
+    pub(crate) target_mode: Option<String>,
+}
+
+impl Relationship {
+    /// If the target of the relationship is outside of the package.
+    pub(crate) fn is_external(&self) -> bool {
+        self.target_mode.as_deref() == Some("External")
+    }
+}
+
+/// The types of the story parts of a document, which are the parts that contain text.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StoryType {
+    /// The main document, e.g. `word/document.xml`
+    Main,
+    Header,
+    Footer,
+    Footnotes,
+    Endnotes,
+    Comments,
+}
+
+impl StoryType {
+    pub const ALL: [StoryType; 6] = [StoryType::Main, StoryType::Header, StoryType::Footer,
+        StoryType::Footnotes, StoryType::Endnotes, StoryType::Comments];
+
+    /// The name of the story type, which is also the last segment of the type of the relationship
+    /// from the main document to parts of this type.
+    pub fn name(&self) -> &'static str {
+        match self {
+            StoryType::Main => "main",
+            StoryType::Header => "header",
+            StoryType::Footer => "footer",
+            StoryType::Footnotes => "footnotes",
+            StoryType::Endnotes => "endnotes",
+            StoryType::Comments => "comments",
+        }
+    }
+
+    /// Obtain the story type with `name`, as returned by `StoryType::name()`.
+    pub fn from_name(name: &str) -> Option<StoryType> {
+        Self::ALL.into_iter().find(|st| st.name() == name)
+    }
+}
+
+/// A match of a regular expression in the text of the document, as returned by `XMLUtil::grep_matches`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct GrepMatch {
+    /// The part the match was found in, e.g. `word/document.xml`.
+    pub part: String,
+
+    /// The index of the paragraph among the paragraphs with text in the part.
+    pub paragraph: usize,
+
+    /// The id of the paragraph style, if the paragraph has one.
+    pub style: Option<String>,
+
+    /// The text of the whole paragraph.
+    pub text: String,
+
+    /// The location of the match in `text` as byte offsets.
+    pub byte_range: Range<usize>,
+
+    /// The location of the match in `text` as character offsets.
+    pub char_range: Range<usize>,
+
+    /// The capture groups of the match. The first element is the whole match, groups that did
+    /// not participate in the match are `None`.
+    pub groups: Vec<Option<String>>,
+}
+
+/// The text of a paragraph together with the id of its paragraph style.
+struct ParaText {
+    text: String,
+    style: Option<String>,
+}
+
+/// A piece of the text of a <w:t> tag when replacing with tracked changes.
+enum Segment {
+    Keep(String),
+    Delete(String),
+    Insert(String),
+}
+
+/// A <w:r> run that is being written while replacing with tracked changes. The run is split when
+/// a deletion or insertion is made in it, after which it is opened again with the same properties.
+struct TrackedRun {
+    start: BytesStart<'static>,
+
+    /// The events of the <w:rPr> run properties
+    props: Vec<Event<'static>>,
+    props_depth: usize,
+
+    open: bool,
+
+    /// The position in the output where the run was last opened, and if anything but its properties
+    /// was written since
+    offset: usize,
+    has_content: bool,
+}
+
+impl TrackedRun {
+    /// The name of WordprocessingML element `local` with the same prefix as the run.
+    fn w_name(&self, local: &str) -> String {
+        match self.start.name().prefix() {
+            Some(p) => format!("{}:{}", String::from_utf8_lossy(p.as_ref()), local),
+            None => local.to_string(),
+        }
+    }
+}
+
 pub struct XMLUtil {
 }
 
-/// A collection of functions for working with .docx XML files. The functions generally expect that the .docx file
-/// is already unzipped and passed in as the root directory of the location where is was unzipped.
+/// A collection of functions for working with .docx XML files. The functions operate on a `DocxPackage`
+/// which holds the parts of the .docx file in memory. Functions that modify the document update the parts
+/// in the package, which can then be saved.
 impl XMLUtil {
-    /// Send the text content of the docx structure to stdout. `dir` is the directory containing
-    /// the unzipped docx file and `src_file` is the original name of the docx file.
-    pub fn cat(dir: &str, src_file: &str) {
-        Self::snr_xml(Mode::Cat, dir, src_file, None, None);
+    /// Report the text content of the docx structure to `reporter`. `pkg` is the package containing
+    /// the parts of the docx file and `src_file` is the original name of the docx file.
+    pub fn cat(pkg: &DocxPackage, src_file: &str, reporter: &mut dyn DocxReporter) -> DocxResult<()> {
+        Self::scan_xml(Mode::Cat, pkg, src_file, None, reporter)
     }
 
+    /// Report the value of attribute `attr_name` on the `el_name` elements in the relationship parts
+    /// of the document as links to `reporter`. Only elements that have the attribute `cond_key` with
+    /// the value `cond_val` are reported.
     pub fn cat_rel_attr(el_name: &str, attr_name: &str, cond_key: &str, cond_val: &str,
-            dir: &str, src_file: &str) {
-        let fref = Self::get_rel_files(dir);
+            pkg: &DocxPackage, src_file: &str, reporter: &mut dyn DocxReporter) -> DocxResult<()> {
+        let fref = Self::get_rel_files(pkg)?;
 
         let mode = Mode::CatAttrCondition {
                 tagname: el_name.into(), attrname: attr_name.into(),
                 condkey: cond_key.into(), condval: cond_val.into()
             };
-        Self::snr_xml(mode, dir, src_file, Some(fref),None);
+        Self::scan_xml(mode, pkg, src_file, Some(fref), reporter)
+    }
+
+    /// Change paragraphs with style `style` into `replacement` in all the story parts of the document.
+    /// The styles are specified by their display name, e.g. 'Heading 1'.
+    pub fn change_style(pkg: &mut DocxPackage, src_file: &str, style: &str, replacement: &str,
+            reporter: &mut dyn DocxReporter) -> DocxResult<()> {
+        Self::change_style_parts(pkg, src_file, style, replacement, &StoryType::ALL, reporter)
     }
 
-    pub fn change_style(dir: &str, src_file: &str, style: &str, replacement: &str, output_file: &Option<&str>) {
-        Self::invoke_with_files_and_output(dir, src_file, output_file, |files, out_file| {
-            let styles = Self::get_all_styles(dir, src_file);
+    /// Change paragraphs with style `style` into `replacement`, like `change_style`, but only in the story
+    /// parts of the types in `stories`.
+    pub fn change_style_parts(pkg: &mut DocxPackage, src_file: &str, style: &str, replacement: &str,
+            stories: &[StoryType], reporter: &mut dyn DocxReporter) -> DocxResult<()> {
+        let styles = Self::get_all_styles(pkg)?;
 
-            let src = styles.get(&UniCase::new(style.to_string()));
-            let dest = styles.get(&UniCase::new(replacement.to_string()));
+        let src = styles.get(&UniCase::new(style.to_string()));
+        let dest = styles.get(&UniCase::new(replacement.to_string()));
 
-            if let (Some(src_id), Some(dest_id)) = (src, dest) {
+        match (src, dest) {
+            (Some(src_id), Some(dest_id)) => {
                 let mode = Mode::StyleChange {
                     style: src_id.clone(), replacement: dest_id.clone()
                 };
-                Self::snr_xml(mode, dir, src_file, files, out_file);
-            } else {
-                let mut style_names = Vec::from_iter(styles.keys());
+                let files = Self::get_story_parts(pkg, stories)?;
+                Self::snr_xml(mode, pkg, src_file, Some(files), reporter)
+            },
+            _ => {
+                let missing = if src.is_none() { style } else { replacement };
+                let mut style_names: Vec<String> = styles.keys().map(|k| k.to_string()).collect();
                 style_names.sort();
 
-                panic!("Not all styles were found. Known styles (case insensitive): {:?}", style_names);
+                Err(DocxError::MissingStyle { style: missing.to_string(), known_styles: style_names })
             }
-        });
+        }
     }
 
-    /// Search for regex `pattern` in the text of the docx structure and send matches to stdout.
-    /// `dir` is the directory containing
-    /// the unzipped docx file and `src_file` is the original name of the docx file.
-    pub fn grep_xml(dir: &str, src_file: &str, pattern: &str) {
-        let mg = Mode::Grep { regex: Regex::new(pattern).expect(pattern) };
-        Self::snr_xml(mg, dir, src_file, None, None);
+    /// Search for regex `pattern` in the text of the docx structure and report matching paragraphs to `reporter`.
+    /// `pkg` is the package containing the parts of the docx file and `src_file` is the original
+    /// name of the docx file.
+    pub fn grep_xml(pkg: &DocxPackage, src_file: &str, pattern: &str, reporter: &mut dyn DocxReporter) -> DocxResult<()> {
+        let mg = Mode::Grep { regex: Regex::new(pattern)? };
+        Self::scan_xml(mg, pkg, src_file, None, reporter)
     }
 
-    /// Search for regex `pattern` in the text of the docx structure and replace all occurrences with `replacement`.
-    /// `dir` is the directory containing
-    /// the unzipped docx file and `src_file` is the original name of the docx file.
-    ///
-    /// `output_file` can be a .docx filename. If specified the result will be zipped and written to produce this
-    /// new .docx file. Otherwise the result is zipped and written to `src_file`.
-    pub fn replace_xml(dir: &str, src_file: &str, pattern: &str, replacement: &str, output_file: &Option<&str>) {
-        Self::invoke_with_files_and_output(dir, src_file, output_file, |files, out_file| {
-            let mr = Mode::Replace {
-                regex: Regex::new(pattern).unwrap(),
-                replacement: replacement.to_owned()
-            };
-            Self::snr_xml(mr, dir, src_file, files, out_file)
-        });
+    /// Search for regex `pattern` in the text of the docx structure and return every match with its location.
+    /// Where a paragraph contains multiple matches, each is returned separately.
+    pub fn grep_matches(pkg: &DocxPackage, pattern: &str) -> DocxResult<Vec<GrepMatch>> {
+        let regex = Regex::new(pattern)?;
+
+        let mut rep = CollectingReporter::default();
+        Self::scan_xml(Mode::Grep { regex: regex.clone() }, pkg, "", None, &mut rep)?;
+
+        let mut matches = vec![];
+        for para in rep.matches {
+            for caps in regex.captures_iter(&para.text) {
+                let m = caps.get(0).expect("Group 0 is always present");
+                let char_start = para.text[..m.start()].chars().count();
+                let char_end = char_start + m.as_str().chars().count();
+
+                matches.push(GrepMatch {
+                    part: para.part.clone(),
+                    paragraph: para.index,
+                    style: para.style.clone(),
+                    text: para.text.clone(),
+                    byte_range: m.range(),
+                    char_range: char_start..char_end,
+                    groups: caps.iter().map(|g| g.map(|g| g.as_str().to_string())).collect(),
+                });
+            }
+        }
+
+        Ok(matches)
     }
 
-    pub fn replace_rel_attr(dir: &str, src_file: &str, pattern: &str, replace: &str, output_file: &Option<&str>) {
-        let fref = Self::get_rel_files(dir);
+    /// Search for regex `pattern` in the text of the docx structure and replace all occurrences with `replacement`.
+    /// `pkg` is the package containing the parts of the docx file and `src_file` is the original
+    /// name of the docx file. The replacements are made in all the story parts of the document, such as the
+    /// main document, headers and footers. The modified parts are updated in `pkg` and each replacement is
+    /// reported to `reporter`. `replacement` is inserted as is, a `$` in it doesn't refer to a capture group.
+    pub fn replace_xml(pkg: &mut DocxPackage, src_file: &str, pattern: &str, replacement: &str,
+            reporter: &mut dyn DocxReporter) -> DocxResult<()> {
+        Self::replace_xml_parts(pkg, src_file, pattern, replacement, &StoryType::ALL, reporter)
+    }
 
-        let out_file = match output_file {
-            Some(of) => of,
-            None => src_file
+    /// Search and replace like `replace_xml`, but only in the story parts of the types in `stories`.
+    pub fn replace_xml_parts(pkg: &mut DocxPackage, src_file: &str, pattern: &str, replacement: &str,
+            stories: &[StoryType], reporter: &mut dyn DocxReporter) -> DocxResult<()> {
+        let mr = Mode::Replace {
+            regex: Regex::new(pattern)?,
+            replacement: ReplaceWith::Text(replacement.to_owned()),
+            track: None
         };
+        let files = Self::get_story_parts(pkg, stories)?;
+        Self::snr_xml(mr, pkg, src_file, Some(files), reporter)
+    }
 
-        let ma = Mode::ReplaceAttribute {
-            regex: Regex::new(pattern).unwrap(),
-            replacement: replace.to_owned()
+    /// Search and replace like `replace_xml_parts`, but record the replacements as tracked changes made by
+    /// `track.author`, so that they can be reviewed in the word processor. The replaced text is marked as
+    /// deleted and the replacement as inserted, keeping the formatting of the runs they are in.
+    pub fn replace_xml_tracked(pkg: &mut DocxPackage, src_file: &str, pattern: &str, replacement: &str,
+            stories: &[StoryType], track: &TrackChanges, reporter: &mut dyn DocxReporter) -> DocxResult<()> {
+        let mr = Mode::Replace {
+            regex: Regex::new(pattern)?,
+            replacement: ReplaceWith::Text(replacement.to_owned()),
+            track: Some(track.clone())
         };
-        Self::snr_xml(ma, dir, src_file, Some(fref), Some(out_file));
+        let files = Self::get_story_parts(pkg, stories)?;
+        Self::snr_xml(mr, pkg, src_file, Some(files), reporter)
     }
 
-    fn invoke_with_files_and_output<'a, F>(dir: &str, src_file: &'a str, output_file: &Option<&'a str>, op_fn: F)
-        where F: Fn(Option<Vec<String>>, Option<&'a str>) {
-
-        let out_file = match output_file {
-            Some(of) => of,
-            None => src_file
-        };
+    /// Search and replace like `replace_xml`, but replace each match of `regex` with the value in `values` for
+    /// the text of its first capture group, so that several values are filled in with a single pass over the
+    /// story parts. Matches without a value are left as they are.
+    pub(crate) fn replace_xml_values(pkg: &mut DocxPackage, src_file: &str, regex: Regex,
+            values: HashMap<String, String>, reporter: &mut dyn DocxReporter) -> DocxResult<()> {
+        let mr = Mode::Replace { regex, replacement: ReplaceWith::Values(values), track: None };
+        let files = Self::get_story_parts(pkg, &StoryType::ALL)?;
+        Self::snr_xml(mr, pkg, src_file, Some(files), reporter)
+    }
 
-        let (_, files) = Self::get_files_with_content_type(dir, WORDDOC_MT);
+    /// Replace `pattern` with `replace` in the targets of the relationships of type `rel_type` in the
+    /// relationship parts of the document. The type is either the full URI of the relationship type or its last
+    /// part, such as `hyperlink`, `image` or `attachedTemplate`. Other attributes and relationships are left as they are.
+    pub fn replace_rel_attr(pkg: &mut DocxPackage, src_file: &str, pattern: &str, replace: &str, rel_type: &str,
+            reporter: &mut dyn DocxReporter) -> DocxResult<()> {
+        let fref = Self::get_rel_files(pkg)?;
 
-        op_fn(Some(files), Some(out_file));
+        let ma = Mode::ReplaceAttribute {
+            regex: Regex::new(pattern)?,
+            replacement: replace.to_owned(),
+            rel_type: rel_type.to_owned()
+        };
+        Self::snr_xml(ma, pkg, src_file, Some(fref), reporter)
     }
 
-    fn get_rel_files(dir: &str) -> Vec<String> {
-        let (defaults, files) = Self::get_files_with_content_type(dir, WORDDOC_MT);
-        let rels_extension = &defaults[RELATION_MT];
+    fn get_rel_files(pkg: &DocxPackage) -> DocxResult<Vec<String>> {
+        let (defaults, files) = Self::get_files_with_content_type(pkg, WORDDOC_MT)?;
+        let rels_extension = defaults.get(RELATION_MT)
+            .ok_or_else(|| DocxError::MissingContentType(RELATION_MT.to_string()))?;
 
-        let mut rels_files = vec!();
-        for f in files {
-            let last_slash = f.rfind(MAIN_SEPARATOR).expect(&f);
-            let mut new_fn = String::new();
-            new_fn.push_str(&f[..last_slash]);
-            new_fn.push_str("/_");
-            new_fn.push_str(rels_extension);
-            new_fn.push_str(&f[last_slash..]);
-            new_fn.push('.');
-            new_fn.push_str(rels_extension);
-            rels_files.push(FileUtil::normalize_path(&new_fn));
-        }
+        Ok(files.iter().map(|f| Self::rels_part_name(f, rels_extension)).collect())
+    }
 
-        rels_files
+    /// The name of the part that holds the relationships of `part`, e.g. `word/_rels/document.xml.rels`
+    /// for `word/document.xml`.
+    fn rels_part_name(part: &str, rels_extension: &str) -> String {
+        let last_slash = part.rfind('/').unwrap_or(0);
+        let mut new_fn = String::new();
+        new_fn.push_str(&part[..last_slash]);
+        new_fn.push_str("/_");
+        new_fn.push_str(rels_extension);
+        new_fn.push_str(&part[last_slash..]);
+        new_fn.push('.');
+        new_fn.push_str(rels_extension);
+        new_fn
     }
 
-    /// Iterate recursively over all files in `dir` and perform the operation specified in `mode` on each file. The original name
-    /// of the .docx file is provided in `src_file`.
-    ///
-    /// Optionally specify `files` as the list of files to match. If not specified, all files ending with `.xml` are matched.
-    /// `pattern` and `replacement` are used to search/replace operations.
-    /// `output_file` optionally specifies a different output file for replacement operations.
-    fn snr_xml(mode: Mode, dir: &str, src_file: &str, files: Option<Vec<String>>, output_file: Option<&str>) {
-        let mut base_dir = FileUtil::normalize_path(dir);
-        if !base_dir.ends_with(MAIN_SEPARATOR_STR) {
-            base_dir.push(MAIN_SEPARATOR);
-        }
+    /// Obtain the story parts of the types in `stories`. The main document parts are found by their content type,
+    /// the other story parts through the relationships of the main document parts.
+    pub(crate) fn get_story_parts(pkg: &DocxPackage, stories: &[StoryType]) -> DocxResult<Vec<String>> {
+        let (defaults, main_parts) = Self::get_files_with_content_type(pkg, WORDDOC_MT)?;
+        let rels_extension = defaults.get(RELATION_MT).map(|e| e.as_str()).unwrap_or("rels");
 
-        for entry in WalkDir::new(dir).into_iter()
-                .filter_map(|e| e.ok())
-                .filter(|e| e.file_type().is_file()) {
-            let sub_path = FileUtil::get_sub_path(entry.path(), &base_dir);
+        let mut parts = vec![];
+        for main_part in main_parts {
+            if stories.contains(&StoryType::Main) {
+                parts.push(main_part.clone());
+            }
 
-            if let Some(file_list) = &files {
-                if !file_list.contains(&sub_path) {
-                    continue;
-                }
-            } else {
-                if !(sub_path.ends_with(".xml")) {
-                    continue;
-                }
+            let rels_part = Self::rels_part_name(&main_part, rels_extension);
+            if pkg.get_part(&rels_part).is_none() {
+                continue;
             }
 
-            Self::snr_xml_file(&mode, entry.path(), src_file);
+            let mut reader = Self::get_reader(pkg, &rels_part)?;
+            let mut buf = Vec::new();
+            loop {
+                match reader.read_event_into(&mut buf) {
+                    Err(e) => return Err(DocxError::xml(&rels_part, reader.error_position(), e)),
+                    Ok(Event::Eof) => break,
+                    Ok(Event::Empty(e)) | Ok(Event::Start(e))
+                            if Self::is_element(&reader, e.name(), RELATIONSHIPS_NS, "Relationship") => {
+                        let attr_value = |name: &str| e.attributes().flatten()
+                            .find(|a| Self::is_attribute(&reader, a.key, None, name))
+                            .map(|a| String::from_utf8_lossy(&a.value).to_string());
+
+                        if attr_value("TargetMode").as_deref() == Some("External") {
+                            continue;
+                        }
+                        let story = attr_value("Type")
+                            .and_then(|t| t.rsplit('/').next().and_then(StoryType::from_name));
+                        if let (Some(st), Some(target)) = (story, attr_value("Target")) {
+                            let part = Self::resolve_target(&main_part, &target);
+                            if st != StoryType::Main && stories.contains(&st) && !parts.contains(&part) {
+                                parts.push(part);
+                            }
+                        }
+                    },
+                    _ => ()
+                }
+            }
         }
+        Ok(parts)
+    }
 
-        if let Some(outfile) = output_file {
-            ZipUtil::write_zip(dir, outfile).expect(outfile);
-        }
+    /// Obtain the relationships of `part`, as a map from relationship id to target. Returns an empty map if
+    /// the part has no relationships.
+    pub(crate) fn get_relationships(pkg: &DocxPackage, part: &str) -> DocxResult<HashMap<String, String>> {
+        Ok(Self::get_relationship_details(pkg, part)?.into_iter()
+            .map(|(id, rel)| (id, rel.target))
+            .collect())
     }
 
-    fn snr_xml_file(mode: &Mode, path: &Path, src_file: &str) {
-        match mode {
-            Mode::Cat =>
-                Self::cat_text(path, src_file),
-            Mode::ReplaceAttribute { regex, replacement } =>
-                Self::snr_change_attribute(path, regex, replacement, src_file),
-            Mode::CatAttrCondition { .. } =>
-                Self::cat_xml_attribute(mode, path, src_file),
-            Mode::Grep { regex } =>
-                Self::grep_text(path, src_file, regex),
-            Mode::Replace { regex, replacement } =>
-                Self::replace_text(path, src_file, regex, replacement),
-            Mode::StyleChange { style, replacement } =>
-                Self::style_change(path, src_file, style, replacement)
+    /// Returns a map from the relationship id to the relationships of `part`.
+    pub(crate) fn get_relationship_details(pkg: &DocxPackage, part: &str) -> DocxResult<HashMap<String, Relationship>> {
+        let (defaults, _) = Self::get_content_types(pkg)?;
+        let rels_extension = defaults.get(RELATION_MT).map(|e| e.as_str()).unwrap_or("rels");
+        let rels_part = Self::rels_part_name(part, rels_extension);
+
+        let mut rels = HashMap::new();
+        if pkg.get_part(&rels_part).is_none() {
+            return Ok(rels);
         }
-    }
 
-    fn get_reader(path: &Path) -> Reader<BufReader<File>> {
-        Reader::from_file(path).expect(&path.to_string_lossy())
+        let mut reader = Self::get_reader(pkg, &rels_part)?;
+        let mut buf = Vec::new();
+        loop {
+            match reader.read_event_into(&mut buf) {
+                Err(e) => return Err(DocxError::xml(&rels_part, reader.error_position(), e)),
+                Ok(Event::Eof) => break,
+                Ok(Event::Empty(e)) | Ok(Event::Start(e))
+                        if Self::is_element(&reader, e.name(), RELATIONSHIPS_NS, "Relationship") => {
+                    let attr_value = |name: &str| e.attributes().flatten()
+                        .find(|a| Self::is_attribute(&reader, a.key, None, name))
+                        .and_then(|a| a.unescape_value().ok().map(|v| v.to_string()));
+
+                    if let (Some(id), Some(target)) = (attr_value("Id"), attr_value("Target")) {
+                        rels.insert(id, Relationship {
+                            rel_type: attr_value("Type"), target, target_mode: attr_value("TargetMode")
+                        });
+                    }
+                },
+                _ => ()
+            }
+        }
+        Ok(rels)
     }
 
-    fn read_namespaces(e: &BytesStart, nslist: &mut Vec<String>) {
-        if nslist.len() != 1 {
-            panic!("Should contain exactly 1 root namespace: {:?}", nslist);
+    /// Resolve the `target` of a relationship of `part` to the name of the part it refers to.
+    pub(crate) fn resolve_target(part: &str, target: &str) -> String {
+        if let Some(absolute) = target.strip_prefix('/') {
+            return absolute.to_string();
         }
-        let initial_namespace = nslist.get(0).unwrap().to_owned();
-
-        for attr in e.attributes() {
-            if let Ok(a) = attr {
-                let k = str::from_utf8(a.key.as_ref());
-                if let Ok(key) = k {
-                    if key.starts_with("xmlns:") {
-                        if a.value.as_ref() == initial_namespace.as_bytes() {
-                            let alt_name = (&key[6..]).to_string();
-                            nslist.push(alt_name);
-                        }
-                    }
-                }
+
+        let mut segments: Vec<&str> = part.split('/').collect();
+        segments.pop();
+        for seg in target.split('/') {
+            match seg {
+                "." | "" => (),
+                ".." => { segments.pop(); },
+                _ => segments.push(seg),
             }
         }
+        segments.join("/")
     }
 
-    /// Writes the `writer` to disk which is assumed to write to `temp_file`. If `has_changes` is `true`
-    /// then replaces the `xml_file` with `temp_file`, otherwise just deletes `temp_file`.
-    fn finish_writing(writer: Writer<BufWriter<File>>, xml_file: &Path, temp_file: &Path, has_changes: bool) {
-        // This writes out the file
-        writer.into_inner().into_inner().unwrap();
+    /// Select the parts of `pkg` to operate on. If `files` is specified only the parts in this list
+    /// are selected, otherwise all parts ending with `.xml` are.
+    fn select_parts(pkg: &DocxPackage, files: &Option<Vec<String>>) -> Vec<String> {
+        pkg.part_names().into_iter()
+            .filter(|name| match files {
+                Some(file_list) => file_list.contains(name),
+                None => name.ends_with(".xml")
+            })
+            .collect()
+    }
 
-        if has_changes {
-            // Replace the original file with the new one.
-            fs::remove_file(xml_file).unwrap();
-            fs::rename(temp_file, xml_file).unwrap();
-        } else {
-            // No changes, so just remove the generated file.
-            fs::remove_file(temp_file).unwrap();
+    /// Perform the read-only operation specified in `mode` on each part in `pkg`. The original name
+    /// of the .docx file is provided in `src_file`.
+    ///
+    /// Optionally specify `files` as the list of parts to match. If not specified, all parts ending with `.xml` are matched.
+    fn scan_xml(mode: Mode, pkg: &DocxPackage, src_file: &str, files: Option<Vec<String>>,
+            reporter: &mut dyn DocxReporter) -> DocxResult<()> {
+        for part in Self::select_parts(pkg, &files) {
+            if let Some(xml) = pkg.get_part(&part) {
+                Self::snr_xml_part(&mode, xml, &part, src_file, reporter)?;
+            }
         }
+        Ok(())
     }
 
-    /// For each namespace in `nsl` produce a qname result that has the namespace and `tag` as local name.
-    fn nsl_to_fqnames(nsl: &Vec<String>, tag: &str) -> Vec<String> {
-        let mut fqnames = vec![];
+    /// Perform the operation specified in `mode` on each part in `pkg` and update the parts that were
+    /// changed by it. The original name of the .docx file is provided in `src_file`.
+    ///
+    /// Optionally specify `files` as the list of parts to match. If not specified, all parts ending with `.xml` are matched.
+    fn snr_xml(mode: Mode, pkg: &mut DocxPackage, src_file: &str, files: Option<Vec<String>>,
+            reporter: &mut dyn DocxReporter) -> DocxResult<()> {
+        for part in Self::select_parts(pkg, &files) {
+            let new_xml = match pkg.get_part(&part) {
+                Some(xml) => Self::snr_xml_part(&mode, xml, &part, src_file, reporter)?,
+                None => None
+            };
 
-        for ns in nsl {
-            let mut fq = ns.clone();
-            fq.push(':');
-            fq.push_str(tag);
-            fqnames.push(fq);
+            if let Some(data) = new_xml {
+                pkg.set_part(&part, data);
+            }
         }
+        Ok(())
+    }
 
-        fqnames
+    /// Perform the operation in `mode` on the XML in `xml`, which is the content of `part`. If the operation
+    /// changed the XML the new content is returned.
+    fn snr_xml_part(mode: &Mode, xml: &[u8], part: &str, src_file: &str,
+            reporter: &mut dyn DocxReporter) -> DocxResult<Option<Vec<u8>>> {
+        match mode {
+            Mode::Cat =>
+                Self::cat_text(xml, part, src_file, reporter),
+            Mode::ReplaceAttribute { regex, replacement, rel_type } =>
+                Self::snr_change_attribute(xml, part, regex, replacement, rel_type, src_file, reporter),
+            Mode::CatAttrCondition { .. } =>
+                Self::cat_xml_attribute(mode, xml, part, src_file, reporter),
+            Mode::Grep { regex } =>
+                Self::grep_text(xml, part, src_file, regex, reporter),
+            Mode::Replace { regex, replacement, track: None } =>
+                Self::replace_text(xml, part, src_file, regex, replacement, reporter),
+            Mode::Replace { regex, replacement, track: Some(track) } =>
+                Self::replace_text_tracked(xml, part, src_file, regex, replacement, track, reporter),
+            Mode::StyleChange { style, replacement } =>
+                Self::style_change(xml, part, style, replacement, src_file, reporter)
+        }
     }
 
-    /// Convert a list of qnames specified as strings in to a list of QNames.
-    fn qnames(fqnl: &Vec<String>) -> Vec<QName> {
-        let mut qnames = vec![];
+    /// Obtain a namespace-aware reader for `part` in `pkg`.
+    pub(crate) fn get_reader<'a>(pkg: &'a DocxPackage, part: &str) -> DocxResult<NsReader<&'a [u8]>> {
+        let xml = pkg.get_part(part)
+            .ok_or_else(|| DocxError::MissingPart(part.to_string()))?;
+        Ok(NsReader::from_reader(xml))
+    }
 
-        for fqn in fqnl {
-            qnames.push(QName(fqn.as_bytes()));
+    /// Finishes the XML written by `writer`. If `has_changes` is `true` the written XML is returned, so that it
+    /// can replace the original part, otherwise `None` is returned.
+    fn finish_writing(writer: Writer<Vec<u8>>, has_changes: bool) -> Option<Vec<u8>> {
+        if has_changes {
+            Some(writer.into_inner())
+        } else {
+            None
         }
-
-        qnames
     }
 
-    /// Check if any of the namespaces specified as `nslist` with `tag` as local name contains the QName
-    /// specified as `qn`.
-    fn match_tag(qn: &QName, nslist: &Vec<String>, tag: &str) -> bool {
-        let para_fqnl = Self::nsl_to_fqnames(nslist, tag);
-        let para_qnames = Self::qnames(&para_fqnl);
-        let contains = para_qnames.contains(qn);
-        contains
+    /// Check if the element `name` read by `reader` has the local name `local` in namespace `ns`.
+    pub(crate) fn is_element(reader: &NsReader<&[u8]>, name: QName, ns: &str, local: &str) -> bool {
+        let (res, ln) = reader.resolve_element(name);
+        ln.as_ref() == local.as_bytes() && res == ResolveResult::Bound(Namespace(ns.as_bytes()))
     }
 
-    fn create_temp_file(dir: &Path) -> PathBuf {
-        let mut temp_res = dir.to_owned();
-        temp_res.push(format!("{}.xml", Uuid::new_v4()));
+    /// Check if the element `name` read by `reader` is the WordprocessingML element `local`.
+    pub(crate) fn is_w(reader: &NsReader<&[u8]>, name: QName, local: &str) -> bool {
+        Self::is_element(reader, name, WORDDOC_NS, local)
+    }
 
-        temp_res
+    /// Check if the attribute `key` read by `reader` has the local name `local` in namespace `ns`,
+    /// or has no namespace if `ns` is `None`.
+    pub(crate) fn is_attribute(reader: &NsReader<&[u8]>, key: QName, ns: Option<&str>, local: &str) -> bool {
+        let (res, ln) = reader.resolve_attribute(key);
+        let expected = match ns {
+            Some(n) => ResolveResult::Bound(Namespace(n.as_bytes())),
+            None => ResolveResult::Unbound,
+        };
+        ln.as_ref() == local.as_bytes() && res == expected
     }
 
-    /// Read the contents of `xml_file` which would typically be a `word/document.xml` file and collect
-    /// all paragraphs of text in the result as a `Vec<String>`.
+    /// Read the XML in `xml` which would typically be the `word/document.xml` part and collect
+    /// all paragraphs of text, with their paragraph style, in the result as a `Vec<ParaText>`.
     ///
     /// In the input XML file a single paragraph and even a single word might be spread over different
     /// <w:t> tags. The String list returned merges these together so that the result looks like what
@@ -311,85 +602,96 @@ impl XMLUtil {
     /// originated in which tag. For this this method numbers the <w:t> tags in the document and in its
     /// second return value it returns a BTreeMap where the key is the number, or id, of each text element
     /// and the value is a tuple where the first value is the paragraph that is relates to and the second
-    /// value is the character position in that paragraph that the tag with this id starts.
+    /// value is the character position in that paragraph that the tag with this id starts. The third value
+    /// is the length of the text in the tag.
     ///
-    /// `src_file` is the name of the original .docx file.
-    /// If the `replacements` HashMap contains data, then these will be applied and the result is used to
-    /// overwrite the `xml_file` input file.
+    /// `part` is the name of the part inside the .docx file and `src_file` is the name of the original .docx file.
+    /// If the `replacements` HashMap contains data, then these will be applied and the resulting XML is
+    /// returned as the third return value.
     /// The keys of the `replacements` map is the id of the tags that need to be replaced and the first
     /// value of the value tuple of `replacements` is the new value for this tag. The second value of the
     /// tuple is not used in this function.
-    fn get_replace_text(xml_file: &Path, src_file: &str, replacements: HashMap<usize, (String, Vec<i32>)>)
-            -> (Vec<String>, BTreeMap<usize, (usize, usize)>) {
-        let mut reader = Self::get_reader(xml_file);
-
-        let temp_file = Self::create_temp_file(xml_file.parent().unwrap());
-        let tf = File::create(&temp_file).unwrap();
-        let mut writer = Writer::new(BufWriter::new(tf));
+    #[allow(clippy::type_complexity)]
+    fn get_replace_text(xml: &[u8], part: &str, src_file: &str, replacements: HashMap<usize, (String, Vec<i32>)>,
+            reporter: &mut dyn DocxReporter)
+            -> DocxResult<(Vec<ParaText>, BTreeMap<usize, (usize, usize, usize)>, Option<Vec<u8>>)> {
+        let mut reader = NsReader::from_reader(xml);
+        let mut writer = Writer::new(Vec::new());
 
         let mut paras = Vec::new();
         let mut cur_line = String::new();
+        let mut new_line = String::new();
+        let mut cur_style = None;
         let mut coords = BTreeMap::new();
 
         let mut buf = Vec::new();
 
-        let mut nslist = vec![WORDDOC_NS.to_string()];
 
-        let mut first_element = true;
         let mut inside_paragraph = false;
         let mut inside_text = false;
         let mut text_els: usize = 0;
         loop {
             let ev = reader.read_event_into(&mut buf);
-            // println!("Read event: {:?}", ev);
             match ev {
-                Err(e) => panic!("Error reading {} at position {}: {:?}", src_file, reader.buffer_position(), e),
+                Err(e) => return Err(DocxError::xml(part, reader.error_position(), e)),
                 Ok(Event::Eof) => break,
                 Ok(Event::Empty(e)) => {
-                    if Self::match_tag(&e.name(), &nslist, "br") {
+                    if Self::is_w(&reader, e.name(), "br") {
                         cur_line.push_str(LINE_ENDING);
+                        new_line.push_str(LINE_ENDING);
+                    } else if inside_paragraph && Self::is_w(&reader, e.name(), "pStyle") {
+                        cur_style = Self::get_attribute(&e, &reader, "val", part).ok();
                     }
-                    writer.write_event(Event::Empty(e)).expect(&temp_file.to_string_lossy());
+                    writer.write_event(Event::Empty(e))?;
                 },
                 Ok(Event::Start(e)) => {
-                    if first_element {
-                        first_element = false;
-                        Self::read_namespaces(&e, &mut nslist);
-                    }
-
-                    if Self::match_tag(&e.name(), &nslist, "p") {
+                    if Self::is_w(&reader, e.name(), "p") {
                         inside_paragraph = true;
-                    } else if inside_paragraph && Self::match_tag(&e.name(), &nslist, "t") {
+                    } else if inside_paragraph && Self::is_w(&reader, e.name(), "t") {
                         inside_text = true;
-                    } else if inside_paragraph && Self::match_tag(&e.name(), &nslist, "br") {
+                    } else if inside_paragraph && Self::is_w(&reader, e.name(), "br") {
                         cur_line.push_str(LINE_ENDING);
+                        new_line.push_str(LINE_ENDING);
+                    } else if inside_paragraph && Self::is_w(&reader, e.name(), "pStyle") {
+                        cur_style = Self::get_attribute(&e, &reader, "val", part).ok();
                     }
-                    writer.write_event(Event::Start(e)).expect(&temp_file.to_string_lossy());
+                    writer.write_event(Event::Start(e))?;
                 },
                 Ok(Event::End(e)) => {
-                    if Self::match_tag(&e.name(), &nslist, "p") {
+                    if Self::is_w(&reader, e.name(), "p") {
                         inside_paragraph = false;
-                        if cur_line.len() > 0 {
-                            paras.push(cur_line.clone());
+                        if !cur_line.is_empty() {
+                            if cur_line != new_line {
+                                reporter.on_replacement(&Replacement {
+                                    src_file: src_file.to_string(), part: part.to_string(),
+                                    paragraph: Some(paras.len()), attribute: None,
+                                    old: cur_line.clone(), new: new_line.clone()
+                                });
+                            }
+                            paras.push(ParaText { text: cur_line.clone(), style: cur_style.clone() });
                         }
                         cur_line.clear();
-                    } else if inside_paragraph && Self::match_tag(&e.name(), &nslist, "t") {
+                        new_line.clear();
+                        cur_style = None;
+                    } else if inside_paragraph && Self::is_w(&reader, e.name(), "t") {
                         inside_text = false;
                     }
-                    writer.write_event(Event::End(e)).expect(&temp_file.to_string_lossy());
+                    writer.write_event(Event::End(e))?;
                 },
                 Ok(Event::Text(t)) => {
                     let mut ct = t;
 
                     if inside_text {
-                        let val = ct.unescape().expect(src_file);
-                        if val.len() > 0 {
-                            coords.insert(text_els, (paras.len(), cur_line.len()));
+                        let val = ct.unescape().map_err(|e| DocxError::xml(part, reader.buffer_position(), e))?;
+                        if !val.is_empty() {
+                            coords.insert(text_els, (paras.len(), cur_line.len(), val.len()));
 
                             let new_text = replacements.get(&text_els);
                             if let Some((nt, _)) = new_text {
+                                new_line.push_str(nt);
                                 ct = BytesText::new(nt);
-                                println!("{}: {}\n-> {}", src_file, val, nt);
+                            } else {
+                                new_line.push_str(val.as_ref());
                             }
 
                             text_els += 1;
@@ -397,61 +699,48 @@ impl XMLUtil {
                             cur_line.push_str(val.as_ref());
                         }
                     }
-                    writer.write_event(Event::Text(ct)).expect(&temp_file.to_string_lossy());
+                    writer.write_event(Event::Text(ct))?;
                 },
-                Ok(e) => writer.write_event(e).expect(&temp_file.to_string_lossy())
+                Ok(e) => writer.write_event(e)?
             }
         }
 
-        drop(reader); // Close the file being read
+        let new_xml = Self::finish_writing(writer, !replacements.is_empty());
 
-        Self::finish_writing(writer, xml_file, &temp_file, !replacements.is_empty());
-
-        (paras, coords)
+        Ok((paras, coords, new_xml))
     }
 
-    fn cat_text(path: &Path, src_file: &str) {
-        let (paras, _) = Self::get_replace_text(path, src_file, HashMap::new());
+    fn cat_text(xml: &[u8], part: &str, src_file: &str, reporter: &mut dyn DocxReporter) -> DocxResult<Option<Vec<u8>>> {
+        let (paras, _, _) = Self::get_replace_text(xml, part, src_file, HashMap::new(), reporter)?;
 
-        for para in paras {
-            println!("{}: {}", src_file, para);
+        for (index, para) in paras.into_iter().enumerate() {
+            reporter.on_paragraph(&Paragraph {
+                src_file: src_file.to_string(), part: part.to_string(), index,
+                style: para.style, text: para.text
+            });
         }
+        Ok(None)
     }
 
-    fn grep_text(path: &Path, src_file: &str, regex: &Regex) {
-        let (paras, _) = Self::get_replace_text(path, src_file, HashMap::new());
+    fn grep_text(xml: &[u8], part: &str, src_file: &str, regex: &Regex, reporter: &mut dyn DocxReporter) -> DocxResult<Option<Vec<u8>>> {
+        let (paras, _, _) = Self::get_replace_text(xml, part, src_file, HashMap::new(), reporter)?;
 
-        for para in paras {
-            if regex.is_match(&para) {
-                println!("{}: {}", src_file, para);
+        for (index, para) in paras.into_iter().enumerate() {
+            if regex.is_match(&para.text) {
+                reporter.on_match(&Paragraph {
+                    src_file: src_file.to_string(), part: part.to_string(), index,
+                    style: para.style, text: para.text
+                });
             }
         }
+        Ok(None)
     }
 
-    fn get_line_coords(cur_line: usize, coords: &BTreeMap<usize, (usize, usize)>) -> BTreeMap<usize, (usize, usize)> {
-        let mut res = BTreeMap::new();
-
-        for (id, (line, pos)) in coords {
-            if cur_line == *line {
-                res.insert(*pos, (usize::MAX, *id));
-
-                if *pos > 0 {
-                    // The position is not at the start of the line, update the previous position with the endpos
-                    let prev_id = id - 1;
-                    let prev = coords.get(&prev_id);
-
-                    if let Some((pl, ppos)) = prev {
-                        if *pl != cur_line {
-                            // Shouldn't happen
-                            break;
-                        }
-                        res.insert(*ppos, (*pos, prev_id));
-                    }
-                }
-            }
-        }
-
-        res
+    fn get_line_coords(cur_line: usize, coords: &BTreeMap<usize, (usize, usize, usize)>) -> BTreeMap<usize, (usize, usize)> {
+        coords.iter()
+            .filter(|(_, (line, _, _))| *line == cur_line)
+            .map(|(id, (_, pos, len))| (*pos, (*pos + *len, *id)))
+            .collect()
     }
 
     /// Apply the replacements needed for a single <w:t> tag. The id (internal number) of the tag is
@@ -464,7 +753,8 @@ impl XMLUtil {
     /// the current value of the tag, given any previous replacements and an offset mapping that contains
     /// for each character position in the original tag text a positive or negative offset in case the
     /// match locations must be adjusted given any previously applied replacements, as they may have
-    /// changed the length of text in the tag.
+    /// changed the length of text in the tag. The offset of a position is the sum of the entries up to and
+    /// including it, the first entry holds the length of text inserted at the very start of the tag.
     fn replace_within_tag(replacements: &mut HashMap<usize, (String, Vec<i32>)>, tag_id: usize, tag: &str,
             match_start: usize, match_end: usize, replace: &str) {
         let prev_repl = replacements.get(&tag_id);
@@ -476,34 +766,24 @@ impl XMLUtil {
             corr_idxs = c.clone();
         } else {
             replaced = tag.to_string();
-            corr_idxs = vec![0; replaced.len()];
+            corr_idxs = vec![0; replaced.len() + 1];
         }
 
-        let mut correction = 0;
-        for i in 0..match_start {
-            correction += corr_idxs[i as usize];
-        }
+        let correction: i32 = corr_idxs[..=match_start].iter().sum();
 
         let repl_start = (match_start as i32 + correction) as usize;
         let repl_end = (match_end as i32 + correction) as usize;
         replaced.replace_range(repl_start..repl_end, replace);
 
-        let delta = replace.len() as i32 - match_end as i32 + match_start as i32;
-        if delta < 0 {
-            let from_pos = (match_end as i32) + correction;
-            for i in from_pos+delta .. from_pos {
-                corr_idxs[i as usize] -= 1;
-            }
-        } else if delta > 0 {
-            let corr_pos = (match_end as i32) - 1;
-            corr_idxs[corr_pos as usize] += delta;
-        }
+        // Matches don't overlap, so the text after this match moves by the difference in length. For an
+        // empty match at the start of the tag this is the first entry.
+        corr_idxs[match_end] += replace.len() as i32 - (match_end - match_start) as i32;
 
         replacements.insert(tag_id, (replaced, corr_idxs));
     }
 
-    /// In the file pointed to by `path` replace all matching `regex`es with the `replace` value.
-    /// The input file will be overwritten with the result. `src_file` is the name of the original
+    /// In the XML in `xml` replace all matching `regex`es with the values in `replace_with`.
+    /// If anything was replaced the resulting XML is returned. `src_file` is the name of the original
     /// .docx file
     ///
     /// This method works by reading the file contents first via `get_replace_text` and applying the
@@ -512,276 +792,595 @@ impl XMLUtil {
     /// Replacements are mapped to <w:t> tags which are numbered internally.
     /// Once all the replacements have been found, the `get_replace_text` method is called again
     /// but now with the replacements to-be-applied.
-    fn replace_text(path: &Path, src_file: &str, regex: &Regex, replace: &str) {
+    fn replace_text(xml: &[u8], part: &str, src_file: &str, regex: &Regex, replace_with: &ReplaceWith,
+            reporter: &mut dyn DocxReporter) -> DocxResult<Option<Vec<u8>>> {
         let mut replacements: HashMap<usize, (String, Vec<i32>)> = HashMap::new();
 
-        let (paras, coords) = Self::get_replace_text(path, src_file, HashMap::new());
+        let (paras, coords, _) = Self::get_replace_text(xml, part, src_file, HashMap::new(), reporter)?;
 
-        let mut cur_line: usize = 0;
-        for para in paras {
+        for (cur_line, para_text) in paras.iter().enumerate() {
+            let para = &para_text.text;
             let line_coords = Self::get_line_coords(cur_line, &coords);
-            for m in regex.find_iter(&para) {
-                let mstart = m.start();
-                let mend = m.end();
-
-                let mut start_id = 0;
-                let mut end_id = 0;
-                let mut start_idx = 0;
-
-                let mut tags = BTreeMap::new();
-                for (idx, (eidx, id)) in &line_coords {
-                    let neidx;
-                    if *eidx > para.len() {
-                        neidx = para.len();
+            for caps in regex.captures_iter(para) {
+                let m = caps.get(0).expect("the whole match is always captured");
+                let replace = replace_with.for_match(&caps);
+
+                // The tags that hold the text of the match. An empty match is placed in the first tag it touches.
+                let mut tags: Vec<(usize, usize, usize)> = line_coords.iter()
+                    .map(|(start, (end, id))| (*start, (*end).min(para.len()), *id))
+                    .filter(|(start, end, _)| if m.is_empty() {
+                        *start <= m.start() && *end >= m.start()
                     } else {
-                        neidx = *eidx;
-                    }
+                        *start < m.end() && *end > m.start()
+                    })
+                    .collect();
+                if m.is_empty() {
+                    tags.truncate(1);
+                }
 
-                    let t = &para[*idx..neidx];
-                    tags.insert(*id, t);
+                // Each tag gets as much of the replacement as it held of the match, the last one gets the rest
+                let mut remaining = replace.as_ref();
+                for (n, (start, end, id)) in tags.iter().enumerate() {
+                    let match_start = m.start().max(*start) - start;
+                    let match_end = m.end().min(*end) - start;
 
-                    if *idx <= mstart {
-                        start_id = *id;
-                        end_id = *id;
-                        start_idx = *idx;
-                    }
-                    if *idx < mend {
-                        end_id = *id;
-                    }
+                    let repl = if n + 1 == tags.len() {
+                        remaining
+                    } else {
+                        let mut len = (match_end - match_start).min(remaining.len());
+                        while !remaining.is_char_boundary(len) {
+                            len -= 1;
+                        }
+                        &remaining[..len]
+                    };
+                    remaining = &remaining[repl.len()..];
+
+                    Self::replace_within_tag(&mut replacements, *id, &para[*start..*end], match_start, match_end, repl);
                 }
+            }
+        }
+
+        if replacements.is_empty() {
+            return Ok(None);
+        }
+
+        let (_, _, new_xml) = Self::get_replace_text(xml, part, src_file, replacements, reporter)?;
+        Ok(new_xml)
+    }
+
+    /// In the XML in `xml` replace all matching `regex`es with the values in `replace_with` as tracked changes
+    /// made by `track`. If anything was replaced the resulting XML is returned.
+    ///
+    /// The matches in each paragraph are mapped onto the <w:t> tags holding the text, dividing the text of
+    /// these tags into segments that are kept, deleted or inserted. The text inserted for a match is placed in
+    /// the last tag that starts before the end of the match. `write_tracked` then writes the XML, splitting
+    /// the runs around the deleted and inserted segments.
+    fn replace_text_tracked(xml: &[u8], part: &str, src_file: &str, regex: &Regex, replace_with: &ReplaceWith,
+            track: &TrackChanges, reporter: &mut dyn DocxReporter) -> DocxResult<Option<Vec<u8>>> {
+        let (paras, coords, _) = Self::get_replace_text(xml, part, src_file, HashMap::new(), reporter)?;
+
+        let mut segments = HashMap::new();
+        for (index, para) in paras.iter().enumerate() {
+            let changes: Vec<(Range<usize>, String)> = regex.captures_iter(&para.text)
+                .filter_map(|caps| {
+                    let m = caps.get(0)?;
+                    let new = replace_with.for_match(&caps);
+                    (m.as_str() != new).then(|| (m.range(), new.into_owned()))
+                })
+                .collect();
+            let tags: Vec<(usize, Range<usize>)> = coords.iter()
+                .filter(|(_, (p, _, _))| *p == index)
+                .map(|(id, (_, pos, len))| (*id, *pos..pos + len))
+                .collect();
+            if changes.is_empty() || tags.is_empty() {
+                continue;
+            }
+
+            for (id, range) in &tags {
+                let segs = Self::tag_segments(&para.text, range, *id, &tags, &changes);
+                if segs.iter().any(|s| !matches!(s, Segment::Keep(_))) {
+                    segments.insert(*id, segs);
+                }
+            }
+
+            reporter.on_replacement(&Replacement {
+                src_file: src_file.to_string(), part: part.to_string(),
+                paragraph: Some(index), attribute: None,
+                old: para.text.clone(), new: regex.replace_all(&para.text, |caps: &Captures| replace_with.for_match(caps).into_owned()).to_string()
+            });
+        }
+
+        if segments.is_empty() {
+            return Ok(None);
+        }
+        Self::write_tracked(xml, part, &segments, track).map(Some)
+    }
+
+    /// Divide the text of tag `id`, which covers `range` of the paragraph text `text`, into the segments
+    /// to keep, delete and insert for the `changes` made in the paragraph. `tags` holds all the tags of the
+    /// paragraph with their ranges.
+    fn tag_segments(text: &str, range: &Range<usize>, id: usize, tags: &[(usize, Range<usize>)],
+            changes: &[(Range<usize>, String)]) -> Vec<Segment> {
+        let mut segs = vec![];
+        let mut pos = range.start;
+        for (change, new) in changes {
+            let del_start = change.start.max(range.start);
+            let del_end = change.end.min(range.end);
+            if del_start < del_end {
+                if pos < del_start {
+                    segs.push(Segment::Keep(text[pos..del_start].to_string()));
+                }
+                segs.push(Segment::Delete(text[del_start..del_end].to_string()));
+                pos = del_end;
+            }
+
+            let insert_tag = tags.iter().rev()
+                .find(|(_, r)| r.start < change.end || r.start <= change.start)
+                .unwrap_or(&tags[0]).0;
+            if insert_tag == id && !new.is_empty() {
+                let at = change.end.clamp(range.start, range.end).max(pos);
+                if pos < at {
+                    segs.push(Segment::Keep(text[pos..at].to_string()));
+                    pos = at;
+                }
+                segs.push(Segment::Insert(new.clone()));
+            }
+        }
+        if pos < range.end {
+            segs.push(Segment::Keep(text[pos..range.end].to_string()));
+        }
+        segs
+    }
 
-                // The match region is between start_id and end_id now
+    /// Write the XML in `xml` with the text of the <w:t> tags in `segments` replaced by their segments. The
+    /// runs holding these tags are split, so that deleted segments can be written in a <w:del> and inserted
+    /// segments in a <w:ins> element, each in a run with the same properties as the original run.
+    fn write_tracked(xml: &[u8], part: &str, segments: &HashMap<usize, Vec<Segment>>, track: &TrackChanges)
+            -> DocxResult<Vec<u8>> {
+        let mut next_id = Self::max_id(xml, part)? + 1;
 
-                if start_id == end_id {
-                    // simplest case start and end are the same:
-                    if let Some(tag) = tags.get(&start_id) {
-                        Self::replace_within_tag(&mut replacements, start_id, tag, mstart - start_idx, mend - start_idx, replace);
+        let mut reader = NsReader::from_reader(xml);
+        let mut writer = Writer::new(Vec::new());
+        let mut buf = Vec::new();
+
+        let mut runs: Vec<TrackedRun> = vec![];
+        let mut pending_text: Option<BytesStart<'static>> = None;
+        let mut open_text: Option<String> = None;
+        let mut inside_paragraph = false;
+        let mut inside_text = false;
+        let mut text_els: usize = 0;
+        loop {
+            match reader.read_event_into(&mut buf) {
+                Err(e) => return Err(DocxError::xml(part, reader.error_position(), e)),
+                Ok(Event::Eof) => break,
+                Ok(Event::Start(e)) => {
+                    if Self::is_w(&reader, e.name(), "r") {
+                        runs.push(TrackedRun { start: e.clone().into_owned(), props: vec![], props_depth: 0, open: true,
+                            offset: writer.get_ref().len(), has_content: false });
+                    } else if let Some(run) = runs.last_mut().filter(|r| r.props_depth > 0
+                            || (r.props.is_empty() && Self::is_w(&reader, e.name(), "rPr"))) {
+                        run.props_depth += 1;
+                        run.props.push(Event::Start(e.clone().into_owned()));
+                    } else {
+                        if Self::is_w(&reader, e.name(), "p") {
+                            inside_paragraph = true;
+                        } else if inside_paragraph && Self::is_w(&reader, e.name(), "t") {
+                            inside_text = true;
+                            pending_text = Some(e.into_owned());
+                            continue;
+                        }
+                        Self::run_content(&mut writer, &mut runs)?;
+                    }
+                    writer.write_event(Event::Start(e))?;
+                },
+                Ok(Event::Empty(e)) => {
+                    if let Some(run) = runs.last_mut().filter(|r| r.props_depth > 0
+                            || (r.props.is_empty() && Self::is_w(&reader, e.name(), "rPr"))) {
+                        run.props.push(Event::Empty(e.clone().into_owned()));
+                    } else {
+                        Self::run_content(&mut writer, &mut runs)?;
+                    }
+                    writer.write_event(Event::Empty(e))?;
+                },
+                Ok(Event::End(e)) => {
+                    if Self::is_w(&reader, e.name(), "r") {
+                        if runs.pop().is_some_and(|r| r.open) {
+                            writer.write_event(Event::End(e))?;
+                        }
+                        continue;
+                    } else if let Some(run) = runs.last_mut().filter(|r| r.props_depth > 0) {
+                        run.props_depth -= 1;
+                        run.props.push(Event::End(e.clone().into_owned()));
+                    } else if inside_text && Self::is_w(&reader, e.name(), "t") {
+                        inside_text = false;
+                        if let Some(start) = pending_text.take() {
+                            Self::run_content(&mut writer, &mut runs)?;
+                            writer.write_event(Event::Start(start))?;
+                            writer.write_event(Event::End(e))?;
+                        } else if let Some(name) = open_text.take() {
+                            writer.write_event(Event::End(BytesEnd::new(name)))?;
+                        }
+                        continue;
+                    } else {
+                        if Self::is_w(&reader, e.name(), "p") {
+                            inside_paragraph = false;
+                        }
+                        Self::run_content(&mut writer, &mut runs)?;
+                    }
+                    writer.write_event(Event::End(e))?;
+                },
+                Ok(Event::Text(t)) => {
+                    if !inside_text {
+                        // Whitespace between the elements doesn't need a run to be opened again
+                        if !t.iter().all(u8::is_ascii_whitespace) {
+                            Self::run_content(&mut writer, &mut runs)?;
+                        }
+                        writer.write_event(Event::Text(t))?;
+                        continue;
                     }
-                } else {
-                    /*
-                        1. get the length of the replacement
-                        2. get all tags
-                        3. Walk over tags, first one from match position, later ones from start
-                        4. divide up the caracters:
-                            all up to but not including last:
-                                replace the characters
-                            last one:
-                                replace the rest
-                    */
-
-                    let mut remaining_chars = mend as i32 - mstart as i32;
-                    let mut cur_replacement = replace.to_string();
-                    for i in start_id..end_id + 1 {
-                        if remaining_chars < 0 { remaining_chars = 0; }
-
-                        if let Some(tag) = tags.get(&i) {
-                            if i == start_id {
-                                let chars = tag.len() - mstart;
-
-                                let repl;
-                                if cur_replacement.len() >= chars {
-                                    repl = &cur_replacement[0..chars];
-                                } else {
-                                    repl = &cur_replacement;
-                                }
-                                Self::replace_within_tag(&mut replacements, i, tag, mstart, mstart + chars, repl);
 
-                                remaining_chars -= chars as i32;
-                                if cur_replacement.len() >= chars {
-                                    cur_replacement = cur_replacement[chars..].to_string();
-                                } else {
-                                    cur_replacement.clear();
+                    let val = t.unescape().map_err(|e| DocxError::xml(part, reader.buffer_position(), e))?;
+                    let segs = if val.is_empty() {
+                        None
+                    } else {
+                        text_els += 1;
+                        segments.get(&(text_els - 1))
+                    };
+                    let Some(segs) = segs else {
+                        if let Some(start) = pending_text.take() {
+                            Self::run_content(&mut writer, &mut runs)?;
+                            open_text = Some(String::from_utf8_lossy(start.name().as_ref()).to_string());
+                            writer.write_event(Event::Start(start))?;
+                        }
+                        writer.write_event(Event::Text(t))?;
+                        continue;
+                    };
+
+                    for seg in segs {
+                        match seg {
+                            Segment::Keep(text) => {
+                                Self::run_content(&mut writer, &mut runs)?;
+                                if open_text.is_none() {
+                                    let start = match pending_text.take() {
+                                        Some(start) => start,
+                                        None => match runs.last() {
+                                            Some(run) => BytesStart::new(run.w_name("t")),
+                                            None => BytesStart::new("w:t"),
+                                        }
+                                    };
+                                    open_text = Some(String::from_utf8_lossy(start.name().as_ref()).to_string());
+                                    writer.write_event(Event::Start(Self::preserve_space(start)))?;
                                 }
-                            } else if i == end_id {
-                                Self::replace_within_tag(&mut replacements, i, tag, 0, remaining_chars as usize, &cur_replacement);
-                            } else {
-                                let repl;
-                                if cur_replacement.len() >= tag.len() {
-                                    repl = &cur_replacement[0..tag.len()];
-                                } else {
-                                    repl = &cur_replacement;
+                                writer.write_event(Event::Text(BytesText::new(text)))?;
+                            },
+                            Segment::Delete(text) | Segment::Insert(text) => {
+                                let Some(run) = runs.last_mut() else {
+                                    continue;
+                                };
+                                if let Some(name) = open_text.take() {
+                                    writer.write_event(Event::End(BytesEnd::new(name)))?;
+                                }
+                                pending_text = None;
+                                if run.open {
+                                    if run.has_content {
+                                        writer.write_event(Event::End(run.start.to_end()))?;
+                                    } else {
+                                        // Nothing but the properties was written, leave out the run
+                                        writer.get_mut().truncate(run.offset);
+                                    }
+                                    run.open = false;
                                 }
 
-                                Self::replace_within_tag(&mut replacements, i, tag, 0, tag.len(), repl);
-
-                                remaining_chars -= tag.len() as i32;
-                                if cur_replacement.len() >= tag.len() {
-                                    cur_replacement = cur_replacement[tag.len()..].to_string();
-                                } else {
-                                    cur_replacement.clear();
+                                let (kind, text_kind) = match seg {
+                                    Segment::Delete(_) => ("del", "delText"),
+                                    _ => ("ins", "t"),
+                                };
+                                let attr_prefix = match run.start.name().prefix() {
+                                    Some(p) => String::from_utf8_lossy(p.as_ref()).to_string(),
+                                    None => "w".to_string(),
+                                };
+                                let mut wrapper = BytesStart::new(run.w_name(kind));
+                                if run.start.name().prefix().is_none() {
+                                    wrapper.push_attribute(("xmlns:w", WORDDOC_NS));
                                 }
+                                wrapper.push_attribute((format!("{}:id", attr_prefix).as_str(), next_id.to_string().as_str()));
+                                wrapper.push_attribute((format!("{}:author", attr_prefix).as_str(), track.author.as_str()));
+                                wrapper.push_attribute((format!("{}:date", attr_prefix).as_str(), track.date.as_str()));
+                                next_id += 1;
+
+                                let text_start = Self::preserve_space(BytesStart::new(run.w_name(text_kind)));
+                                writer.write_event(Event::Start(wrapper.borrow()))?;
+                                writer.write_event(Event::Start(run.start.borrow()))?;
+                                for ev in &run.props {
+                                    writer.write_event(ev.borrow())?;
+                                }
+                                writer.write_event(Event::Start(text_start.borrow()))?;
+                                writer.write_event(Event::Text(BytesText::new(text)))?;
+                                writer.write_event(Event::End(text_start.to_end()))?;
+                                writer.write_event(Event::End(run.start.to_end()))?;
+                                writer.write_event(Event::End(wrapper.to_end()))?;
                             }
                         }
                     }
+                },
+                Ok(e) => {
+                    Self::run_content(&mut writer, &mut runs)?;
+                    writer.write_event(e)?;
+                }
+            }
+        }
+
+        Ok(writer.into_inner())
+    }
+
+    /// Called before content is written in the innermost run in `runs`. If the run was split it is opened
+    /// again with the same properties.
+    fn run_content(writer: &mut Writer<Vec<u8>>, runs: &mut [TrackedRun]) -> DocxResult<()> {
+        if let Some(run) = runs.last_mut() {
+            if !run.open {
+                run.offset = writer.get_ref().len();
+                writer.write_event(Event::Start(run.start.borrow()))?;
+                for ev in &run.props {
+                    writer.write_event(ev.borrow())?;
                 }
+                run.open = true;
             }
-            cur_line += 1;
+            run.has_content = true;
         }
+        Ok(())
+    }
 
-        if !replacements.is_empty() {
-            Self::get_replace_text(path, src_file, replacements);
+    /// Add `xml:space="preserve"` to the text element `start`, as splitting its text may leave
+    /// leading or trailing spaces.
+    fn preserve_space(mut start: BytesStart) -> BytesStart {
+        if !start.attributes().flatten().any(|a| a.key.as_ref() == b"xml:space") {
+            start.push_attribute(("xml:space", "preserve"));
         }
+        start
     }
 
-    fn style_change(xml_file: &Path, src_file: &str, style: &str, replace: &str) {
-        let mut reader = Self::get_reader(xml_file);
+    /// The highest numeric `w:id` attribute in the XML in `xml`, or 0 if there are none. New revisions
+    /// are numbered from there, as the ids of revisions must be unique.
+    fn max_id(xml: &[u8], part: &str) -> DocxResult<u64> {
+        let mut reader = NsReader::from_reader(xml);
+        let mut buf = Vec::new();
+        let mut max = 0;
+        loop {
+            match reader.read_event_into(&mut buf) {
+                Err(e) => return Err(DocxError::xml(part, reader.error_position(), e)),
+                Ok(Event::Eof) => break,
+                Ok(Event::Start(e)) | Ok(Event::Empty(e)) => {
+                    for a in e.attributes().flatten() {
+                        if Self::is_attribute(&reader, a.key, Some(WORDDOC_NS), "id") {
+                            if let Ok(id) = str::from_utf8(&a.value).unwrap_or_default().parse::<u64>() {
+                                max = max.max(id);
+                            }
+                        }
+                    }
+                },
+                _ => ()
+            }
+        }
+        Ok(max)
+    }
 
-        let temp_file = Self::create_temp_file(xml_file.parent().unwrap());
-        let tf = File::create(&temp_file).unwrap();
-        let mut writer = Writer::new(BufWriter::new(tf));
+    fn style_change(xml: &[u8], part: &str, style: &str, replace: &str, src_file: &str,
+            reporter: &mut dyn DocxReporter) -> DocxResult<Option<Vec<u8>>> {
+        let mut reader = NsReader::from_reader(xml);
+        let mut writer = Writer::new(Vec::new());
 
         let mut has_changes = false;
         let mut buf = Vec::new();
-        let mut nslist = vec![WORDDOC_NS.to_string()];
-        let mut first_element = true;
+
+        // Style changes are reported once the end of the paragraph is reached, so that they
+        // can refer to the paragraph by the same index as the other operations.
+        let mut pending = CollectingReporter::default();
+        let mut para_index = 0;
+        let mut para_has_text = false;
+        let mut inside_text = false;
         loop {
             match reader.read_event_into(&mut buf) {
                 Ok(Event::Empty(mut e)) => {
-                    if Self::match_tag(&e.name(), &nslist, "pStyle") {
-                        let (updated, c) = Self::update_attribute(e, "val", style, replace, src_file);
+                    if Self::is_w(&reader, e.name(), "pStyle") {
+                        let (updated, c) = Self::update_attribute(e, &reader, "val", style, replace, src_file, part, &mut pending)?;
                         has_changes |= c;
                         e = updated;
                     }
-                    writer.write_event(Event::Empty(e)).expect(&temp_file.to_string_lossy());
+                    writer.write_event(Event::Empty(e))?;
                 },
                 Ok(Event::Start(mut e)) => {
-                    if first_element {
-                        first_element = false;
-                        Self::read_namespaces(&e, &mut nslist);
-                    }
-                    if Self::match_tag(&e.name(), &nslist, "pStyle") {
-                        let (updated, c) = Self::update_attribute(e, "val", style, replace, src_file);
+                    if Self::is_w(&reader, e.name(), "pStyle") {
+                        let (updated, c) = Self::update_attribute(e, &reader, "val", style, replace, src_file, part, &mut pending)?;
                         has_changes |= c;
                         e = updated;
+                    } else if Self::is_w(&reader, e.name(), "t") {
+                        inside_text = true;
+                    }
+                    writer.write_event(Event::Start(e))?;
+                },
+                Ok(Event::End(e)) => {
+                    if Self::is_w(&reader, e.name(), "p") {
+                        for mut repl in pending.replacements.drain(..) {
+                            repl.paragraph = if para_has_text { Some(para_index) } else { None };
+                            reporter.on_replacement(&repl);
+                        }
+                        if para_has_text {
+                            para_index += 1;
+                        }
+                        para_has_text = false;
+                    } else if Self::is_w(&reader, e.name(), "t") {
+                        inside_text = false;
                     }
-                    writer.write_event(Event::Start(e)).expect(&temp_file.to_string_lossy());
+                    writer.write_event(Event::End(e))?;
+                },
+                Ok(Event::Text(t)) => {
+                    para_has_text |= inside_text && !t.is_empty();
+                    writer.write_event(Event::Text(t))?;
                 },
                 Ok(Event::Eof) => break,
-                Ok(e) => writer.write_event(e).unwrap(),
-                Err(e) => panic!("Error {:?}", e),
+                Ok(e) => writer.write_event(e)?,
+                Err(e) => return Err(DocxError::xml(part, reader.error_position(), e)),
             }
         }
 
-        Self::finish_writing(writer, xml_file, &temp_file, has_changes);
+        // Style references outside of a paragraph
+        for repl in pending.replacements.drain(..) {
+            reporter.on_replacement(&repl);
+        }
+
+        Ok(Self::finish_writing(writer, has_changes))
     }
 
-    fn snr_change_attribute(xml_file: &Path, regex: &Regex, replace: &str, src_file: &str) {
-        let mut reader = Self::get_reader(xml_file);
+    /// Replace `regex` with `replace` in the `Target` of the relationships of type `rel_type` in the
+    /// relationship part `xml`.
+    #[allow(clippy::too_many_arguments)]
+    fn snr_change_attribute(xml: &[u8], part: &str, regex: &Regex, replace: &str, rel_type: &str, src_file: &str,
+            reporter: &mut dyn DocxReporter) -> DocxResult<Option<Vec<u8>>> {
+        let mut reader = NsReader::from_reader(xml);
+        let mut writer = Writer::new(Vec::new());
 
         let mut has_changes = false;
         let mut buf = Vec::new();
 
-        let temp_file = Self::create_temp_file(xml_file.parent().unwrap());
-        let tf = File::create(&temp_file).unwrap();
-        let mut writer = Writer::new(BufWriter::new(tf));
         loop {
             match reader.read_event_into(&mut buf) {
                 Ok(Event::Empty(e)) => {
-                    let (update_attributes, c) = Self::update_any_attribute(e, &regex, replace, src_file);
+                    let (update_attributes, c) = Self::update_target(e, &reader, regex, replace, rel_type,
+                        src_file, part, reporter);
                     has_changes |= c;
-                    writer.write_event(Event::Empty(update_attributes)).unwrap();
+                    writer.write_event(Event::Empty(update_attributes))?;
                 },
                 Ok(Event::Start(e)) => {
-                    let (update_attributes, c) = Self::update_any_attribute(e, &regex, replace, src_file);
+                    let (update_attributes, c) = Self::update_target(e, &reader, regex, replace, rel_type,
+                        src_file, part, reporter);
                     has_changes |= c;
-                    writer.write_event(Event::Start(update_attributes)).unwrap();
+                    writer.write_event(Event::Start(update_attributes))?;
                 },
                 Ok(Event::Eof) => break,
-                Ok(e) => writer.write_event(e).unwrap(),
-                Err(e) => panic!("Error {:?}", e),
+                Ok(e) => writer.write_event(e)?,
+                Err(e) => return Err(DocxError::xml(part, reader.error_position(), e)),
             }
         }
 
-        Self::finish_writing(writer, xml_file, &temp_file, has_changes);
+        Ok(Self::finish_writing(writer, has_changes))
     }
 
-    fn update_attribute<'a>(bs: BytesStart<'a>, attr_name: &str, search: &str, replace: &str, src_file: &str) -> (BytesStart<'a>, bool) {
-        let prefix = bs.name().prefix();
+    /// Replace the value of the WordprocessingML attribute `attr_name` of element `bs`, read by `reader`,
+    /// if it is equal to `search`.
+    #[allow(clippy::too_many_arguments)]
+    fn update_attribute<'a>(bs: BytesStart<'a>, reader: &NsReader<&[u8]>, attr_name: &str, search: &str, replace: &str,
+            src_file: &str, part: &str, reporter: &mut dyn DocxReporter) -> DocxResult<(BytesStart<'a>, bool)> {
+        let key = bs.attributes().flatten()
+            .find(|a| Self::is_attribute(reader, a.key, Some(WORDDOC_NS), attr_name))
+            .map(|a| a.key.as_ref().to_vec());
+
+        if let Some(k) = key {
+            let rex = Regex::new(&format!("^{}$", regex::escape(search)))?;
+            Ok(Self::update_attributes(bs, Some(QName(&k)), &rex, replace, src_file, part, reporter))
+        } else {
+            Ok((bs, false))
+        }
+    }
 
-        if let Some(pfx) = prefix {
-            let attr_key = format!("{}:{}", str::from_utf8(pfx.as_ref()).unwrap(), attr_name);
-            let qnattr = QName(attr_key.as_bytes());
+    /// Replace `regex` with `replace` in the `Target` attribute of `bs`, read by `reader`, if it is a
+    /// relationship of type `rel_type`.
+    #[allow(clippy::too_many_arguments)]
+    fn update_target<'a>(bs: BytesStart<'a>, reader: &NsReader<&[u8]>, regex: &Regex, replace: &str, rel_type: &str,
+            src_file: &str, part: &str, reporter: &mut dyn DocxReporter) -> (BytesStart<'a>, bool) {
+        if !Self::is_element(reader, bs.name(), RELATIONSHIPS_NS, "Relationship") {
+            return (bs, false);
+        }
 
-            let rex = Regex::new(&format!("^{}$", search)).expect(search);
-            Self::update_attributes(bs, Some(qnattr), &rex, replace, src_file)
-        } else {
-            (bs, false)
+        let attr_key = |name: &str| bs.attributes().flatten()
+            .find(|a| Self::is_attribute(reader, a.key, None, name));
+        let type_matches = attr_key("Type")
+            .is_some_and(|a| a.unescape_value().is_ok_and(|t| Self::is_rel_type(&t, rel_type)));
+        let target = attr_key("Target").map(|a| a.key.as_ref().to_vec());
+
+        match target {
+            Some(k) if type_matches => Self::update_attributes(bs, Some(QName(&k)), regex, replace, src_file, part, reporter),
+            _ => (bs, false),
         }
     }
 
-    fn update_any_attribute<'a>(bs: BytesStart<'a>, regex: &Regex, replace: &str, src_file: &str) -> (BytesStart<'a>, bool) {
-        Self::update_attributes(bs, None, regex, replace, src_file)
+    /// Check if the relationship type URI `uri` is `rel_type`, which is either a full URI or its last part.
+    pub(crate) fn is_rel_type(uri: &str, rel_type: &str) -> bool {
+        uri == rel_type || uri.rsplit('/').next().is_some_and(|t| t.eq_ignore_ascii_case(rel_type))
     }
 
-    fn update_attributes<'a>(bs: BytesStart<'a>, attr_name: Option<QName>, regex: &Regex, replace: &str, src_file: &str) -> (BytesStart<'a>, bool) {
+    fn update_attributes<'a>(bs: BytesStart<'a>, attr_name: Option<QName>, regex: &Regex, replace: &str, src_file: &str, part: &str,
+            reporter: &mut dyn DocxReporter) -> (BytesStart<'a>, bool) {
         let mut es = bs.clone();
 
         es.clear_attributes();
 
         let mut changed = false;
-        for attr in bs.attributes() {
-            if let Ok(a) = attr {
-                let key = a.key;
-                if let Some(ak) = attr_name {
-                    if ak != key {
-                        // There is a key QName specified and they don't match, go to the next attribute
-                        continue;
-                    }
+        for a in bs.attributes().flatten() {
+            let key = a.key;
+            if let Some(ak) = attr_name {
+                if ak != key {
+                    // There is a key QName specified and they don't match, keep the attribute as it is
+                    es.push_attribute(a);
+                    continue;
                 }
+            }
 
-                let val = str::from_utf8(&a.value);
+            // Match the value as it is read, with entities such as &amp; resolved, and escape it again when writing
+            if let Ok(v) = a.unescape_value() {
+                let mut rval = v.clone();
+                if regex.is_match(&v) {
+                    changed = true;
 
-                if let Ok(v) = val {
-                    let mut rval = v;
-                    let rv;
-                    if regex.is_match(&v) {
-                        println!("{}: {}={}", src_file, str::from_utf8(a.key.as_ref()).unwrap(), v);
-                        changed = true;
+                    rval = regex.replace_all(&v, replace).to_string().into();
 
-                        rv = regex.replace_all(&v, replace);
-                        rval = &rv;
-                    }
-                    let na = Attr::DoubleQ(a.key.as_ref(), rval.as_bytes());
-                    let new_attr = Attribute::from(na);
-                    es.push_attribute(new_attr);
+                    reporter.on_replacement(&Replacement {
+                        src_file: src_file.to_string(), part: part.to_string(),
+                        paragraph: None, attribute: Some(String::from_utf8_lossy(a.key.as_ref()).to_string()),
+                        old: v.to_string(), new: rval.to_string()
+                    });
                 }
+                let escaped = escape(rval.as_ref());
+                let na = Attr::DoubleQ(a.key.as_ref(), escaped.as_bytes());
+                let new_attr = Attribute::from(na);
+                es.push_attribute(new_attr);
             }
         }
 
         if changed {
-            return (es, changed);
+            (es, changed)
         } else {
-            return (bs, changed);
+            (bs, changed)
         }
     }
 
-    fn cat_xml_attribute(mode: &Mode, path: &Path, src_file: &str) {
-        let mut reader = Self::get_reader(path);
+    fn cat_xml_attribute(mode: &Mode, xml: &[u8], part: &str, src_file: &str,
+            reporter: &mut dyn DocxReporter) -> DocxResult<Option<Vec<u8>>> {
+        let mut reader = NsReader::from_reader(xml);
         let mut buf = Vec::new();
 
         loop {
             match reader.read_event_into(&mut buf) {
-                Err(e) => panic!("Error reading {} at position {}: {:?}", src_file, reader.buffer_position(), e),
+                Err(e) => return Err(DocxError::xml(part, reader.error_position(), e)),
                 Ok(Event::Eof) => break,
                 Ok(Event::Empty(e)) |
                 Ok(Event::Start(e)) => {
                     if let Mode::CatAttrCondition { tagname, attrname, condkey, condval } = mode {
-                        let s = e.name();
-                        let tq = QName(tagname.as_bytes());
-                        if s.local_name() == tq.local_name() {
-                            let attrs = e.attributes();
-
-                            let use_node = attrs.filter(|a| a.is_ok())
-                                .map(|a| a.unwrap())
-                                .filter(|a| a.key.local_name() == QName(condkey.as_bytes()).local_name())
-                                .filter(|a| a.value == condval.as_bytes())
-                                .count();
-                            if use_node > 0 {
-                                let attr = e.try_get_attribute(attrname);
-                                if let Ok(ao) = attr {
-                                    if let Some(av) = ao {
-                                        println!("{}: {}", src_file, str::from_utf8(&av.value).unwrap_or_default());
-                                    }
+                        if Self::is_element(&reader, e.name(), RELATIONSHIPS_NS, tagname) {
+                            let attr_value = |name: &str| e.attributes().flatten()
+                                .find(|a| Self::is_attribute(&reader, a.key, None, name))
+                                .map(|a| String::from_utf8_lossy(&a.value).to_string());
+
+                            if attr_value(condkey).as_ref() == Some(condval) {
+                                if let Some(target) = attr_value(attrname) {
+                                    let target_mode = attr_value("TargetMode");
+                                    reporter.on_link(&Link {
+                                        src_file: src_file.to_string(), part: part.to_string(),
+                                        id: attr_value("Id"), rel_type: attr_value("Type"),
+                                        target,
+                                        external: target_mode.as_deref() == Some("External"),
+                                        target_mode,
+                                        text: None, paragraph: None
+                                    });
                                 }
                             }
                         }
@@ -790,77 +1389,85 @@ impl XMLUtil {
                 _ => (),
             }
         }
+        Ok(None)
     }
 
-    fn get_content_types(dir: &str)  -> (HashMap<String, String>, HashMap<String, String>) {
+    #[allow(clippy::type_complexity)]
+    fn get_content_types(pkg: &DocxPackage) -> DocxResult<(HashMap<String, String>, HashMap<String, String>)> {
         let mut defaults = HashMap::new();
         let mut mappings = HashMap::new();
 
-        let ct_file = Path::new(dir).join("[Content_Types].xml");
-        let mut reader = Reader::from_file(&ct_file).unwrap();
+        let part = "[Content_Types].xml";
+        let mut reader = Self::get_reader(pkg, part)?;
 
         let mut buf = Vec::new();
         let mut in_types = false;
 
         loop {
             match reader.read_event_into(&mut buf) {
-                Err(e) => panic!("Error reading {:?} at position {}: {:?}", ct_file, reader.buffer_position(), e),
+                Err(e) => return Err(DocxError::xml(part, reader.error_position(), e)),
                 Ok(Event::Eof) => break,
                 Ok(Event::Empty(e)) |
                 Ok(Event::Start(e)) => {
-                    if e.local_name().as_ref() == b"Types" {
+                    if Self::is_element(&reader, e.name(), CONTENT_TYPES_NS, "Types") {
                         in_types = true;
                         continue;
                     }
                     if in_types {
-                        match e.local_name().as_ref() {
+                        let (ns, local) = reader.resolve_element(e.name());
+                        if ns != ResolveResult::Bound(Namespace(CONTENT_TYPES_NS.as_bytes())) {
+                            continue;
+                        }
+                        match local.as_ref() {
                             b"Default" => {
                                 let en = e.try_get_attribute(b"Extension");
                                 let ct = e.try_get_attribute(b"ContentType");
 
-                                if let (Ok(e), Ok(c)) = (en, ct) {
-                                    if let (Some(ev), Some(cv)) = (e, c) {
-                                        defaults.insert(str::from_utf8(cv.value.as_ref()).unwrap().to_string(),
-                                            str::from_utf8(ev.value.as_ref()).unwrap().to_string());
-                                    }
+                                if let (Ok(Some(ev)), Ok(Some(cv))) = (en, ct) {
+                                    defaults.insert(String::from_utf8_lossy(cv.value.as_ref()).to_string(),
+                                        String::from_utf8_lossy(ev.value.as_ref()).to_string());
                                 }
                             },
                             b"Override" => {
                                 let pn = e.try_get_attribute(b"PartName");
                                 let ct = e.try_get_attribute(b"ContentType");
 
-                                if let (Ok(p), Ok(c)) = (pn, ct) {
-                                    if let (Some(pv), Some(cv)) = (p, c) {
-                                        let pn = str::from_utf8(pv.value.as_ref()).unwrap();
-                                        let rel_pn;
-                                        if pn.starts_with('/') {
-                                            rel_pn = &pn[1..];
-                                        } else {
-                                            rel_pn = pn;
-                                        }
-                                        mappings.insert(FileUtil::normalize_path(rel_pn),
-                                            str::from_utf8(cv.value.as_ref()).unwrap().to_string());
-                                    }
+                                if let (Ok(Some(pv)), Ok(Some(cv))) = (pn, ct) {
+                                    let pn = String::from_utf8_lossy(pv.value.as_ref());
+                                    let rel_pn = pn.strip_prefix('/').unwrap_or(&pn);
+                                    mappings.insert(rel_pn.to_string(),
+                                        String::from_utf8_lossy(cv.value.as_ref()).to_string());
                                 }
                             },
                             _ => ()
                         }
                     }
                 },
-                Ok(Event::End(e)) => {
-                    if e.local_name().as_ref() == b"Types" {
-                        in_types = false;
-                    }
-                }
+                Ok(Event::End(e)) if Self::is_element(&reader, e.name(), CONTENT_TYPES_NS, "Types") => {
+                    in_types = false;
+                },
                 _ => ()
             }
         }
 
-        (defaults, mappings)
+        Ok((defaults, mappings))
+    }
+
+    /// Obtain the content type of `part`, from its override or else from the default for its extension.
+    pub(crate) fn get_content_type(pkg: &DocxPackage, part: &str) -> DocxResult<Option<String>> {
+        let (defaults, mappings) = Self::get_content_types(pkg)?;
+        if let Some(ct) = mappings.get(part) {
+            return Ok(Some(ct.clone()));
+        }
+
+        let ext = part.rsplit_once('.').map(|(_, e)| e).unwrap_or("");
+        Ok(defaults.iter()
+            .find(|(_, e)| e.eq_ignore_ascii_case(ext))
+            .map(|(ct, _)| ct.clone()))
     }
 
-    fn get_files_with_content_type(dir: &str, content_type: &str) -> (HashMap<String, String>, Vec<String>) {
-        let (defaults, mappings) = Self::get_content_types(dir);
+    fn get_files_with_content_type(pkg: &DocxPackage, content_type: &str) -> DocxResult<(HashMap<String, String>, Vec<String>)> {
+        let (defaults, mappings) = Self::get_content_types(pkg)?;
 
         let mut result = vec!();
         for (file, ct) in &mappings {
@@ -868,116 +1475,97 @@ impl XMLUtil {
                 result.push(file.to_owned());
             }
         }
-        (defaults, result)
+        Ok((defaults, result))
     }
 
-    fn get_attribute(attrs: &mut Attributes, nslist: &Vec<String>, name: &str) -> String {
-        for attr in attrs {
-            if let Ok(a) = attr {
-                let key = a.key;
-                if Self::match_tag(&key, nslist, name) {
-                    let val = str::from_utf8(&a.value);
-                    if let Ok(v) = val {
-                        return v.to_string()
-                    }
+    /// Obtain the value of the WordprocessingML attribute `name` of the element `e` read by `reader`.
+    pub(crate) fn get_attribute(e: &BytesStart, reader: &NsReader<&[u8]>, name: &str, part: &str) -> DocxResult<String> {
+        for a in e.attributes().flatten() {
+            if Self::is_attribute(reader, a.key, Some(WORDDOC_NS), name) {
+                if let Ok(v) = a.unescape_value() {
+                    return Ok(v.to_string())
                 }
             }
         }
 
-        panic!("Attribute with name {} not found", name);
+        Err(DocxError::MissingAttribute {
+            part: part.to_string(),
+            element: String::from_utf8_lossy(e.name().as_ref()).to_string(),
+            attribute: name.to_string()
+        })
     }
 
     /// Returns a map from display name to styleId
-    fn get_all_styles(dir: &str, src_file: &str) -> HashMap<UniCase<String>, String> {
+    pub(crate) fn get_all_styles(pkg: &DocxPackage) -> DocxResult<HashMap<UniCase<String>, String>> {
         let mut style_map = HashMap::new();
 
-        let styles_file_name = &format!("{}{}", dir, "/word/styles.xml");
-        let styles_path = Path::new(styles_file_name);
-        let mut reader = Self::get_reader(&styles_path);
+        let part = "word/styles.xml";
+        let mut reader = Self::get_reader(pkg, part)?;
 
         let mut buf = Vec::new();
-        let mut nslist = vec!(WORDDOC_NS.to_string());
         let mut inside_style = String::new();
-        let mut first_element = true;
         loop {
             match reader.read_event_into(&mut buf) {
-                Err(e) => panic!("Error reading {} from {} at position {}: {:?}",
-                    styles_file_name, src_file, reader.buffer_position(), e),
+                Err(e) => return Err(DocxError::xml(part, reader.error_position(), e)),
                 Ok(Event::Eof) => break,
-                Ok(Event::Empty(e)) => {
-                    if inside_style.len() > 0 && Self::match_tag(&e.name(), &nslist, "name") {
-                        let display_name = Self::get_attribute(&mut e.attributes(), &nslist, "val");
-                        style_map.insert(UniCase::new(display_name), inside_style.to_owned());
-                    }
+                Ok(Event::Empty(e)) if !inside_style.is_empty() && Self::is_w(&reader, e.name(), "name") => {
+                    let display_name = Self::get_attribute(&e, &reader, "val", part)?;
+                    style_map.insert(UniCase::new(display_name), inside_style.to_owned());
                 },
                 Ok(Event::Start(e)) => {
-                    if first_element {
-                        first_element = false;
-                        Self::read_namespaces(&e, &mut nslist);
-                    }
-
-                    if Self::match_tag(&e.name(), &nslist, "style") {
-                        let style_id = Self::get_attribute(&mut e.attributes(), &nslist, "styleId");
+                    if Self::is_w(&reader, e.name(), "style") {
+                        let style_id = Self::get_attribute(&e, &reader, "styleId", part)?;
                         inside_style.clear();
                         inside_style.push_str(style_id.as_str());
-                    } else if inside_style.len() > 0 && Self::match_tag(&e.name(), &nslist, "name") {
-                        let display_name = Self::get_attribute(&mut e.attributes(), &nslist, "val");
+                    } else if !inside_style.is_empty() && Self::is_w(&reader, e.name(), "name") {
+                        let display_name = Self::get_attribute(&e, &reader, "val", part)?;
                         style_map.insert(UniCase::new(display_name), inside_style.to_owned());
                     }
                 },
-                Ok(Event::End(e)) => {
-                    if Self::match_tag(&e.name(), &nslist, "style") {
-                        inside_style.clear();
-                    }
-                }
+                Ok(Event::End(e)) if Self::is_w(&reader, e.name(), "style") => {
+                    inside_style.clear();
+                },
                 _ => ()
             }
         }
 
-        style_map
+        Ok(style_map)
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use super::XMLUtil;
-    use serial_test::serial;
-    use std::{fs, io};
-    use std::path::Path;
-    use testdir::testdir;
-
-    // Macro to wrap around any statement to capture stdout.
-    // Note tests using this need to be annotated with #[serial] as multiple concurrent
-    // redirections of stdout fail.
-    macro_rules! capture_stdout {
-        ($test:expr) => {{
-            use gag::BufferRedirect;
-            use std::io::Read;
-
-            let mut buf = BufferRedirect::stdout().unwrap();
-
-            $test;
-
-            let mut output = String::new();
-            buf.read_to_string(&mut output).unwrap();
-            drop(buf);
-
-            output
-        }};
+    use super::{StoryType, XMLUtil, HYPERLINK_REL_TYPE};
+    use crate::revision_util::{RevisionFilter, RevisionUtil, TrackChanges};
+    use regex::{NoExpand, Regex};
+    use crate::docx_package::DocxPackage;
+    use crate::error::{DocxError, DocxResult};
+    use crate::reporter::{CollectingReporter, Paragraph};
+    use crate::test_util::part_str;
+    use std::fs;
+
+    fn texts(paras: &[Paragraph]) -> String {
+        paras.iter().map(|p| format!("{}: {}\n", p.src_file, p.text)).collect()
     }
 
     #[test]
-    #[serial] // This test has to run serially to avoid multiple tests to capture stdout
-    fn test_cat() {
-        let out = capture_stdout!(XMLUtil::cat("./src/test/test_tree2", "my-file.docx"));
+    fn test_cat() -> DocxResult<()> {
+        let mut rep = CollectingReporter::default();
+        XMLUtil::cat(&DocxPackage::from_dir("./src/test/test_tree2")?, "my-file.docx", &mut rep)?;
+
+        let out = texts(&rep.paragraphs);
         assert!(out.contains("my-file.docx: Testing 123"));
         assert!(out.contains("my-file.docx: Here’s a hyperlink:"));
+        assert_eq!("word/document.xml", rep.paragraphs[0].part);
+        Ok(())
     }
 
     #[test]
-    #[serial]
-    fn test_cat2() {
-        let out = capture_stdout!(XMLUtil::cat("./src/test/test_tree5", "wordbreak.docx"));
+    fn test_cat2() -> DocxResult<()> {
+        let mut rep = CollectingReporter::default();
+        XMLUtil::cat(&DocxPackage::from_dir("./src/test/test_tree5")?, "wordbreak.docx", &mut rep)?;
+
+        let out = texts(&rep.paragraphs);
         let expected =
             "wordbreak.docx: Notwithstanding the eventual resulting quotations punters were agreeable to a technocratic compromise.".to_string()
             + super::LINE_ENDING + "Here’s another line of text.";
@@ -985,39 +1573,91 @@ mod tests {
         let idx2 = out.find("wordbreak.docx: And this text is in the next paragraph.").unwrap();
 
         assert!(idx1 < idx2);
+        for (i, p) in rep.paragraphs.iter().enumerate() {
+            assert_eq!(i, p.index);
+        }
+        Ok(())
     }
 
     #[test]
-    #[serial] // This test has to run serially to avoid multiple tests to capture stdout
-    fn test_grep() {
-        let out = capture_stdout!(XMLUtil::grep_xml("./src/test/test_tree2", "doc123.docx", "[oe]re"));
+    fn test_grep() -> DocxResult<()> {
+        let mut rep = CollectingReporter::default();
+        XMLUtil::grep_xml(&DocxPackage::from_dir("./src/test/test_tree2")?, "doc123.docx", "[oe]re", &mut rep)?;
+
+        let out = texts(&rep.matches);
         assert!(out.contains("doc123.docx: And some some some more text"));
         assert!(out.contains("doc123.docx: Something here"));
         assert!(out.contains("doc123.docx: Here’s a hyperlink:"));
         assert!(out.contains("doc123.docx: And here’s just some text:"));
         assert!(!out.contains("Target"));
+        assert!(rep.paragraphs.is_empty());
+        Ok(())
     }
 
     #[test]
-    #[serial]
-    fn test_links() {
-        let out = capture_stdout!(
-            XMLUtil::cat_rel_attr (
-                "Relationship", "Target",
-                "Type", "http://schemas.openxmlformats.org/officeDocument/2006/relationships/hyperlink",
-                "./src/test/test_tree4", "testing789.docx"));
+    fn test_grep_matches() -> DocxResult<()> {
+        let pkg = DocxPackage::from_dir("./src/test/test_tree2")?;
+        let matches = XMLUtil::grep_matches(&pkg, "hyper(li)nk")?;
+
+        assert_eq!(1, matches.len());
+        let m = &matches[0];
+        assert_eq!("word/document.xml", m.part);
+        assert!(m.text.starts_with("Here’s a hyperlink:"));
+        assert_eq!("hyperlink", &m.text[m.byte_range.clone()]);
+        assert_eq!(11..20, m.byte_range, "’ takes 3 bytes");
+        assert_eq!(9..18, m.char_range);
+        assert_eq!(vec![Some("hyperlink".to_string()), Some("li".to_string())], m.groups);
+
+        let all = XMLUtil::grep_matches(&pkg, "[Ss]ome")?;
+        let para = all.iter().find(|m| m.text == "And some some some more text and then some").unwrap().paragraph;
+        assert_eq!(1, para);
+        assert_eq!(4, all.iter().filter(|m| m.paragraph == para).count());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_grep_matches_style() -> DocxResult<()> {
+        let pkg = DocxPackage::from_dir("./src/test/test_tree6")?;
+        let matches = XMLUtil::grep_matches(&pkg, ".+")?;
+
+        let headings: Vec<&str> = matches.iter()
+            .filter(|m| m.style == Some("Heading1".to_string()))
+            .map(|m| m.text.as_str())
+            .collect();
+        assert_eq!(vec!["Head 1", "Head 1 again"], headings);
+        assert!(matches.iter().any(|m| m.style.is_none()));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_links() -> DocxResult<()> {
+        let mut rep = CollectingReporter::default();
+        XMLUtil::cat_rel_attr (
+            "Relationship", "Target",
+            "Type", "http://schemas.openxmlformats.org/officeDocument/2006/relationships/hyperlink",
+            &DocxPackage::from_dir("./src/test/test_tree4")?, "testing789.docx", &mut rep)?;
+
+        let out: String = rep.links.iter().map(|l| format!("{}: {}\n", l.src_file, l.target)).collect();
         assert!(out.contains("testing789.docx: http://www.example.com/somewhere"));
         assert!(out.contains("testing789.docx: https://www.example.com/somewhere"));
         assert!(out.contains("testing789.docx: file://www.example.com/infosheet.pdf"));
-        assert!(!out.contains("Target=webSettings.xml"))
+        assert!(!out.contains("webSettings.xml"));
+        assert!(rep.links.iter().all(|l| l.part == "word/_rels/document.xml.rels"));
+
+        let link = rep.links.iter().find(|l| l.id.as_deref() == Some("rId13")).unwrap();
+        assert_eq!("file://www.example.com/infosheet.pdf", link.target);
+        assert_eq!(Some("http://schemas.openxmlformats.org/officeDocument/2006/relationships/hyperlink"),
+            link.rel_type.as_deref());
+        assert_eq!(Some("External"), link.target_mode.as_deref());
+        Ok(())
     }
 
     #[test]
-    fn test_replace_shorten() -> io::Result<()> {
+    fn test_replace_shorten() -> DocxResult<()> {
         let orgdir = "./src/test/test_tree2";
-        let testdir = testdir!();
-
-        copy_dir_all(orgdir, &testdir)?;
+        let mut pkg = DocxPackage::from_dir(orgdir)?;
 
         let before = fs::read_to_string("./src/test/test_tree2/word/document.xml")?;
         assert!(before.contains("And some some some more text"), "Precondition");
@@ -1027,12 +1667,11 @@ mod tests {
         assert!(before.contains(">Some <"), "Precondition");
         assert!(!before.contains("zzz"), "Precondition");
 
-        XMLUtil::replace_xml(&testdir.to_string_lossy(), "my-source.docx",
-            "[Ss]ome", "zzz",
-            &Some(&testdir.join("output.docx").to_string_lossy()));
+        XMLUtil::replace_xml(&mut pkg, "my-source.docx",
+            "[Ss]ome", "zzz", &mut CollectingReporter::default())?;
 
         // Check that the replacement worked as expected
-        let after = fs::read_to_string(testdir.join("word/document.xml"))?;
+        let after = part_str(&pkg, "word/document.xml");
         assert!(after.contains("And zzz zzz zzz more text"));
         assert!(after.contains("and then zzz"));
         assert!(after.contains("zzzthing here"));
@@ -1044,11 +1683,9 @@ mod tests {
     }
 
     #[test]
-    fn test_replace_make_longer() -> io::Result<()> {
+    fn test_replace_make_longer() -> DocxResult<()> {
         let orgdir = "./src/test/test_tree2";
-        let testdir = testdir!();
-
-        copy_dir_all(orgdir, &testdir)?;
+        let mut pkg = DocxPackage::from_dir(orgdir)?;
 
         let before = fs::read_to_string("./src/test/test_tree2/word/document.xml")?;
         assert!(before.contains("And some some some more text"), "Precondition");
@@ -1058,12 +1695,11 @@ mod tests {
         assert!(before.contains(">Some <"), "Precondition");
         assert!(!before.contains("zzz"), "Precondition");
 
-        XMLUtil::replace_xml(&testdir.to_string_lossy(), "my-source.docx",
-            "[Ss]ome", "ABCDEF",
-            &Some(&testdir.join("output.docx").to_string_lossy()));
+        XMLUtil::replace_xml(&mut pkg, "my-source.docx",
+            "[Ss]ome", "ABCDEF", &mut CollectingReporter::default())?;
 
         // Check that the replacement worked as expected
-        let after = fs::read_to_string(testdir.join("word/document.xml"))?;
+        let after = part_str(&pkg, "word/document.xml");
         assert!(after.contains("And ABCDEF ABCDEF ABCDEF more text"));
         assert!(after.contains("and then ABCDEF"));
         assert!(after.contains("ABCDEFthing here"));
@@ -1075,11 +1711,9 @@ mod tests {
     }
 
     #[test]
-    fn test_replace_across_tags() -> io::Result<()> {
+    fn test_replace_across_tags() -> DocxResult<()> {
         let orgdir = "./src/test/test_tree5";
-        let testdir = testdir!();
-
-        copy_dir_all(orgdir, &testdir)?;
+        let mut pkg = DocxPackage::from_dir(orgdir)?;
 
         let before = fs::read_to_string("./src/test/test_tree5/word/document.xml")?;
         assert!(before.contains("re"), "Precondition");
@@ -1087,25 +1721,31 @@ mod tests {
         assert!(before.contains("ing"), "Precondition");
         assert!(!before.contains("resulting"), "Precondition");
 
-        XMLUtil::replace_xml(&testdir.to_string_lossy(), "acrstags.docx",
-            "resulting", "1234567890",
-            &Some(&testdir.join("output.docx").to_string_lossy()));
+        let mut rep = CollectingReporter::default();
+        XMLUtil::replace_xml(&mut pkg, "acrstags.docx",
+            "resulting", "1234567890", &mut rep)?;
 
-        let after = fs::read_to_string(testdir.join("word/document.xml"))?;
+        let after = part_str(&pkg, "word/document.xml");
         assert!(after.contains("eventual 12<"));
         assert!(after.contains(">3456<"));
         assert!(after.contains(">7890 quotations"));
         assert!(!after.contains("1234567890"));
 
+        // The replacement spans 3 tags but is reported once, for the whole paragraph
+        assert_eq!(1, rep.replacements.len());
+        let repl = &rep.replacements[0];
+        assert_eq!(None, repl.attribute);
+        assert_eq!(Some(0), repl.paragraph);
+        assert!(repl.old.contains("eventual resulting quotations"), "{}", repl.old);
+        assert!(repl.new.contains("eventual 1234567890 quotations"), "{}", repl.new);
+
         Ok(())
     }
 
     #[test]
-    fn test_replace_across_tags0() -> io::Result<()> {
+    fn test_replace_across_tags0() -> DocxResult<()> {
         let orgdir = "./src/test/test_tree5";
-        let testdir = testdir!();
-
-        copy_dir_all(orgdir, &testdir)?;
+        let mut pkg = DocxPackage::from_dir(orgdir)?;
 
         let before = fs::read_to_string("./src/test/test_tree5/word/document.xml")?;
         assert!(before.contains("re"), "Precondition");
@@ -1113,11 +1753,10 @@ mod tests {
         assert!(before.contains("ing"), "Precondition");
         assert!(!before.contains("resulting"), "Precondition");
 
-        XMLUtil::replace_xml(&testdir.to_string_lossy(), "acrstags.docx",
-            "resulting", "1",
-            &Some(&testdir.join("output.docx").to_string_lossy()));
+        XMLUtil::replace_xml(&mut pkg, "acrstags.docx",
+            "resulting", "1", &mut CollectingReporter::default())?;
 
-        let after = fs::read_to_string(testdir.join("word/document.xml"))?;
+        let after = part_str(&pkg, "word/document.xml");
         assert!(after.contains("eventual 1<"));
         assert!(after.contains("><"));
         assert!(after.contains("> quotations"));
@@ -1126,11 +1765,9 @@ mod tests {
     }
 
     #[test]
-    fn test_replace_across_tags1() -> io::Result<()> {
+    fn test_replace_across_tags1() -> DocxResult<()> {
         let orgdir = "./src/test/test_tree5";
-        let testdir = testdir!();
-
-        copy_dir_all(orgdir, &testdir)?;
+        let mut pkg = DocxPackage::from_dir(orgdir)?;
 
         let before = fs::read_to_string("./src/test/test_tree5/word/document.xml")?;
         assert!(before.contains("re"), "Precondition");
@@ -1138,11 +1775,10 @@ mod tests {
         assert!(before.contains("ing"), "Precondition");
         assert!(!before.contains("resulting"), "Precondition");
 
-        XMLUtil::replace_xml(&testdir.to_string_lossy(), "acrstags.docx",
-            "resulting", "123",
-            &Some(&testdir.join("output.docx").to_string_lossy()));
+        XMLUtil::replace_xml(&mut pkg, "acrstags.docx",
+            "resulting", "123", &mut CollectingReporter::default())?;
 
-        let after = fs::read_to_string(testdir.join("word/document.xml"))?;
+        let after = part_str(&pkg, "word/document.xml");
         assert!(after.contains("eventual 12<"));
         assert!(after.contains(">3<"));
         assert!(after.contains("> quotations"));
@@ -1151,16 +1787,86 @@ mod tests {
     }
 
     #[test]
-    fn test_replace_across_tags2() -> io::Result<()> {
-        let orgdir = "./src/test/test_tree2";
-        let testdir = testdir!();
+    fn test_replace_empty_and_uneven_matches() -> DocxResult<()> {
+        // Empty matches insert the replacement, matches of different lengths shift the text after them
+        for pattern in ["^", "x*", "a|some more"] {
+            let mut pkg = DocxPackage::from_dir("./src/test/test_tree2")?;
+            let mut before = CollectingReporter::default();
+            XMLUtil::cat(&pkg, "my-source.docx", &mut before)?;
+
+            XMLUtil::replace_xml(&mut pkg, "my-source.docx", pattern, "XYZ", &mut CollectingReporter::default())?;
+
+            let mut after = CollectingReporter::default();
+            XMLUtil::cat(&pkg, "my-source.docx", &mut after)?;
+            let regex = Regex::new(pattern)?;
+            let expected: Vec<String> = before.paragraphs.iter()
+                .map(|p| regex.replace_all(&p.text, "XYZ").to_string())
+                .collect();
+            let texts: Vec<String> = after.paragraphs.into_iter().map(|p| p.text).collect();
+            assert_eq!(expected, texts, "{}", pattern);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_replace_literal() -> DocxResult<()> {
+        // Both ways of replacing insert the replacement as is, without expanding capture groups
+        let track = TrackChanges { author: "Reviewer".to_string(), date: "2024-03-01T12:00:00Z".to_string() };
+        for tracked in [false, true] {
+            let mut pkg = DocxPackage::from_dir("./src/test/test_tree2")?;
+            let mut before = CollectingReporter::default();
+            XMLUtil::cat(&pkg, "my-source.docx", &mut before)?;
+
+            let (pattern, replace) = (r"(\w)ome (\w+)", "$2-$1$$");
+            if tracked {
+                XMLUtil::replace_xml_tracked(&mut pkg, "my-source.docx", pattern, replace, &StoryType::ALL, &track,
+                    &mut CollectingReporter::default())?;
+            } else {
+                XMLUtil::replace_xml(&mut pkg, "my-source.docx", pattern, replace, &mut CollectingReporter::default())?;
+            }
 
-        copy_dir_all(orgdir, &testdir)?;
+            let mut after = CollectingReporter::default();
+            XMLUtil::cat(&pkg, "my-source.docx", &mut after)?;
+            assert_eq!("And $2-$1$$ $2-$1$$ text and then some", after.paragraphs[1].text, "tracked: {}", tracked);
+            let regex = Regex::new(pattern)?;
+            let expected: Vec<String> = before.paragraphs.iter()
+                .map(|p| regex.replace_all(&p.text, NoExpand(replace)).to_string())
+                .collect();
+            let texts: Vec<String> = after.paragraphs.into_iter().map(|p| p.text).collect();
+            assert_eq!(expected, texts, "tracked: {}", tracked);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_replace_after_line_break() -> DocxResult<()> {
+        let mut pkg = DocxPackage::from_dir("./src/test/test_tree5")?;
+
+        XMLUtil::replace_xml(&mut pkg, "acrstags.docx",
+            "resulting|another", "X", &mut CollectingReporter::default())?;
+
+        let after = part_str(&pkg, "word/document.xml");
+        assert!(after.contains("eventual X<"));
+        assert!(after.contains("Here’s X<"));
 
-        XMLUtil::replace_xml(&testdir.to_string_lossy(), "xyz.docx",
-            "(text and|then some)", "aaa", &None);
+        let mut rep = CollectingReporter::default();
+        XMLUtil::cat(&pkg, "acrstags.docx", &mut rep)?;
+        let expected = "eventual X quotations punters were agreeable to a technocratic compromise.".to_string()
+            + super::LINE_ENDING + "Here’s X line of text.";
+        assert!(rep.paragraphs[0].text.contains(&expected), "{}", rep.paragraphs[0].text);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_replace_across_tags2() -> DocxResult<()> {
+        let orgdir = "./src/test/test_tree2";
+        let mut pkg = DocxPackage::from_dir(orgdir)?;
 
-        let after = fs::read_to_string(testdir.join("word/document.xml"))?;
+        XMLUtil::replace_xml(&mut pkg, "xyz.docx",
+            "(text and|then some)", "aaa", &mut CollectingReporter::default())?;
+
+        let after = part_str(&pkg, "word/document.xml");
         assert!(after.contains("some more aaa</w:t"));
         assert!(after.contains("> aaa<"));
 
@@ -1168,16 +1874,14 @@ mod tests {
     }
 
     #[test]
-    fn test_replace_across_tags3() -> io::Result<()> {
+    fn test_replace_across_tags3() -> DocxResult<()> {
         let orgdir = "./src/test/test_tree2";
-        let testdir = testdir!();
-
-        copy_dir_all(orgdir, &testdir)?;
+        let mut pkg = DocxPackage::from_dir(orgdir)?;
 
-        XMLUtil::replace_xml(&testdir.to_string_lossy(), "xyz.docx",
-            "(text and|then some)", "bbbbb", &None);
+        XMLUtil::replace_xml(&mut pkg, "xyz.docx",
+            "(text and|then some)", "bbbbb", &mut CollectingReporter::default())?;
 
-        let after = fs::read_to_string(testdir.join("word/document.xml"))?;
+        let after = part_str(&pkg, "word/document.xml");
         assert!(after.contains("some more bbbb</w:t"));
         assert!(after.contains(">b bbbbb<"));
 
@@ -1185,11 +1889,9 @@ mod tests {
     }
 
     #[test]
-    fn test_replace_hyperlink() -> io::Result<()> {
+    fn test_replace_hyperlink() -> DocxResult<()> {
         let orgdir = "./src/test/test_tree2";
-        let testdir = testdir!();
-
-        copy_dir_all(orgdir, &testdir)?;
+        let mut pkg = DocxPackage::from_dir(orgdir)?;
 
         let before_doc = fs::read_to_string("./src/test/test_tree2/word/document.xml")?;
         let before = fs::read_to_string("./src/test/test_tree2/word/_rels/document.xml.rels")?;
@@ -1197,25 +1899,29 @@ mod tests {
         assert!(before.contains("Target=\"http://www.example.com/\""), "Precondition");
         assert!(before_doc.contains(">www.example.com<"), "Precondition");
 
-        XMLUtil::replace_rel_attr(&testdir.to_string_lossy(), "my-source.docx",
-            "www.example.com", "foobar.org",
-            &Some(&testdir.join("output-2.docx").to_string_lossy()));
+        let mut rep = CollectingReporter::default();
+        XMLUtil::replace_rel_attr(&mut pkg, "my-source.docx",
+            "www.example.com", "foobar.org", "hyperlink", &mut rep)?;
 
-        let after_doc = fs::read_to_string(testdir.join("word/document.xml"))?;
-        let after = fs::read_to_string(testdir.join("word/_rels/document.xml.rels"))?;
+        let after_doc = part_str(&pkg, "word/document.xml");
+        let after = part_str(&pkg, "word/_rels/document.xml.rels");
 
         assert!(after.contains("Target=\"http://foobar.org/\""));
         assert!(after_doc.contains(">www.example.com<"), "Should not have changed the document text");
 
+        assert_eq!(1, rep.replacements.len());
+        let repl = &rep.replacements[0];
+        assert_eq!(Some("Target".to_string()), repl.attribute);
+        assert_eq!("http://www.example.com/", repl.old);
+        assert_eq!("http://foobar.org/", repl.new);
+
         Ok(())
     }
 
     #[test]
-    fn test_replace_hyperlink2() -> io::Result<()> {
+    fn test_replace_hyperlink2() -> DocxResult<()> {
         let orgdir = "./src/test/test_tree7";
-        let testdir = testdir!();
-
-        copy_dir_all(orgdir, &testdir)?;
+        let mut pkg = DocxPackage::from_dir(orgdir)?;
 
         let before_doc = fs::read_to_string("./src/test/test_tree7/word/document2.xml")?;
         let before = fs::read_to_string("./src/test/test_tree7/word/_rels/document2.xml.rels")?;
@@ -1225,12 +1931,11 @@ mod tests {
         assert!(before.contains("Target=\"http://www.example.com/a/b\""), "Precondition");
         assert!(before_doc.contains(">www.example.com/a/b<"), "Precondition");
 
-        XMLUtil::replace_rel_attr(&testdir.to_string_lossy(), "my-source.docx",
-            "www.example.com/a$", "www.example.com/a/",
-            &Some(&testdir.join("output-2.docx").to_string_lossy()));
+        XMLUtil::replace_rel_attr(&mut pkg, "my-source.docx",
+            "www.example.com/a$", "www.example.com/a/", "hyperlink", &mut CollectingReporter::default())?;
 
-        let after_doc = fs::read_to_string(testdir.join("word/document2.xml"))?;
-        let after = fs::read_to_string(testdir.join("word/_rels/document2.xml.rels"))?;
+        let after_doc = part_str(&pkg, "word/document2.xml");
+        let after = part_str(&pkg, "word/_rels/document2.xml.rels");
 
         assert!(after.contains("Target=\"http://www.example.com/a/\""));
         assert!(after_doc.contains(">www.example.com/a<"), "Should not have changed the document text");
@@ -1241,12 +1946,59 @@ mod tests {
     }
 
     #[test]
-    fn test_replace_both() -> io::Result<()> {
-        let orgdir = "./src/test/test_tree3";
-        let testroot = testdir!();
-        let testdir = testroot.join("subdir");
+    fn test_replace_rel_type() -> DocxResult<()> {
+        let mut pkg = DocxPackage::from_dir("./src/test/test_tree12")?;
 
-        copy_dir_all(orgdir, &testdir)?;
+        // A pattern that matches every attribute only changes the targets of the hyperlinks
+        let mut rep = CollectingReporter::default();
+        XMLUtil::replace_rel_attr(&mut pkg, "my-source.docx", ".+", "https://example.org/", "hyperlink", &mut rep)?;
+
+        let after = part_str(&pkg, "word/_rels/document.xml.rels");
+        assert!(after.contains(r#"Id="rId3" Type="http://schemas.openxmlformats.org/officeDocument/2006/relationships/hyperlink" Target="https://example.org/""#), "{}", after);
+        assert!(after.contains(r#"Id="rId1" Type="http://schemas.openxmlformats.org/officeDocument/2006/relationships/styles" Target="styles.xml""#), "{}", after);
+        assert!(after.contains(r#"Target="media/image1.png""#), "{}", after);
+        assert_eq!(1, rep.replacements.len());
+
+        XMLUtil::replace_rel_attr(&mut pkg, "my-source.docx", "^media/", "images/",
+            "http://schemas.openxmlformats.org/officeDocument/2006/relationships/image", &mut CollectingReporter::default())?;
+        let after = part_str(&pkg, "word/_rels/document.xml.rels");
+        assert!(after.contains(r#"Target="images/image1.png""#), "{}", after);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_replace_rel_escaped() -> DocxResult<()> {
+        let mut pkg = DocxPackage::from_dir("./src/test/test_tree12")?;
+        let rels = part_str(&pkg, "word/_rels/document.xml.rels")
+            .replace("https://example.com/docs", "https://example.com/docs?a=1&amp;b=2");
+        pkg.set_part("word/_rels/document.xml.rels", rels.into_bytes());
+
+        // The pattern matches the value of the attribute with its entities resolved
+        let mut rep = CollectingReporter::default();
+        XMLUtil::replace_rel_attr(&mut pkg, "my-source.docx", "a=1&b=2$", "a=<1>&b=\"2\"", "hyperlink", &mut rep)?;
+        assert_eq!(1, rep.replacements.len());
+        assert_eq!("https://example.com/docs?a=1&b=2", rep.replacements[0].old);
+        assert_eq!("https://example.com/docs?a=<1>&b=\"2\"", rep.replacements[0].new);
+
+        let after = part_str(&pkg, "word/_rels/document.xml.rels");
+        assert!(after.contains(r#"Target="https://example.com/docs?a=&lt;1&gt;&amp;b=&quot;2&quot;""#), "{}", after);
+
+        let rels = XMLUtil::get_relationship_details(&pkg, "word/document.xml")?;
+        assert_eq!("https://example.com/docs?a=<1>&b=\"2\"", rels["rId3"].target);
+
+        let mut rep = CollectingReporter::default();
+        XMLUtil::replace_rel_attr(&mut pkg, "my-source.docx", "a=<1>&b=\"2\"", "a=1", "hyperlink", &mut rep)?;
+        let after = part_str(&pkg, "word/_rels/document.xml.rels");
+        assert!(after.contains(r#"Target="https://example.com/docs?a=1""#), "{}", after);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_replace_both() -> DocxResult<()> {
+        let orgdir = "./src/test/test_tree3";
+        let mut pkg = DocxPackage::from_dir(orgdir)?;
 
         let before = fs::read_to_string("./src/test/test_tree3/word/document2.xml")?;
         assert!(before.contains("And some some more text"), "Precondition");
@@ -1260,15 +2012,13 @@ mod tests {
         let before_rels = fs::read_to_string("./src/test/test_tree3/word/_rels/document2.xml.rels")?;
         assert!(before_rels.contains("Target=\"http://www.example.com/\""), "Precondition");
 
-        XMLUtil::replace_xml(&testdir.to_string_lossy(), "my-source.docx",
-            "[Ss]ome", "zzz",
-            &Some(&testroot.join("output.docx").to_string_lossy()));
-        XMLUtil::replace_rel_attr(&testdir.to_string_lossy(), "my-source.docx",
-            "www.example.com", "foobar.org",
-            &Some(&testroot.join("output-2.docx").to_string_lossy()));
+        XMLUtil::replace_xml(&mut pkg, "my-source.docx",
+            "[Ss]ome", "zzz", &mut CollectingReporter::default())?;
+        XMLUtil::replace_rel_attr(&mut pkg, "my-source.docx",
+            "www.example.com", "foobar.org", HYPERLINK_REL_TYPE, &mut CollectingReporter::default())?;
 
         // Check that the replacement worked as expected
-        let after = fs::read_to_string(testdir.join("word/document2.xml"))?;
+        let after = part_str(&pkg, "word/document2.xml");
         assert!(after.contains("And zzz zzz more text"));
         assert!(after.contains("and then zzz"));
         assert!(after.contains("zzzthing here"));
@@ -1277,28 +2027,31 @@ mod tests {
         assert!(!after.contains("some"));
         assert!(!after.contains("Some"));
 
-        let after_rels = fs::read_to_string(testdir.join("word/_rels/document2.xml.rels"))?;
+        let after_rels = part_str(&pkg, "word/_rels/document2.xml.rels");
         assert!(after_rels.contains("Target=\"http://foobar.org/\""));
 
         Ok(())
     }
 
     #[test]
-    fn test_change_style() -> io::Result<()> {
+    fn test_change_style() -> DocxResult<()> {
         let orgdir = "./src/test/test_tree6";
-        let testroot = testdir!();
-        let testdir = testroot.join("subdir");
-
-        copy_dir_all(orgdir, &testdir)?;
+        let mut pkg = DocxPackage::from_dir(orgdir)?;
 
         let before = fs::read_to_string("./src/test/test_tree6/word/document.xml")?;
         let match_indexes: Vec<_> = before.match_indices("Heading1").map(|(i, _)| i).collect();
 
         assert!(before.contains("Heading1"), "Precondition");
-        XMLUtil::change_style(&testdir.to_string_lossy(), "headings.docx",
-            "Heading 1", "Heading 3", &None);
+        let mut rep = CollectingReporter::default();
+        XMLUtil::change_style(&mut pkg, "headings.docx",
+            "Heading 1", "Heading 3", &mut rep)?;
+
+        let changed: Vec<_> = rep.replacements.iter()
+            .map(|r| (r.paragraph, r.old.as_str(), r.new.as_str()))
+            .collect();
+        assert_eq!(vec![(Some(0), "Heading1", "Heading3"), (Some(2), "Heading1", "Heading3")], changed);
 
-        let after = fs::read_to_string(testdir.join("word/document.xml"))?;
+        let after = part_str(&pkg, "word/document.xml");
 
         for idx in match_indexes {
             let new_val = &after[idx..idx+"Heading1".len()];
@@ -1309,18 +2062,174 @@ mod tests {
         Ok(())
     }
 
-    fn copy_dir_all(src: impl AsRef<Path>, dst: impl AsRef<Path>) -> io::Result<()> {
-        fs::create_dir_all(&dst)?;
-        for entry in fs::read_dir(src)? {
-            let entry = entry?;
-            let ty = entry.file_type()?;
-            if ty.is_dir() {
-                copy_dir_all(entry.path(), dst.as_ref().join(entry.file_name()))?;
-            } else {
-                fs::copy(entry.path(), dst.as_ref().join(entry.file_name()))?;
-            }
+    #[test]
+    fn test_change_style_unknown() -> DocxResult<()> {
+        let orgdir = "./src/test/test_tree6";
+        let mut pkg = DocxPackage::from_dir(orgdir)?;
+
+        let res = XMLUtil::change_style(&mut pkg, "headings.docx",
+            "Heading 1", "No Such Style", &mut CollectingReporter::default());
+
+        match res {
+            Err(DocxError::MissingStyle { style, known_styles }) => {
+                assert_eq!("No Such Style", style);
+                assert!(known_styles.contains(&"heading 1".to_string()));
+            },
+            _ => panic!("Expected a missing style error")
         }
+
         Ok(())
     }
-}
 
+    #[test]
+    fn test_invalid_regex() {
+        let res = XMLUtil::grep_xml(&DocxPackage::from_dir("./src/test/test_tree2").unwrap(), "doc123.docx", "[oe",
+            &mut CollectingReporter::default());
+        assert!(matches!(res, Err(DocxError::Regex(_))));
+    }
+
+    #[test]
+    fn test_malformed_xml() -> DocxResult<()> {
+        let orgdir = "./src/test/test_tree2";
+        let mut pkg = DocxPackage::from_dir(orgdir)?;
+        let content = part_str(&pkg, "word/document.xml");
+        pkg.set_part("word/document.xml", content.replace("</w:body>", "").into_bytes());
+
+        let res = XMLUtil::replace_xml(&mut pkg, "broken.docx",
+            "[Ss]ome", "zzz", &mut CollectingReporter::default());
+
+        match res {
+            Err(DocxError::Xml { part, position, .. }) => {
+                assert_eq!("word/document.xml", part);
+                assert!(position > 0);
+            },
+            _ => panic!("Expected an XML error")
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_namespaces() -> DocxResult<()> {
+        // The document uses a default namespace, redeclares the namespace with another prefix
+        // and contains elements with the same local names in another namespace
+        let mut pkg = DocxPackage::from_dir("./src/test/test_tree8")?;
+
+        let mut rep = CollectingReporter::default();
+        XMLUtil::cat(&pkg, "ns.docx", &mut rep)?;
+        let paras: Vec<_> = rep.paragraphs.iter().map(|p| (p.text.as_str(), p.style.as_deref())).collect();
+        assert_eq!(vec![("Default namespace heading", Some("Heading1")), ("Redeclared prefix text", None),
+            ("Prefixed text", None)], paras);
+
+        let mut rep = CollectingReporter::default();
+        XMLUtil::cat_rel_attr("Relationship", "Target",
+            "Type", "http://schemas.openxmlformats.org/officeDocument/2006/relationships/hyperlink",
+            &pkg, "ns.docx", &mut rep)?;
+        let targets: Vec<_> = rep.links.iter().map(|l| l.target.as_str()).collect();
+        assert_eq!(vec!["https://www.example.com/"], targets);
+
+        XMLUtil::replace_xml(&mut pkg, "ns.docx", "Redeclared prefix", "Changed",
+            &mut CollectingReporter::default())?;
+        XMLUtil::change_style(&mut pkg, "ns.docx", "Heading 1", "Heading 2",
+            &mut CollectingReporter::default())?;
+
+        let after = part_str(&pkg, "word/document.xml");
+        assert!(after.contains("<wx:t>Changed</wx:t>"), "{}", after);
+        assert!(after.contains("<wx:t> text</wx:t>"), "{}", after);
+        assert!(after.contains(r#"<pStyle w:val="Heading2"/>"#), "{}", after);
+        assert!(after.contains("<o:t>Not a WordprocessingML paragraph</o:t>"), "{}", after);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_story_parts() -> DocxResult<()> {
+        let mut pkg = DocxPackage::from_dir("./src/test/test_tree9")?;
+
+        let mut rep = CollectingReporter::default();
+        XMLUtil::replace_xml_parts(&mut pkg, "stories.docx", "Draft", "Final",
+            &[StoryType::Header, StoryType::Footnotes], &mut rep)?;
+        let parts: Vec<_> = rep.replacements.iter().map(|r| r.part.as_str()).collect();
+        assert_eq!(vec!["word/footnotes.xml", "word/header1.xml"], parts);
+        assert!(part_str(&pkg, "word/document.xml").contains("Draft title"));
+        assert!(part_str(&pkg, "word/footer1.xml").contains("Draft footer"));
+
+        let mut rep = CollectingReporter::default();
+        XMLUtil::replace_xml(&mut pkg, "stories.docx", "Draft", "Final", &mut rep)?;
+        let parts: Vec<_> = rep.replacements.iter().map(|r| r.part.as_str()).collect();
+        assert_eq!(vec!["word/comments.xml", "word/document.xml", "word/document.xml", "word/footer1.xml"], parts);
+        for part in ["word/document.xml", "word/header1.xml", "word/footer1.xml", "word/footnotes.xml", "word/comments.xml"] {
+            assert!(!part_str(&pkg, part).contains("Draft"), "{}", part);
+        }
+
+        let mut rep = CollectingReporter::default();
+        XMLUtil::change_style_parts(&mut pkg, "stories.docx", "Heading 1", "Heading 2",
+            &[StoryType::Header], &mut rep)?;
+        let parts: Vec<_> = rep.replacements.iter().map(|r| r.part.as_str()).collect();
+        assert_eq!(vec!["word/header1.xml"], parts);
+        assert!(part_str(&pkg, "word/document.xml").contains(r#"w:val="Heading1""#));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_replace_tracked() -> DocxResult<()> {
+        let mut pkg = DocxPackage::from_dir("./src/test/test_tree5")?;
+        let regex = Regex::new("resulting|another")?;
+
+        let mut rep = CollectingReporter::default();
+        XMLUtil::cat(&pkg, "tracked.docx", &mut rep)?;
+        let original: Vec<String> = rep.paragraphs.into_iter().map(|p| p.text).collect();
+        let expected: Vec<String> = original.iter().map(|t| regex.replace_all(t, "X").to_string()).collect();
+        assert_ne!(original, expected, "Precondition");
+
+        let track = TrackChanges { author: "Reviewer".to_string(), date: "2024-03-01T12:00:00Z".to_string() };
+        let mut rep = CollectingReporter::default();
+        XMLUtil::replace_xml_tracked(&mut pkg, "tracked.docx", "resulting|another", "X",
+            &StoryType::ALL, &track, &mut rep)?;
+        assert_eq!(1, rep.replacements.len());
+        assert_eq!(Some(0), rep.replacements[0].paragraph);
+
+        let after = part_str(&pkg, "word/document.xml");
+        assert!(after.contains(r#"w:author="Reviewer" w:date="2024-03-01T12:00:00Z""#), "{}", after);
+        assert!(after.contains(r#"<w:delText xml:space="preserve">re</w:delText>"#), "{}", after);
+        assert!(after.contains(r#"<w:delText xml:space="preserve">sult</w:delText>"#), "{}", after);
+        assert!(after.contains(r#"<w:t xml:space="preserve">X</w:t>"#), "{}", after);
+        assert!(after.contains(r#"<w:t xml:space="preserve">Notwithstanding the eventual </w:t>"#), "{}", after);
+        assert!(after.matches("<w:lang w:val=\"en-US\"/>").count() > before_lang_count(), "{}", after);
+
+        // The deleted text is not part of the text anymore, but it can be restored by rejecting the changes
+        let mut rep = CollectingReporter::default();
+        XMLUtil::cat(&pkg, "tracked.docx", &mut rep)?;
+        assert_eq!(expected, rep.paragraphs.iter().map(|p| p.text.clone()).collect::<Vec<_>>());
+
+        let mut accepted = DocxPackage::from_dir("./src/test/test_tree5")?;
+        accepted.set_part("word/document.xml", after.clone().into_bytes());
+        RevisionUtil::accept_changes(&mut accepted, "tracked.docx", &RevisionFilter::default(),
+            &mut CollectingReporter::default())?;
+        let mut rep = CollectingReporter::default();
+        XMLUtil::cat(&accepted, "tracked.docx", &mut rep)?;
+        assert_eq!(expected, rep.paragraphs.iter().map(|p| p.text.clone()).collect::<Vec<_>>());
+
+        RevisionUtil::reject_changes(&mut pkg, "tracked.docx", &RevisionFilter::default(),
+            &mut CollectingReporter::default())?;
+        let mut rep = CollectingReporter::default();
+        XMLUtil::cat(&pkg, "tracked.docx", &mut rep)?;
+        assert_eq!(original, rep.paragraphs.iter().map(|p| p.text.clone()).collect::<Vec<_>>());
+
+        Ok(())
+    }
+
+    fn before_lang_count() -> usize {
+        fs::read_to_string("./src/test/test_tree5/word/document.xml").unwrap()
+            .matches("<w:lang w:val=\"en-US\"/>").count()
+    }
+
+    #[test]
+    fn test_resolve_target() {
+        assert_eq!("word/header1.xml", XMLUtil::resolve_target("word/document.xml", "header1.xml"));
+        assert_eq!("word/header1.xml", XMLUtil::resolve_target("word/document.xml", "./header1.xml"));
+        assert_eq!("media/image1.png", XMLUtil::resolve_target("word/document.xml", "../media/image1.png"));
+        assert_eq!("word/footnotes.xml", XMLUtil::resolve_target("word/document.xml", "/word/footnotes.xml"));
+    }
+}
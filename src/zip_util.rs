@@ -1,34 +1,194 @@
 use std::fs;
-use std::io::{self, Read, Write, Seek};
+use std::collections::{HashMap, HashSet};
+use std::io::{self, Read, Write, Seek, SeekFrom};
+use std::ops::Range;
 use std::path::Path;
 use std::io::Cursor;
 use zip::result::ZipError;
 use zip::write::SimpleFileOptions;
-use walkdir::{DirEntry, WalkDir};
+use zip::read::ZipFile;
+use zip::{CompressionMethod, DateTime, ZipArchive};
+use walkdir::WalkDir;
+
+use crate::error::{DocxError, DocxResult};
+use crate::file_util::FileUtil;
+
+/// An entry of a zip file held in memory, together with the metadata needed to write it back
+/// the way it was read.
+#[derive(Clone, Debug)]
+pub struct ZipEntry {
+    /// The name of the entry inside the zip file, always using `/` as separator. Directory
+    /// entries end with a `/`.
+    pub name: String,
+
+    /// The uncompressed content of the entry.
+    pub data: Vec<u8>,
+
+    pub compression: CompressionMethod,
+    pub last_modified: Option<DateTime>,
+    pub unix_mode: Option<u32>,
+
+    /// The comment of the entry.
+    pub comment: String,
+
+    /// The index of the entry in the zip file that it was read from, as long as its content is unchanged.
+    /// Such entries can be copied from that zip file as-is, without compressing them again.
+    pub source_index: Option<usize>,
+}
+
+impl ZipEntry {
+    /// Create a new entry with default metadata.
+    pub fn new(name: &str, data: Vec<u8>) -> Self {
+        ZipEntry {
+            name: name.to_string(),
+            data,
+            compression: CompressionMethod::Deflated,
+            last_modified: None,
+            unix_mode: None,
+            comment: String::new(),
+            source_index: None,
+        }
+    }
+
+    pub fn is_dir(&self) -> bool {
+        self.name.ends_with('/')
+    }
+}
+
+/// Limits that are applied when reading a zip file, to protect against malicious files such as zip bombs.
+/// Reading fails with `DocxError::ZipLimit` as soon as one of them is exceeded.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ZipLimits {
+    /// The maximum number of entries in the zip file.
+    pub max_entries: usize,
+
+    /// The maximum total uncompressed size of all entries, in bytes.
+    pub max_total_size: u64,
+
+    /// The maximum ratio between the uncompressed and compressed size of an entry. This is only checked
+    /// for entries of more than 1 MiB, as small entries can legitimately compress very well.
+    pub max_ratio: u64,
+}
+
+impl Default for ZipLimits {
+    fn default() -> Self {
+        ZipLimits {
+            max_entries: 10_000,
+            max_total_size: 1024 * 1024 * 1024,
+            max_ratio: 100,
+        }
+    }
+}
+
+impl ZipLimits {
+    /// No limits, for zip files that are trusted.
+    pub fn unlimited() -> Self {
+        ZipLimits {
+            max_entries: usize::MAX,
+            max_total_size: u64::MAX,
+            max_ratio: u64::MAX,
+        }
+    }
+}
+
+const RATIO_CHECK_MIN_SIZE: u64 = 1024 * 1024;
+
+const EOCD_SIG: u32 = 0x06054b50;
+const ZIP64_LOCATOR_SIG: u32 = 0x07064b50;
+const ZIP64_EOCD_SIG: u32 = 0x06064b50;
+const CD_SIG: u32 = 0x02014b50;
+
+/// The most memory reserved up front for an entry. The size declared in the zip file is not trusted, larger
+/// entries grow their buffer while they are read.
+const INITIAL_ENTRY_CAPACITY: u64 = 64 * 1024;
+
+/// The central directory of a zip file. `start` is its position in the file and `data` its content. `eocd_pos`
+/// is the position of the end of central directory record and `zip64_pos` that of the zip64 one, if present.
+struct CentralDirectory {
+    start: u64,
+    data: Vec<u8>,
+    eocd_pos: u64,
+    zip64_pos: Option<u64>,
+}
+
+impl CentralDirectory {
+    /// The ranges of the records of the entries in `data`.
+    fn records(&self) -> DocxResult<Vec<Range<usize>>> {
+        let mut records = Vec::new();
+        let mut pos = 0;
+        while pos + 46 <= self.data.len() && u32_at(&self.data, pos) == CD_SIG {
+            let extra_len = u16_at(&self.data, pos + 30) as usize;
+            let comment_len = u16_at(&self.data, pos + 32) as usize;
+            let end = pos + 46 + name_len(&self.data, pos) + extra_len + comment_len;
+            if end > self.data.len() {
+                return Err(invalid("Invalid central directory entry"));
+            }
+            records.push(pos..end);
+            pos = end;
+        }
+        Ok(records)
+    }
+}
+
+fn invalid(msg: &'static str) -> DocxError {
+    DocxError::Zip(ZipError::InvalidArchive(msg.into()))
+}
+
+fn u16_at(b: &[u8], i: usize) -> u16 {
+    u16::from_le_bytes([b[i], b[i + 1]])
+}
+
+fn u32_at(b: &[u8], i: usize) -> u32 {
+    u32::from_le_bytes([b[i], b[i + 1], b[i + 2], b[i + 3]])
+}
+
+fn u64_at(b: &[u8], i: usize) -> u64 {
+    u64::from_le_bytes(b[i..i + 8].try_into().unwrap())
+}
+
+/// The length of the name in the central directory record at `pos` in `cd`.
+fn name_len(cd: &[u8], pos: usize) -> usize {
+    u16_at(cd, pos + 28) as usize
+}
 
 pub struct ZipUtil {
 }
 
 impl ZipUtil {
+    /// Extract `zip_file` into `dest_dir`, applying the default `ZipLimits`. The names and comments
+    /// of the entries that have a comment are returned.
     pub fn read_zip(
         zip_file: &str,
         dest_dir: &str
-    ) -> zip::result::ZipResult<()> {
+    ) -> DocxResult<Vec<(String, String)>> {
+        Self::read_zip_with_limits(zip_file, dest_dir, &ZipLimits::default())
+    }
+
+    /// Extract `zip_file` into `dest_dir`, applying `limits`. The names and comments of the entries
+    /// that have a comment are returned.
+    pub fn read_zip_with_limits(
+        zip_file: &str,
+        dest_dir: &str,
+        limits: &ZipLimits
+    ) -> DocxResult<Vec<(String, String)>> {
         let fname = std::path::Path::new(zip_file);
         let file = fs::File::open(fname)?;
 
         let tname = std::path::Path::new(dest_dir);
 
-        Self::read_zip_file(file, tname)
+        Self::read_zip_file(file, tname, limits)
     }
 
     fn read_zip_file(
         file: fs::File,
-        temp_path: &Path
-    ) -> zip::result::ZipResult<()> {
-        let mut archive = zip::ZipArchive::new(file)?;
+        temp_path: &Path,
+        limits: &ZipLimits
+    ) -> DocxResult<Vec<(String, String)>> {
+        let mut archive = Self::open_archive(file, limits)?;
 
         let outpathbase = temp_path.to_owned();
+        let mut comments = Vec::new();
+        let mut total_size = 0;
 
         for i in 0..archive.len() {
             let mut file = archive.by_index(i)?;
@@ -39,30 +199,23 @@ impl ZipUtil {
 
             let outpath = outpathbase.join(outpathfn);
 
-            {
-                let comment = file.comment();
-                if !comment.is_empty() {
-                    println!("File {i} comment: {comment}");
-                }
+            if !file.comment().is_empty() {
+                comments.push((file.name().to_string(), file.comment().to_string()));
             }
 
             if (*file.name()).ends_with('/') {
-                // println!("File {} extracted to \"{}\"", i, outpath.display());
                 fs::create_dir_all(&outpath)?;
             } else {
-                // println!(
-                //     "File {} extracted to \"{}\" ({} bytes)",
-                //     i,
-                //     outpath.display(),
-                //     file.size()
-                // );
                 if let Some(p) = outpath.parent() {
                     if !p.exists() {
                         fs::create_dir_all(p)?;
                     }
                 }
                 let mut outfile = fs::File::create(&outpath)?;
-                io::copy(&mut file, &mut outfile)?;
+                let remaining = limits.max_total_size - total_size;
+                let size = io::copy(&mut (&mut file).take(remaining.saturating_add(1)), &mut outfile)?;
+                total_size += size;
+                Self::check_size(&file, size, total_size, limits)?;
             }
 
             // Get and Set permissions
@@ -76,26 +229,282 @@ impl ZipUtil {
             }
         }
 
+        Ok(comments)
+    }
+
+    /// Read all entries of the zip file provided by `reader` into memory, in the order in which they
+    /// appear in the zip file, applying the default `ZipLimits`. The comment of the zip file is returned
+    /// with the entries.
+    pub fn read_entries<R: Read + Seek>(reader: R) -> DocxResult<(Vec<ZipEntry>, Vec<u8>)> {
+        Self::read_entries_with_limits(reader, &ZipLimits::default())
+    }
+
+    /// Read all entries of the zip file provided by `reader` into memory, like `read_entries`,
+    /// applying `limits`.
+    pub fn read_entries_with_limits<R: Read + Seek>(reader: R, limits: &ZipLimits)
+            -> DocxResult<(Vec<ZipEntry>, Vec<u8>)> {
+        let mut archive = Self::open_archive(reader, limits)?;
+
+        let mut entries = Vec::with_capacity(archive.len());
+        let mut total_size = 0;
+        for i in 0..archive.len() {
+            let mut file = archive.by_index(i)?;
+
+            let remaining = limits.max_total_size - total_size;
+            let mut data = Vec::with_capacity(file.size().min(remaining).min(INITIAL_ENTRY_CAPACITY) as usize);
+            (&mut file).take(remaining.saturating_add(1)).read_to_end(&mut data)?;
+            total_size += data.len() as u64;
+            Self::check_size(&file, data.len() as u64, total_size, limits)?;
+
+            entries.push(ZipEntry {
+                name: file.name().to_string(),
+                data,
+                compression: file.compression(),
+                last_modified: file.last_modified(),
+                unix_mode: file.unix_mode(),
+                comment: file.comment().to_string(),
+                source_index: Some(i),
+            });
+        }
+
+        Ok((entries, archive.comment().to_vec()))
+    }
+
+    /// Open the zip file provided by `reader`, after checking that its entry names are unique and that
+    /// the number of entries is within `limits`.
+    fn open_archive<R: Read + Seek>(mut reader: R, limits: &ZipLimits) -> DocxResult<ZipArchive<R>> {
+        let start = reader.stream_position()?;
+        let names = Self::entry_names(&mut reader)?;
+        reader.seek(SeekFrom::Start(start))?;
+
+        if names.len() > limits.max_entries {
+            return Err(DocxError::ZipLimit(
+                format!("{} entries, the maximum is {}", names.len(), limits.max_entries)));
+        }
+
+        let mut seen = HashSet::new();
+        for name in names {
+            if !seen.insert(name.clone()) {
+                return Err(DocxError::DuplicateEntry(name));
+            }
+        }
+
+        Ok(ZipArchive::new(reader)?)
+    }
+
+    fn check_size<R: Read>(file: &ZipFile<R>, size: u64, total_size: u64, limits: &ZipLimits) -> DocxResult<()> {
+        if total_size > limits.max_total_size {
+            return Err(DocxError::ZipLimit(
+                format!("total uncompressed size exceeds the maximum of {} bytes", limits.max_total_size)));
+        }
+
+        if size > RATIO_CHECK_MIN_SIZE && size > file.compressed_size().saturating_mul(limits.max_ratio) {
+            return Err(DocxError::ZipLimit(
+                format!("{} has a compression ratio of more than {}", file.name(), limits.max_ratio)));
+        }
+
+        Ok(())
+    }
+
+    /// Obtain the names of all the entries in the central directory of the zip file. These are read
+    /// directly, as `ZipArchive` only keeps one entry for names that occur more than once.
+    fn entry_names<R: Read + Seek>(reader: &mut R) -> DocxResult<Vec<String>> {
+        let dir = Self::central_directory(reader)?;
+        Ok(dir.records()?.into_iter()
+            .map(|r| String::from_utf8_lossy(&dir.data[r.start + 46..r.start + 46 + name_len(&dir.data, r.start)]).to_string())
+            .collect())
+    }
+
+    /// Read the central directory of the zip file provided by `reader`.
+    fn central_directory<R: Read + Seek>(reader: &mut R) -> DocxResult<CentralDirectory> {
+        // The end of central directory record is at most 22 bytes + a 64 KiB comment from the end
+        let len = reader.seek(SeekFrom::End(0))?;
+        let tail_start = len.saturating_sub(22 + u16::MAX as u64);
+        let mut tail = Vec::new();
+        reader.seek(SeekFrom::Start(tail_start))?;
+        reader.read_to_end(&mut tail)?;
+
+        let eocd = (0..tail.len().saturating_sub(21)).rev()
+            .find(|&i| u32_at(&tail, i) == EOCD_SIG)
+            .ok_or(invalid("Could not find end of central directory"))?;
+        let eocd_pos = tail_start + eocd as u64;
+
+        let mut size = u32_at(&tail, eocd + 12) as u64;
+        let mut end = eocd_pos;
+        let mut zip64_pos = None;
+        if eocd >= 20 && u32_at(&tail, eocd - 20) == ZIP64_LOCATOR_SIG {
+            // Zip64, the sizes are in the zip64 end of central directory record that precedes the locator
+            let mut zip64_eocd = [0u8; 56];
+            let pos = u64_at(&tail, eocd - 12);
+            reader.seek(SeekFrom::Start(pos))?;
+            reader.read_exact(&mut zip64_eocd)?;
+            if u32_at(&zip64_eocd, 0) != ZIP64_EOCD_SIG {
+                return Err(invalid("Invalid zip64 end of central directory"));
+            }
+            size = u64_at(&zip64_eocd, 40);
+            end = pos;
+            zip64_pos = Some(pos);
+        }
+
+        let start = end.checked_sub(size).ok_or(invalid("Invalid central directory size"))?;
+        let mut data = Vec::new();
+        reader.seek(SeekFrom::Start(start))?;
+        reader.take(size).read_to_end(&mut data)?;
+
+        Ok(CentralDirectory { start, data, eocd_pos, zip64_pos })
+    }
+
+    /// Add the comments of `entries` to the central directory of `zip`, a zip file written by `ZipWriter`,
+    /// which has no option to set the comment of an entry.
+    fn set_entry_comments(zip: &mut Vec<u8>, entries: &[ZipEntry]) -> DocxResult<()> {
+        let comments: HashMap<&str, &str> = entries.iter()
+            .filter(|e| !e.comment.is_empty())
+            .map(|e| (e.name.as_str(), e.comment.as_str()))
+            .collect();
+        if comments.is_empty() {
+            return Ok(());
+        }
+
+        let dir = Self::central_directory(&mut Cursor::new(&*zip))?;
+        let mut cd = Vec::with_capacity(dir.data.len());
+        let mut pos = 0;
+        for r in dir.records()? {
+            let mut record = dir.data[r.clone()].to_vec();
+            let name = String::from_utf8_lossy(&record[46..46 + name_len(&record, 0)]).to_string();
+            if let (0, Some(comment)) = (u16_at(&record, 32), comments.get(name.as_str())) {
+                let comment_len = u16::try_from(comment.len()).map_err(|_| invalid("Entry comment too long"))?;
+                record[32..34].copy_from_slice(&comment_len.to_le_bytes());
+                if !comment.is_ascii() {
+                    // Mark the name and comment as UTF-8
+                    let flags = u16_at(&record, 8) | 0x0800;
+                    record[8..10].copy_from_slice(&flags.to_le_bytes());
+                }
+                record.extend_from_slice(comment.as_bytes());
+            }
+            cd.extend_from_slice(&record);
+            pos = r.end;
+        }
+        cd.extend_from_slice(&dir.data[pos..]);
+
+        // The records after the central directory hold its size and the position of the zip64 record
+        let delta = (cd.len() - dir.data.len()) as u64;
+        let cd_end = (dir.start + dir.data.len() as u64) as usize;
+        let mut tail = zip.split_off(cd_end);
+        zip.truncate(dir.start as usize);
+        let eocd = dir.eocd_pos as usize - cd_end;
+        let size = u32_at(&tail, eocd + 12);
+        if size != u32::MAX {
+            let size = u32::try_from(size as u64 + delta).map_err(|_| invalid("Central directory too large"))?;
+            tail[eocd + 12..eocd + 16].copy_from_slice(&size.to_le_bytes());
+        }
+        if let Some(zip64_pos) = dir.zip64_pos {
+            let zip64_eocd = zip64_pos as usize - cd_end;
+            let size = u64_at(&tail, zip64_eocd + 40) + delta;
+            tail[zip64_eocd + 40..zip64_eocd + 48].copy_from_slice(&size.to_le_bytes());
+            tail[eocd - 12..eocd - 4].copy_from_slice(&(zip64_pos + delta).to_le_bytes());
+        }
+        zip.extend_from_slice(&cd);
+        zip.extend_from_slice(&tail);
+        Ok(())
+    }
+
+    /// Write `entries` as a zip file with `comment` to `writer`. The entries are written in the order provided
+    /// and with their own compression method, modification time, permissions and comment. Entries compressed
+    /// with a method other than stored or deflated are written deflated.
+    pub fn write_entries<W: Write + Seek>(entries: &[ZipEntry], comment: &[u8], writer: W) -> DocxResult<()> {
+        Self::write_entries_from::<Cursor<&[u8]>, W>(entries, comment, None, writer)
+    }
+
+    /// Write `entries` as a zip file with `comment` to `writer`, like `write_entries`. The entries that
+    /// have a `source_index` are copied from the zip file provided by `source` in their compressed form,
+    /// so that they are not compressed again and stay exactly the same.
+    pub fn copy_entries<R: Read + Seek, W: Write + Seek>(entries: &[ZipEntry], comment: &[u8], source: R,
+            writer: W) -> DocxResult<()> {
+        let mut archive = zip::ZipArchive::new(source)?;
+        Self::write_entries_from(entries, comment, Some(&mut archive), writer)
+    }
+
+    fn write_entries_from<R: Read + Seek, W: Write + Seek>(entries: &[ZipEntry], comment: &[u8],
+            mut source: Option<&mut ZipArchive<R>>, mut writer: W) -> DocxResult<()> {
+        let mut data = Vec::new();
+        let mut zip = zip::ZipWriter::new(Cursor::new(&mut data));
+        zip.set_raw_comment(comment.into());
+
+        for entry in entries {
+            if let (Some(archive), Some(idx)) = (source.as_mut(), entry.source_index) {
+                zip.raw_copy_file(archive.by_index_raw(idx)?)?;
+                continue;
+            }
+
+            let method = match entry.compression {
+                CompressionMethod::Stored => CompressionMethod::Stored,
+                CompressionMethod::Deflated => CompressionMethod::Deflated,
+                // Other methods, such as Deflate64, can't always be written, the entry is deflated instead
+                _ => CompressionMethod::Deflated,
+            };
+            let mut options = SimpleFileOptions::default()
+                .compression_method(method)
+                .large_file(entry.data.len() as u64 >= u32::MAX as u64);
+            if let Some(lm) = entry.last_modified {
+                options = options.last_modified_time(lm);
+            }
+            if let Some(mode) = entry.unix_mode {
+                options = options.unix_permissions(mode);
+            }
+
+            if entry.is_dir() {
+                zip.add_directory(entry.name.as_str(), options)?;
+            } else {
+                zip.start_file(entry.name.as_str(), options)?;
+                zip.write_all(&entry.data)?;
+            }
+        }
+        zip.finish()?;
+
+        Self::set_entry_comments(&mut data, entries)?;
+        writer.write_all(&data)?;
         Ok(())
     }
 
+    /// Write the content of `src_dir` as a zip file to `dst_file`. `[Content_Types].xml` is written
+    /// first, as some consumers of .docx files require this, the other entries follow in the order of their names.
     pub fn write_zip(
         src_dir: &str,
         dst_file: &str,
-    ) -> zip::result::ZipResult<()> {
+    ) -> DocxResult<()> {
+        Self::write_dir(src_dir, None, dst_file)
+    }
+
+    /// Write the content of `src_dir`, which was extracted from the zip file `template`, as a zip file to
+    /// `dst_file`. The entries are written in the same order and with the same compression method, modification
+    /// time and permissions as in `template`, as well as its comment. Files that are unchanged are copied from
+    /// `template` without compressing them again. Files that are not in `template` are added at the end and
+    /// entries for which there is no file anymore are left out.
+    pub fn write_zip_with_template(
+        src_dir: &str,
+        template: &str,
+        dst_file: &str,
+    ) -> DocxResult<()> {
+        Self::write_dir(src_dir, Some(template), dst_file)
+    }
+
+    fn write_dir(
+        src_dir: &str,
+        template: Option<&str>,
+        dst_file: &str,
+    ) -> DocxResult<()> {
         if !Path::new(src_dir).is_dir() {
-            return Err(ZipError::FileNotFound);
+            return Err(ZipError::FileNotFound.into());
         }
 
+        let (entries, comment) = Self::dir_entries(src_dir, template)?;
+
         let path = Path::new(dst_file);
         let mut new_zip_data = Vec::new();
-        let mut cursor = Cursor::new(&mut new_zip_data);
-
-        let walkdir = WalkDir::new(src_dir);
-        let it = walkdir.into_iter();
-
-        Self::deflate(&mut it.filter_map(|e| e.ok()), src_dir, &mut cursor,
-                      zip::CompressionMethod::Deflated)?;
+        match template {
+            Some(t) => Self::copy_entries(&entries, &comment, fs::File::open(t)?, Cursor::new(&mut new_zip_data))?,
+            None => Self::write_entries(&entries, &comment, Cursor::new(&mut new_zip_data))?,
+        }
 
         if path.exists() {
             let mut existing_zip_data = Vec::new();
@@ -114,49 +523,83 @@ impl ZipUtil {
         Ok(())
     }
 
-    fn deflate<T>(
-        it: &mut dyn Iterator<Item = DirEntry>,
-        prefix: &str,
-        writer: T,
-        method: zip::CompressionMethod,
-    ) -> zip::result::ZipResult<()>
-    where
-        T: Write + Seek,
-    {
-        let mut zip = zip::ZipWriter::new(writer);
-        let options = SimpleFileOptions::default().compression_method(method).unix_permissions(0o755);
+    /// Obtain the zip entries for the content of `src_dir`, using the entries in `template` for their
+    /// order and metadata if provided.
+    fn dir_entries(src_dir: &str, template: Option<&str>) -> DocxResult<(Vec<ZipEntry>, Vec<u8>)> {
+        let mut dir_content = Vec::new();
+        for entry in WalkDir::new(src_dir).sort_by_file_name().into_iter().filter_map(|e| e.ok()) {
+            let name = FileUtil::get_sub_path(entry.path(), src_dir).replace('\\', "/");
+            if entry.file_type().is_dir() {
+                if entry.depth() > 0 {
+                    dir_content.push((name + "/", entry.into_path()));
+                }
+            } else {
+                dir_content.push((name, entry.into_path()));
+            }
+        }
 
-        let mut buffer = Vec::new();
-        for entry in it {
-            let path = entry.path();
-            let name = path.strip_prefix(Path::new(prefix)).unwrap();
+        let (template_entries, comment) = match template {
+            // The template is the file that src_dir was extracted from, the limits were applied then
+            Some(t) => Self::read_entries_with_limits(fs::File::open(t)?, &ZipLimits::unlimited())?,
+            None => (vec![], vec![]),
+        };
+
+        let mut entries = Vec::new();
+        for mut entry in template_entries {
+            let path = Path::new(src_dir).join(FileUtil::normalize_path(&entry.name));
+            if entry.is_dir() {
+                if path.is_dir() {
+                    entries.push(entry);
+                }
+            } else if path.is_file() {
+                let data = fs::read(path)?;
+                if data != entry.data {
+                    entry.data = data;
+                    entry.source_index = None;
+                }
+                entries.push(entry);
+            }
+        }
 
-            if path.is_file() {
-                zip.start_file_from_path(name, options)?;
-                let mut f = fs::File::open(path)?;
+        for (name, path) in dir_content {
+            if entries.iter().any(|e| e.name == name) {
+                continue;
+            }
 
-                f.read_to_end(&mut buffer)?;
-                zip.write_all(&buffer)?;
-                buffer.clear();
-            } else if !name.as_os_str().is_empty() {
-                zip.add_directory_from_path(name, options)?;
+            let new_entry = if name.ends_with('/') {
+                if template.is_some() {
+                    // Only keep the directory entries that were in the template
+                    continue;
+                }
+                ZipEntry::new(&name, vec![])
+            } else {
+                ZipEntry::new(&name, fs::read(path)?)
+            };
+
+            if name == "[Content_Types].xml" {
+                entries.insert(0, new_entry);
+            } else {
+                entries.push(new_entry);
             }
         }
-        zip.finish()?;
-        Ok(())
+
+        Ok((entries, comment))
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use crate::error::{DocxError, DocxResult};
     use crate::file_util::FileUtil;
-    use super::ZipUtil;
-    use std::{path::MAIN_SEPARATOR_STR, path::Path, fs, io};
+    use super::{ZipEntry, ZipLimits, ZipUtil};
+    use std::{path::MAIN_SEPARATOR_STR, path::Path, fs};
+    use std::io::Cursor;
+    use zip::{CompressionMethod, DateTime};
     use walkdir::WalkDir;
     use testdir::testdir;
 
     #[test]
-    fn test_unzip() -> io::Result<()> {
+    fn test_unzip() -> DocxResult<()> {
         let zipfile = "./src/test/test_zip.zip";
         let outdir = testdir!();
 
@@ -164,7 +607,7 @@ mod tests {
 
         let wd = WalkDir::new(&outdir);
         let extracts: Vec<String> = wd.into_iter()
-            .map(|e| FileUtil::get_sub_path(&e.unwrap().path(), &outdir.to_string_lossy()))
+            .map(|e| FileUtil::get_sub_path(e.unwrap().path(), &outdir.to_string_lossy()))
             .filter(|e| !e.starts_with(MAIN_SEPARATOR_STR))
             .filter(|e| e.contains('.'))
             .collect();
@@ -194,7 +637,7 @@ mod tests {
     }
 
     #[test]
-    fn test_zip() -> io::Result<()> {
+    fn test_zip() -> DocxResult<()> {
         let indir = "./src/test/test_tree1";
         let outdir = testdir!();
         let zipfile = outdir.join("out.zip");
@@ -208,7 +651,7 @@ mod tests {
         ZipUtil::read_zip(&zipfile.to_string_lossy(), &expldir.to_string_lossy())?;
 
         let extracts: Vec<String> = WalkDir::new(&expldir).into_iter()
-            .map(|e| FileUtil::get_sub_path(&e.unwrap().path(), &expldir.to_string_lossy()))
+            .map(|e| FileUtil::get_sub_path(e.unwrap().path(), &expldir.to_string_lossy()))
             .filter(|e| !e.starts_with(MAIN_SEPARATOR_STR))
             .filter(|e| e.contains('.'))
             .collect();
@@ -236,4 +679,166 @@ mod tests {
 
         Ok(())
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_zip_with_template() -> DocxResult<()> {
+        let outdir = testdir!();
+        let template = outdir.join("template.docx");
+        let time = DateTime::from_date_and_time(2020, 2, 29, 13, 45, 10).unwrap();
+
+        let mut entries = vec![
+            ZipEntry::new("[Content_Types].xml", b"<Types/>".to_vec()),
+            ZipEntry::new("word/document.xml", b"<document/>".to_vec()),
+            ZipEntry::new("docProps/app.xml", b"<Properties/>".to_vec()),
+            ZipEntry::new("removed.xml", b"<removed/>".to_vec()),
+        ];
+        for e in entries.iter_mut() {
+            e.last_modified = Some(time);
+            e.unix_mode = Some(0o100600);
+        }
+        entries[2].compression = CompressionMethod::Stored;
+        ZipUtil::write_entries(&entries, b"a comment", fs::File::create(&template)?)?;
+
+        let expldir = outdir.join("expl");
+        let expl = expldir.to_string_lossy();
+        ZipUtil::read_zip(&template.to_string_lossy(), &expl)?;
+        fs::write(expldir.join("word/document.xml"), "<document>changed</document>")?;
+        fs::write(expldir.join("word/added.xml"), "<added/>")?;
+        fs::remove_file(expldir.join("removed.xml"))?;
+
+        let zipfile = outdir.join("out.docx");
+        ZipUtil::write_zip_with_template(&expl, &template.to_string_lossy(), &zipfile.to_string_lossy())?;
+
+        let (written, comment) = ZipUtil::read_entries(fs::File::open(&zipfile)?)?;
+        assert_eq!(b"a comment".to_vec(), comment);
+
+        let names: Vec<_> = written.iter().map(|e| e.name.as_str()).collect();
+        assert_eq!(vec!["[Content_Types].xml", "word/document.xml", "docProps/app.xml", "word/added.xml"], names);
+        assert_eq!(b"<document>changed</document>".to_vec(), written[1].data);
+
+        for e in &written[..3] {
+            assert_eq!(Some(time), e.last_modified, "{}", e.name);
+            assert_eq!(Some(0o100600), e.unix_mode, "{}", e.name);
+        }
+        assert_eq!(CompressionMethod::Deflated, written[1].compression);
+        assert_eq!(CompressionMethod::Stored, written[2].compression);
+
+        Ok(())
+    }
+
+    fn zip_data(entries: &[ZipEntry]) -> DocxResult<Vec<u8>> {
+        let mut data = Vec::new();
+        ZipUtil::write_entries(entries, b"", Cursor::new(&mut data))?;
+        Ok(data)
+    }
+
+    #[test]
+    fn test_limits() -> DocxResult<()> {
+        let data = zip_data(&[
+            ZipEntry::new("a.xml", vec![b'a'; 1000]),
+            ZipEntry::new("b.xml", vec![b'b'; 1000]),
+            ZipEntry::new("zeros.bin", vec![0; 2 * 1024 * 1024]),
+        ])?;
+
+        let (entries, _) = ZipUtil::read_entries_with_limits(Cursor::new(&data), &ZipLimits::unlimited())?;
+        assert_eq!(3, entries.len());
+
+        let limits = ZipLimits { max_entries: 2, ..ZipLimits::unlimited() };
+        match ZipUtil::read_entries_with_limits(Cursor::new(&data), &limits) {
+            Err(DocxError::ZipLimit(msg)) => assert!(msg.contains("3 entries"), "{}", msg),
+            _ => panic!("Expected the entry limit to be exceeded"),
+        }
+
+        let limits = ZipLimits { max_total_size: 1024 * 1024, ..ZipLimits::unlimited() };
+        assert!(matches!(ZipUtil::read_entries_with_limits(Cursor::new(&data), &limits),
+            Err(DocxError::ZipLimit(_))));
+
+        // 2 MiB of zeros compresses by a factor of about 1000
+        match ZipUtil::read_entries(Cursor::new(&data)) {
+            Err(DocxError::ZipLimit(msg)) => assert!(msg.contains("zeros.bin"), "{}", msg),
+            _ => panic!("Expected the compression ratio limit to be exceeded"),
+        }
+
+        // The limits also apply when extracting
+        let outdir = testdir!();
+        let zipfile = outdir.join("bomb.zip");
+        fs::write(&zipfile, &data)?;
+        assert!(matches!(ZipUtil::read_zip(&zipfile.to_string_lossy(), &outdir.join("x").to_string_lossy()),
+            Err(DocxError::ZipLimit(_))));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_duplicate_entries() -> DocxResult<()> {
+        let mut data = zip_data(&[
+            ZipEntry::new("a.xml", b"<a/>".to_vec()),
+            ZipEntry::new("b.xml", b"<b/>".to_vec()),
+        ])?;
+
+        // Rename b.xml to a.xml in both the local header and the central directory
+        for i in 0..data.len() - 4 {
+            if &data[i..i + 5] == b"b.xml" {
+                data[i] = b'a';
+            }
+        }
+
+        match ZipUtil::read_entries(Cursor::new(&data)) {
+            Err(DocxError::DuplicateEntry(name)) => assert_eq!("a.xml", name),
+            Err(e) => panic!("Unexpected error {}", e),
+            Ok(_) => panic!("Expected duplicate entries to be rejected"),
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_comments() -> DocxResult<()> {
+        let zipfile = "./src/test/test_comments.zip";
+
+        let comments = ZipUtil::read_zip(zipfile, &testdir!().to_string_lossy())?;
+        assert_eq!(vec![("foo.txt".to_string(), "A comment on foo".to_string())], comments);
+
+        let (entries, comment) = ZipUtil::read_entries(fs::File::open(zipfile)?)?;
+        assert_eq!(b"Archive comment".to_vec(), comment);
+        assert_eq!("A comment on foo", entries[0].comment);
+        assert_eq!("", entries[1].comment);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_write_comments() -> DocxResult<()> {
+        let zipfile = "./src/test/test_comments.zip";
+        let dir = testdir!();
+        let extracted = dir.join("extracted").to_string_lossy().to_string();
+        ZipUtil::read_zip(zipfile, &extracted)?;
+
+        // The comments are kept both for the entries that are copied and the ones that are modified
+        let out = dir.join("copied.zip").to_string_lossy().to_string();
+        ZipUtil::write_zip_with_template(&extracted, zipfile, &out)?;
+        let (entries, comment) = ZipUtil::read_entries(fs::File::open(&out)?)?;
+        assert_eq!(b"Archive comment".to_vec(), comment);
+        assert_eq!("A comment on foo", entries[0].comment);
+        assert_eq!("", entries[1].comment);
+
+        fs::write(Path::new(&extracted).join(&entries[0].name), "modified")?;
+        let out = dir.join("modified.zip").to_string_lossy().to_string();
+        ZipUtil::write_zip_with_template(&extracted, zipfile, &out)?;
+        let (entries, comment) = ZipUtil::read_entries(fs::File::open(&out)?)?;
+        assert_eq!(b"Archive comment".to_vec(), comment);
+        assert_eq!(b"modified".to_vec(), entries[0].data);
+        assert_eq!("A comment on foo", entries[0].comment);
+        assert_eq!("", entries[1].comment);
+
+        let mut entry = ZipEntry::new("b\u{e9}b\u{e9}.txt", b"data".to_vec());
+        entry.comment = "Commentaire \u{e0} propos".to_string();
+        let mut data = Vec::new();
+        ZipUtil::write_entries(&[ZipEntry::new("a.txt", vec![]), entry], b"", Cursor::new(&mut data))?;
+        let (entries, _) = ZipUtil::read_entries(Cursor::new(&data))?;
+        assert_eq!("b\u{e9}b\u{e9}.txt", entries[1].name);
+        assert_eq!("Commentaire \u{e0} propos", entries[1].comment);
+
+        Ok(())
+    }
+}
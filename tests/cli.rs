@@ -0,0 +1,194 @@
+use serde_json::Value;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output};
+use testdir::testdir;
+
+use docxtools::zip_util::ZipUtil;
+
+/// Run docxtools with `args` in `dir`.
+fn run(dir: &Path, args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_docxtools")).current_dir(dir).args(args).output().unwrap()
+}
+
+fn stdout(output: &Output) -> String {
+    String::from_utf8(output.stdout.clone()).unwrap()
+}
+
+fn stderr(output: &Output) -> String {
+    String::from_utf8(output.stderr.clone()).unwrap()
+}
+
+/// Copy the test document to `name` in `dir`.
+fn copy_doc(dir: &Path, name: &str) -> PathBuf {
+    let path = dir.join(name);
+    fs::copy("./src/test/test_doc1.docx", &path).unwrap();
+    path
+}
+
+#[test]
+fn test_jobs_keep_order() {
+    let dir = testdir!();
+    let names: Vec<String> = (0..12).rev().map(|i| format!("doc{:02}.docx", i)).collect();
+    for name in &names {
+        copy_doc(&dir, name);
+    }
+    fs::write(dir.join("broken.docx"), "not a zip file").unwrap();
+
+    let mut args = vec!["-j", "4"];
+    args.extend(names.iter().map(|n| n.as_str()));
+    args.insert(5, "broken.docx");
+    args.extend(["grep", "Testing"]);
+    let output = run(&dir, &args);
+
+    // The results are reported in the order of the input files, whichever thread finishes first
+    let expected: Vec<String> = names.iter().map(|n| format!("{}:Testing 123", n)).collect();
+    assert_eq!(expected.join("\n") + "\n", stdout(&output));
+    let err = stderr(&output);
+    assert!(err.starts_with("broken.docx: Zip error:"), "{}", err);
+    assert!(err.ends_with("13 files scanned, 12 matched, 0 modified, 1 failed\n"), "{}", err);
+    assert_eq!(Some(2), output.status.code());
+}
+
+#[test]
+fn test_jobs_summary() {
+    let dir = testdir!();
+    for name in ["a.docx", "b.docx", "c.docx"] {
+        copy_doc(&dir, name);
+    }
+
+    let output = run(&dir, &["-j", "0", "-o", "out", ".", "replace", "Testing", "Tested"]);
+    assert_eq!("3 files scanned, 3 matched, 3 modified, 0 failed\n", stderr(&output));
+    assert_eq!(Some(0), output.status.code());
+
+    let output = run(&dir, &["-j", "2", "out", "grep", "-c", "Tested"]);
+    assert_eq!("out/a.docx:1\nout/b.docx:1\nout/c.docx:1\n", stdout(&output).replace('\\', "/"));
+    assert_eq!("3 files scanned, 3 matched, 0 modified, 0 failed\n", stderr(&output));
+}
+
+#[test]
+fn test_grep_options() {
+    let dir = testdir!();
+    copy_doc(&dir, "a.docx");
+
+    let grep = |args: &[&str]| {
+        let mut all = vec!["a.docx", "grep"];
+        all.extend(args);
+        let output = run(&dir, &all);
+        (stdout(&output), output.status.code())
+    };
+
+    assert_eq!(("And some more text and then some\nsome\nSome some some\nAnd here’s just some text: www.example.com\n"
+        .to_string(), Some(0)), grep(&["some"]));
+    assert_eq!("And some more text and then some\nSomething here\nsome\nSome some some\n\
+        And here’s just some text: www.example.com\n", grep(&["-i", "some"]).0);
+    assert_eq!("Testing 123\nBlah\nNone\nhello\nHere’s a hyperlink: link\n", grep(&["-v", "-i", "some"]).0);
+    assert_eq!("4\n", grep(&["-c", "some"]).0);
+    assert_eq!("some\nsome\nSome\nsome\nSome\nsome\nsome\nsome\n", grep(&["-o", "-i", "some"]).0);
+    assert_eq!("3:Something here\n7:Some some some\n", grep(&["-n", "Some"]).0);
+    assert_eq!("a.docx:Blah\n", grep(&["-H", "Blah"]).0);
+    assert_eq!(("a.docx\n".to_string(), Some(0)), grep(&["-l", "hello"]));
+    assert_eq!(("".to_string(), Some(1)), grep(&["-L", "hello"]));
+    assert_eq!(("a.docx\n".to_string(), Some(0)), grep(&["-L", "zzz"]));
+}
+
+#[test]
+fn test_grep_files_and_exit_codes() {
+    let dir = testdir!();
+    copy_doc(&dir, "a.docx");
+    copy_doc(&dir, "b.docx");
+
+    // The file names are shown when searching more than one file, unless -h is given
+    let output = run(&dir, &["a.docx", "b.docx", "grep", "-c", "-i", "some"]);
+    assert_eq!("a.docx:5\nb.docx:5\n", stdout(&output));
+    let output = run(&dir, &["a.docx", "b.docx", "grep", "-h", "Blah"]);
+    assert_eq!("Blah\nBlah\n", stdout(&output));
+    assert_eq!(Some(0), output.status.code());
+
+    // Like grep the exit code is 0 if anything matched, 1 if nothing matched and 2 if there was an error
+    let output = run(&dir, &["a.docx", "b.docx", "grep", "zzz"]);
+    assert_eq!(("", Some(1)), (stdout(&output).as_str(), output.status.code()));
+    let output = run(&dir, &["a.docx", "missing.docx", "grep", "Blah"]);
+    assert_eq!("a.docx:Blah\n", stdout(&output));
+    assert!(stderr(&output).starts_with("missing.docx: I/O error:"), "{}", stderr(&output));
+    assert_eq!(Some(2), output.status.code());
+}
+
+/// The keys of the JSON object `value`, in alphabetical order.
+fn keys(value: &Value) -> Vec<&str> {
+    value.as_object().unwrap().keys().map(|k| k.as_str()).collect()
+}
+
+#[test]
+fn test_json_output() {
+    let dir = testdir!();
+    copy_doc(&dir, "a.docx");
+    copy_doc(&dir, "b.docx");
+
+    // A single array holds the results of all the files
+    let output = run(&dir, &["-f", "json", "a.docx", "b.docx", "grep", "Blah"]);
+    let out = stdout(&output);
+    assert!(out.starts_with("[\n  {") && out.ends_with("}\n]\n"), "{}", out);
+    let results: Vec<Value> = serde_json::from_str(&out).unwrap();
+    assert_eq!(2, results.len());
+    assert_eq!(vec!["file", "paragraph", "part", "style", "text"], keys(&results[0]));
+    assert_eq!(("a.docx", 3, "word/document.xml", "Blah"), (results[0]["file"].as_str().unwrap(),
+        results[0]["paragraph"].as_u64().unwrap(), results[0]["part"].as_str().unwrap(), results[0]["text"].as_str().unwrap()));
+    assert!(results[0]["style"].is_null());
+    assert_eq!("b.docx", results[1]["file"]);
+
+    let output = run(&dir, &["-f", "json", "a.docx", "grep", "-o", "Bla."]);
+    let results: Vec<Value> = serde_json::from_str(&stdout(&output)).unwrap();
+    assert_eq!("Blah", results[0]["match"]);
+
+    // Without results the array is empty
+    let output = run(&dir, &["-f", "json", "a.docx", "grep", "zzz"]);
+    assert_eq!("[]\n", stdout(&output));
+    assert_eq!(Vec::<Value>::new(), serde_json::from_str::<Vec<Value>>(&stdout(&output)).unwrap());
+}
+
+#[test]
+fn test_jsonl_output() {
+    let dir = testdir!();
+    copy_doc(&dir, "a.docx");
+
+    // Each line holds a JSON object
+    let output = run(&dir, &["-f", "jsonl", "a.docx", "replace", "--dry-run", "Blah|hello", "Bye"]);
+    let results: Vec<Value> = stdout(&output).lines().map(|l| serde_json::from_str(l).unwrap()).collect();
+    assert_eq!(2, results.len());
+    assert_eq!(vec!["attribute", "file", "new", "old", "paragraph", "part"], keys(&results[0]));
+    assert_eq!(("Blah", "Bye", 3), (results[0]["old"].as_str().unwrap(), results[0]["new"].as_str().unwrap(),
+        results[0]["paragraph"].as_u64().unwrap()));
+    assert!(results[0]["attribute"].is_null());
+    assert_eq!("hello", results[1]["old"]);
+
+    let output = run(&dir, &["-f", "jsonl", "a.docx", "links"]);
+    let out = stdout(&output);
+    let lines: Vec<&str> = out.lines().collect();
+    assert_eq!(1, lines.len());
+    let link: Value = serde_json::from_str(lines[0]).unwrap();
+    assert_eq!(vec!["external", "file", "id", "paragraph", "part", "target", "target_mode", "text", "type"], keys(&link));
+    assert_eq!(serde_json::json!({
+        "external": true, "file": "a.docx", "id": "rId4", "paragraph": 8, "part": "word/document.xml",
+        "target": "http://www.example.com/", "target_mode": "External", "text": "link",
+        "type": "http://schemas.openxmlformats.org/officeDocument/2006/relationships/hyperlink",
+    }), link);
+}
+
+#[test]
+fn test_json_link_problems() {
+    let dir = testdir!();
+    let extracted = dir.join("extracted");
+    ZipUtil::read_zip("./src/test/test_doc1.docx", &extracted.to_string_lossy()).unwrap();
+    let rels = extracted.join("word/_rels/document.xml.rels");
+    fs::write(&rels, fs::read_to_string(&rels).unwrap().replace("http://www.example.com/", "missing.pdf")).unwrap();
+    ZipUtil::write_zip(&extracted.to_string_lossy(), &dir.join("broken.docx").to_string_lossy()).unwrap();
+
+    let output = run(&dir, &["-f", "json", "broken.docx", "check-links"]);
+    let results: Vec<Value> = serde_json::from_str(&stdout(&output)).unwrap();
+    assert_eq!(vec![serde_json::json!({
+        "file": "broken.docx", "id": "rId4", "paragraph": 8, "part": "word/document.xml",
+        "problem": "file not found", "target": "missing.pdf", "text": "link",
+    })], results);
+    assert_eq!(Some(1), output.status.code());
+}